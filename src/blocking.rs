@@ -0,0 +1,163 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::mpsc::Sender;
+
+use crate::resp::RespValue;
+use crate::storage::Store;
+
+/// Retries a blocked command against the store. `None` means the command
+/// still cannot be served and the client stays blocked.
+pub type Attempt = Box<dyn FnMut(&mut Store) -> Option<RespValue> + Send>;
+
+struct Waiter {
+    db: usize,
+    keys: Vec<String>,
+    // Taken out while the attempt runs, since it needs the whole store.
+    attempt: Option<Attempt>,
+    reply: Sender<RespValue>,
+    // A handle on the client's socket, to tell whether it is still there.
+    conn: Option<TcpStream>,
+}
+
+/// Peeks at the socket without consuming input to see whether the peer has
+/// closed the connection.
+fn peer_closed(conn: &TcpStream) -> bool {
+    if conn.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let closed = match conn.peek(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+    let _ = conn.set_nonblocking(false);
+    closed
+}
+
+/// Registry of clients parked by blocking commands such as `BLPOP`.
+///
+/// Waiters are queued per key in arrival order. Writes only signal a key as
+/// ready; the writing command then calls [`Store::serve_blocked`], which
+/// retries the waiters of every ready key first-come first-served before the
+/// store lock is released, so no other client can steal the data in between.
+#[derive(Default)]
+pub struct BlockedClients {
+    waiters: HashMap<u64, Waiter>,
+    by_key: HashMap<(usize, String), VecDeque<u64>>,
+    ready: VecDeque<(usize, String)>,
+}
+
+impl BlockedClients {
+    /// Parks client `id` on `keys` of database `db`. A client can only be
+    /// blocked on one command at a time. `conn` is a clone of its socket,
+    /// checked before the client is served.
+    pub fn block(
+        &mut self,
+        id: u64,
+        db: usize,
+        keys: Vec<String>,
+        attempt: Attempt,
+        reply: Sender<RespValue>,
+        conn: Option<TcpStream>,
+    ) {
+        for key in &keys {
+            let queue = self.by_key.entry((db, key.clone())).or_default();
+            if !queue.contains(&id) {
+                queue.push_back(id);
+            }
+        }
+        self.waiters.insert(
+            id,
+            Waiter {
+                db,
+                keys,
+                attempt: Some(attempt),
+                reply,
+                conn,
+            },
+        );
+    }
+
+    /// Removes a waiter from every queue it is part of. Returns whether it
+    /// was still blocked.
+    pub fn unblock(&mut self, id: u64) -> bool {
+        let Some(waiter) = self.waiters.remove(&id) else {
+            return false;
+        };
+
+        for key in waiter.keys {
+            let slot = (waiter.db, key);
+            if let Some(queue) = self.by_key.get_mut(&slot) {
+                queue.retain(|&w| w != id);
+                if queue.is_empty() {
+                    self.by_key.remove(&slot);
+                }
+            }
+        }
+        true
+    }
+
+    /// Marks `key` as having received data, if anyone is waiting on it.
+    pub fn signal(&mut self, db: usize, key: &str) {
+        let slot = (db, key.to_string());
+        if self.by_key.contains_key(&slot) && !self.ready.contains(&slot) {
+            self.ready.push_back(slot);
+        }
+    }
+
+    /// Marks every key of database `db` that has waiters as ready.
+    pub fn signal_db(&mut self, db: usize) {
+        let keys: Vec<_> = self
+            .by_key
+            .keys()
+            .filter(|(d, _)| *d == db)
+            .cloned()
+            .collect();
+        for (db, key) in keys {
+            self.signal(db, &key);
+        }
+    }
+
+    pub fn next_ready(&mut self) -> Option<(usize, String)> {
+        self.ready.pop_front()
+    }
+
+    /// Snapshot of the waiters queued on a key, oldest first.
+    pub fn waiting_on(&self, db: usize, key: &str) -> Vec<u64> {
+        self.by_key
+            .get(&(db, key.to_string()))
+            .map(|queue| queue.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the peer of waiter `id` has closed its connection. The
+    /// client's own thread is parked while it is blocked, so the socket can
+    /// be probed from here.
+    pub fn is_disconnected(&self, id: u64) -> bool {
+        self.waiters
+            .get(&id)
+            .and_then(|waiter| waiter.conn.as_ref())
+            .is_some_and(peer_closed)
+    }
+
+    pub fn take_attempt(&mut self, id: u64) -> Option<Attempt> {
+        self.waiters.get_mut(&id)?.attempt.take()
+    }
+
+    pub fn restore_attempt(&mut self, id: u64, attempt: Attempt) {
+        if let Some(waiter) = self.waiters.get_mut(&id) {
+            waiter.attempt = Some(attempt);
+        }
+    }
+
+    /// Unblocks a waiter and hands it its reply. Returns whether the client
+    /// was blocked.
+    pub fn complete(&mut self, id: u64, reply: RespValue) -> bool {
+        if let Some(waiter) = self.waiters.get(&id) {
+            // The receiver may already be gone if the client disconnected.
+            let _ = waiter.reply.send(reply);
+        }
+        self.unblock(id)
+    }
+}
@@ -1,6 +1,11 @@
-use std::collections::HashMap;
-
-use crate::{resp::RespValue, storage::extract_string};
+use crate::{
+    hash::{MAX_EXPIRE_MS, unix_time_ms},
+    list::End,
+    resp::RespValue,
+    storage::extract_string,
+    stream::{Fields, INVALID_ID, StreamId, Trim, TrimStrategy, XAddId},
+    zset::{LexRange, ScoreRange, parse_score},
+};
 
 #[derive(Debug)]
 pub enum Command {
@@ -13,15 +18,493 @@ pub enum Command {
     LRange(String, (isize, isize)),
     LLen(String),
     LPop(String, Option<usize>),
-    BLPop(String, f32),
+    BLPop(Vec<String>, f64),
+    BRPop(Vec<String>, f64),
     Type(String),
-    XAdd(String, String, HashMap<String, String>),
+    XAdd(String, XAddOptions, XAddId, Fields),
+    /// Key, start, end and `COUNT`. Both range commands take their bounds
+    /// in ascending order here.
+    XRange(String, StreamId, StreamId, Option<usize>),
+    XRevRange(String, StreamId, StreamId, Option<usize>),
+    XLen(String),
+    XRead(XReadOptions, Vec<(String, XReadId)>),
+    XTrim(String, Trim),
+    /// Key, group, start ID (`None` for `$`), `MKSTREAM` and `ENTRIESREAD`.
+    XGroupCreate(String, String, Option<StreamId>, bool, Option<u64>),
+    XGroupSetId(String, String, Option<StreamId>, Option<u64>),
+    XGroupDestroy(String, String),
+    XGroupCreateConsumer(String, String, String),
+    XGroupDelConsumer(String, String, String),
+    /// Group, consumer, options and the streams to read, where a `None` ID
+    /// is `>`: entries never delivered to the group.
+    XReadGroup(
+        String,
+        String,
+        XReadOptions,
+        Vec<(String, Option<StreamId>)>,
+    ),
+    XAck(String, String, Vec<StreamId>),
+    XPending(String, String, Option<XPendingRange>),
+    /// Key, group, consumer, min idle time and the IDs to claim.
+    XClaim(String, String, String, u64, Vec<StreamId>, XClaimOptions),
+    /// Key, group, consumer, min idle time, start, `COUNT` and `JUSTID`.
+    XAutoClaim(String, String, String, u64, StreamId, usize, bool),
+    /// Key, and with `FULL` the `COUNT` of entries and PEL items to list
+    /// (0 for all).
+    XInfoStream(String, Option<usize>),
+    XInfoGroups(String),
+    XInfoConsumers(String, String),
+    /// Key, last ID, `ENTRIESADDED` and `MAXDELETEDID`.
+    XSetId(String, StreamId, Option<u64>, Option<StreamId>),
+    XDel(String, Vec<StreamId>),
+    Keys(String),
+    Scan(u64, ScanOptions),
+    Select(i64),
+    Move(String, i64),
+    SwapDb(i64, i64),
+    FlushDb(FlushMode),
+    FlushAll(FlushMode),
+    Info(Option<String>),
+    RPop(String, Option<usize>),
+    LIndex(String, i64),
+    LSet(String, i64, String),
+    LInsert(String, Position, String, String),
+    LRem(String, i64, String),
+    LTrim(String, i64, i64),
+    LPos(String, String, LPosOptions),
+    LPushX(String, Vec<String>),
+    RPushX(String, Vec<String>),
+    LMove(String, String, End, End),
+    BLMove(String, String, End, End, f64),
+    LMPop(Vec<String>, End, usize),
+    BLMPop(Vec<String>, End, usize, f64),
+    ClientId,
+    ClientUnblock(u64, UnblockMode),
+    ClientPause(u64, PauseMode),
+    ClientUnpause,
+    ConfigGet(Vec<String>),
+    ConfigSet(Vec<(String, String)>),
+    ObjectEncoding(String),
+    HSet(String, Vec<(String, String)>),
+    HGet(String, String),
+    HMGet(String, Vec<String>),
+    HDel(String, Vec<String>),
+    HGetAll(String),
+    HKeys(String),
+    HVals(String),
+    HLen(String),
+    HExists(String, String),
+    HIncrBy(String, String, i64),
+    HIncrByFloat(String, String, f64),
+    HSetNx(String, String, String),
+    HStrLen(String, String),
+    HRandField(String, Option<(i64, bool)>),
+    HScan(String, u64, ScanOptions),
+    HExpire(String, u64, ExpireCondition, Vec<String>),
+    HTtl(String, TtlFormat, Vec<String>),
+    HPersist(String, Vec<String>),
+    HGetEx(String, FieldTtl, Vec<String>),
+    HSetEx(String, SetCondition, FieldTtl, Vec<(String, String)>),
+    SAdd(String, Vec<String>),
+    SRem(String, Vec<String>),
+    SMembers(String),
+    SIsMember(String, String),
+    SMIsMember(String, Vec<String>),
+    SCard(String),
+    SPop(String, Option<usize>),
+    SRandMember(String, Option<i64>),
+    SMove(String, String, String),
+    SScan(String, u64, ScanOptions),
+    SCombine(SetOp, Vec<String>),
+    SCombineStore(SetOp, String, Vec<String>),
+    SInterCard(Vec<String>, usize),
+    ZAdd(String, ZAddOptions, Vec<(f64, String)>),
+    ZRem(String, Vec<String>),
+    ZScore(String, String),
+    ZMScore(String, Vec<String>),
+    ZCard(String),
+    ZCount(String, ScoreRange),
+    ZRank(String, String, bool),
+    ZRevRank(String, String, bool),
+    ZRandMember(String, Option<(i64, bool)>),
+    ZRange(String, ZRangeOptions),
+    ZRangeStore(String, String, ZRangeOptions),
+    ZRemRange(String, RangeSpec),
+    ZLexCount(String, LexRange),
+    ZPopMin(String, Option<usize>),
+    ZPopMax(String, Option<usize>),
+    BZPopMin(Vec<String>, f64),
+    BZPopMax(Vec<String>, f64),
+    ZMPop(Vec<String>, ScoreEnd, usize),
+    BZMPop(Vec<String>, ScoreEnd, usize, f64),
+    ZCombine(SetOp, Vec<String>, ZCombineOptions),
+    ZCombineStore(SetOp, String, Vec<String>, ZCombineOptions),
+    ZInterCard(Vec<String>, usize),
+    PfAdd(String, Vec<String>),
+    PfCount(Vec<String>),
+    PfMerge(String, Vec<String>),
+    PfDebug(PfDebugOp, String),
+    PfSelfTest,
+}
+
+/// How `CLIENT UNBLOCK` releases a blocked client.
+#[derive(Debug, Clone, Copy)]
+pub enum UnblockMode {
+    Timeout,
+    Error,
+}
+
+/// Which commands `CLIENT PAUSE` holds back.
+#[derive(Debug, Clone, Copy)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+/// Where `LINSERT` places the new element relative to the pivot.
+#[derive(Debug, Clone, Copy)]
+pub enum Position {
+    Before,
+    After,
+}
+
+/// What `PFDEBUG` inspects or does to a HyperLogLog.
+#[derive(Debug, Clone, Copy)]
+pub enum PfDebugOp {
+    GetReg,
+    Decode,
+    Encoding,
+    ToDense,
+}
+
+/// When `HEXPIRE` and friends may replace a field's current TTL.
+#[derive(Debug, Clone, Copy)]
+pub enum ExpireCondition {
+    Always,
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+/// How `HTTL` and friends report a field's expiration.
+#[derive(Debug, Clone, Copy)]
+pub enum TtlFormat {
+    Ttl,
+    PTtl,
+    ExpireTime,
+    PExpireTime,
+}
+
+/// What `HGETEX` and `HSETEX` do with the TTL of the fields they touch.
+/// Expiration times are absolute unix milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub enum FieldTtl {
+    Keep,
+    Persist,
+    ExpireAt(u64),
+}
+
+/// The set algebra operation of `SINTER`, `SUNION` and `SDIFF`.
+#[derive(Debug, Clone, Copy)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Flags of `ZADD`. `ZINCRBY` is parsed as `ZADD` with `incr` set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+/// Which elements of a sorted set a range command selects.
+#[derive(Debug, Clone)]
+pub enum RangeSpec {
+    /// Inclusive ranks, possibly negative to count from the end.
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// Arguments of `ZRANGE` and the legacy range commands it subsumes.
+#[derive(Debug, Clone)]
+pub struct ZRangeOptions {
+    pub by: RangeSpec,
+    pub rev: bool,
+    /// `LIMIT offset count`; a negative count means no limit.
+    pub limit: Option<(i64, i64)>,
+    pub withscores: bool,
+}
+
+/// How `ZUNION` and `ZINTER` merge the scores of a member found in several
+/// inputs.
+#[derive(Debug, Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+/// Options of `ZUNION`, `ZINTER`, `ZDIFF` and their `STORE` variants.
+#[derive(Debug, Clone)]
+pub struct ZCombineOptions {
+    /// One multiplier per input key, all 1 unless `WEIGHTS` is given.
+    pub weights: Vec<f64>,
+    pub aggregate: Aggregate,
+    pub withscores: bool,
+}
+
+/// Options of `XADD` preceding the entry ID.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XAddOptions {
+    pub nomkstream: bool,
+    pub trim: Option<Trim>,
+}
+
+/// Options of `XREAD` preceding `STREAMS`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XReadOptions {
+    /// Maximum entries per stream, 0 for no limit.
+    pub count: usize,
+    /// `BLOCK` timeout in seconds, 0 to wait forever.
+    pub block: Option<f64>,
+    /// `XREADGROUP` only: don't add the entries to the PEL.
+    pub noack: bool,
+}
+
+/// The extended form of `XPENDING`: `[IDLE min-idle-time] start end count
+/// [consumer]`, with the bounds resolved like those of `XRANGE`.
+#[derive(Debug, Clone)]
+pub struct XPendingRange {
+    pub min_idle: u64,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+/// Options of `XCLAIM` following the IDs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XClaimOptions {
+    /// `IDLE` or `TIME`, as the unix time in milliseconds the claimed
+    /// entries count as delivered at.
+    pub delivery_time: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub justid: bool,
+    pub last_id: Option<StreamId>,
+}
+
+/// Where `XREAD` starts reading a stream.
+#[derive(Debug, Clone, Copy)]
+pub enum XReadId {
+    /// Entries after this ID.
+    After(StreamId),
+    /// `$`: only entries added from now on.
+    New,
+    /// `+`: the last entry, then anything added after it.
+    Last,
+}
+
+/// Which end of a sorted set a pop operates on.
+#[derive(Debug, Clone, Copy)]
+pub enum ScoreEnd {
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// Which fields must already exist for `HSETEX` to write anything.
+#[derive(Debug, Clone, Copy)]
+pub enum SetCondition {
+    Always,
+    Fnx,
+    Fxx,
+}
+
+#[derive(Debug)]
+pub struct LPosOptions {
+    pub rank: i64,
+    pub count: Option<usize>,
+    pub maxlen: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FlushMode {
+    Sync,
+    Async,
+}
+
+impl FlushMode {
+    fn parse(arg: Option<String>) -> Result<Self, String> {
+        match arg.map(|s| s.to_uppercase()).as_deref() {
+            None | Some("SYNC") => Ok(Self::Sync),
+            Some("ASYNC") => Ok(Self::Async),
+            Some(_) => Err("syntax error".to_string()),
+        }
+    }
+}
+
+impl Command {
+    /// Whether the command may modify the keyspace. Used by `CLIENT PAUSE
+    /// WRITE`; blocking pops count as writes since they consume data.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Set(..)
+                | Self::RPush(..)
+                | Self::LPush(..)
+                | Self::LPop(..)
+                | Self::BLPop(..)
+                | Self::BRPop(..)
+                | Self::XAdd(..)
+                | Self::XTrim(..)
+                | Self::XDel(..)
+                | Self::XGroupCreate(..)
+                | Self::XGroupSetId(..)
+                | Self::XGroupDestroy(..)
+                | Self::XGroupCreateConsumer(..)
+                | Self::XGroupDelConsumer(..)
+                | Self::XReadGroup(..)
+                | Self::XAck(..)
+                | Self::XClaim(..)
+                | Self::XAutoClaim(..)
+                | Self::XSetId(..)
+                | Self::PfAdd(..)
+                | Self::PfCount(..)
+                | Self::PfMerge(..)
+                | Self::PfDebug(..)
+                | Self::Move(..)
+                | Self::SwapDb(..)
+                | Self::FlushDb(..)
+                | Self::FlushAll(..)
+                | Self::RPop(..)
+                | Self::LSet(..)
+                | Self::LInsert(..)
+                | Self::LRem(..)
+                | Self::LTrim(..)
+                | Self::LPushX(..)
+                | Self::RPushX(..)
+                | Self::LMove(..)
+                | Self::BLMove(..)
+                | Self::LMPop(..)
+                | Self::BLMPop(..)
+                | Self::HSet(..)
+                | Self::HDel(..)
+                | Self::HIncrBy(..)
+                | Self::HIncrByFloat(..)
+                | Self::HSetNx(..)
+                | Self::HExpire(..)
+                | Self::HPersist(..)
+                | Self::HGetEx(..)
+                | Self::HSetEx(..)
+                | Self::SAdd(..)
+                | Self::SRem(..)
+                | Self::SPop(..)
+                | Self::SMove(..)
+                | Self::SCombineStore(..)
+                | Self::ZAdd(..)
+                | Self::ZRem(..)
+                | Self::ZRangeStore(..)
+                | Self::ZRemRange(..)
+                | Self::ZPopMin(..)
+                | Self::ZPopMax(..)
+                | Self::BZPopMin(..)
+                | Self::BZPopMax(..)
+                | Self::ZMPop(..)
+                | Self::BZMPop(..)
+                | Self::ZCombineStore(..)
+        )
+    }
+
+    /// `CLIENT` subcommands, which are exempt from `CLIENT PAUSE`.
+    pub fn is_client_admin(&self) -> bool {
+        matches!(
+            self,
+            Self::ClientId | Self::ClientUnblock(..) | Self::ClientPause(..) | Self::ClientUnpause
+        )
+    }
+}
+
+/// What a `SCAN`-family command iterates, which decides the options it
+/// accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanTarget {
+    Keyspace,
+    Hash,
+    Set,
+}
+
+/// Optional arguments shared by the `SCAN` family: `MATCH` and `COUNT`,
+/// plus `TYPE` for the keyspace and `NOVALUES` for hashes.
+#[derive(Debug)]
+pub struct ScanOptions {
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub type_filter: Option<String>,
+    pub novalues: bool,
+}
+
+impl ScanOptions {
+    fn parse(elems: &[RespValue], start: usize, target: ScanTarget) -> Result<Self, String> {
+        let mut opts = ScanOptions {
+            pattern: None,
+            count: 10,
+            type_filter: None,
+            novalues: false,
+        };
+
+        let mut i = start;
+        while i < elems.len() {
+            let flag = extract_string(elems, i)
+                .ok_or("syntax error")?
+                .to_uppercase();
+            if flag == "NOVALUES" && target == ScanTarget::Hash {
+                opts.novalues = true;
+                i += 1;
+                continue;
+            }
+
+            let arg = extract_string(elems, i + 1).ok_or("syntax error")?;
+            match flag.as_str() {
+                "MATCH" => opts.pattern = Some(arg),
+                "COUNT" => {
+                    opts.count = arg
+                        .parse()
+                        .map_err(|_| "value is not an integer or out of range")?;
+                    if opts.count < 1 {
+                        return Err("syntax error".to_string());
+                    }
+                }
+                "TYPE" if target == ScanTarget::Keyspace => {
+                    opts.type_filter = Some(arg.to_lowercase())
+                }
+                _ => return Err("syntax error".to_string()),
+            }
+            i += 2;
+        }
+
+        Ok(opts)
+    }
 }
 
 impl Command {
     pub fn from_resp(resp: RespValue) -> Result<Self, String> {
         if let RespValue::Array(elems) = resp {
-            let cmd_name = match elems.get(0) {
+            let cmd_name = match elems.first() {
                 Some(RespValue::BulkString(s)) => s.to_uppercase(),
                 _ => return Err("Invalid command format".to_string()),
             };
@@ -46,12 +529,11 @@ impl Command {
                     let val = extract_string(&elems, 2).ok_or("SET missing value")?;
 
                     let mut px = None;
-                    if let Some(flag) = extract_string(&elems, 3) {
-                        if flag.to_uppercase() == "PX" {
-                            let ms_str =
-                                extract_string(&elems, 4).ok_or("PX requires milliseconds")?;
-                            px = Some(ms_str.parse::<u64>().map_err(|_| "Invalid PX value")?);
-                        }
+                    if let Some(flag) = extract_string(&elems, 3)
+                        && flag.to_uppercase() == "PX"
+                    {
+                        let ms_str = extract_string(&elems, 4).ok_or("PX requires milliseconds")?;
+                        px = Some(ms_str.parse::<u64>().map_err(|_| "Invalid PX value")?);
                     }
 
                     Ok(Self::Set(key, val, px))
@@ -121,14 +603,20 @@ impl Command {
 
                     Ok(Self::LPop(key, count))
                 }
-                "BLPOP" => {
-                    let key = extract_string(&elems, 1).ok_or("BLPOP missing key")?;
-                    let timeout: f32 = extract_string(&elems, 2)
-                        .ok_or("BLPOP missing timeout duration")?
-                        .parse()
-                        .map_err(|_| "ERR value is not an integer")?;
+                "BLPOP" | "BRPOP" => {
+                    if elems.len() < 3 {
+                        return Err(format!("{} requires a key and a timeout", cmd_name));
+                    }
+                    let keys: Vec<String> = (1..elems.len() - 1)
+                        .filter_map(|i| extract_string(&elems, i))
+                        .collect();
+                    let timeout = parse_timeout(extract_string(&elems, elems.len() - 1))?;
 
-                    Ok(Self::BLPop(key, timeout))
+                    if cmd_name == "BLPOP" {
+                        Ok(Self::BLPop(keys, timeout))
+                    } else {
+                        Ok(Self::BRPop(keys, timeout))
+                    }
                 }
                 "TYPE" => {
                     let key = extract_string(&elems, 1).ok_or("TYPE missing key")?;
@@ -137,15 +625,1372 @@ impl Command {
                 }
                 "XADD" => {
                     let stream_key = extract_string(&elems, 1).ok_or("XADD missing stream_key")?;
-                    let id = extract_string(&elems, 2).ok_or("XADD missing ID")?;
-                    let key = extract_string(&elems, 3).ok_or("XADD missing key")?;
-                    let val = extract_string(&elems, 4).ok_or("XADD missing value")?;
 
-                    let mut key_val = HashMap::new();
-                    key_val.insert(key, val);
+                    let mut opts = XAddOptions::default();
+                    let mut trim = TrimArgs::default();
+                    let mut i = 2;
+                    while let Some(arg) = extract_string(&elems, i) {
+                        if arg.eq_ignore_ascii_case("NOMKSTREAM") {
+                            opts.nomkstream = true;
+                        } else if !trim.parse(&elems, &mut i)? {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    opts.trim = trim.finish()?;
+
+                    let args = strings_from(&elems, i + 1);
+                    if args.is_empty() || !args.len().is_multiple_of(2) {
+                        return Err("wrong number of arguments for 'xadd' command".to_string());
+                    }
+                    let id = extract_string(&elems, i).unwrap_or_default();
+                    let id = match id.strip_suffix("-*") {
+                        _ if id == "*" => XAddId::Auto,
+                        Some(ms) => XAddId::AutoSeq(ms.parse().map_err(|_| INVALID_ID)?),
+                        None => XAddId::Explicit(StreamId::parse(&id, 0).ok_or(INVALID_ID)?),
+                    };
+                    let fields = args
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
+
+                    Ok(Self::XAdd(stream_key, opts, id, fields))
+                }
+                "XRANGE" | "XREVRANGE" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let (first, second) =
+                        match (extract_string(&elems, 2), extract_string(&elems, 3)) {
+                            (Some(first), Some(second)) => (first, second),
+                            _ => {
+                                return Err(format!(
+                                    "wrong number of arguments for '{}' command",
+                                    cmd_name.to_lowercase()
+                                ));
+                            }
+                        };
+                    let (start, end) = if cmd_name == "XRANGE" {
+                        (first, second)
+                    } else {
+                        (second, first)
+                    };
+
+                    let (start, end) = parse_stream_interval(&start, &end)?;
+
+                    let mut count = None;
+                    if elems.len() > 4 {
+                        let flag = extract_string(&elems, 4).unwrap_or_default();
+                        if !flag.eq_ignore_ascii_case("COUNT") || elems.len() != 6 {
+                            return Err("syntax error".to_string());
+                        }
+                        // Like in Redis, a negative count returns nothing.
+                        let n: i64 = extract_integer(&elems, 5, "syntax error")?;
+                        count = Some(n.max(0) as usize);
+                    }
+
+                    if cmd_name == "XRANGE" {
+                        Ok(Self::XRange(key, start, end, count))
+                    } else {
+                        Ok(Self::XRevRange(key, start, end, count))
+                    }
+                }
+                "XREAD" => {
+                    let (opts, keys, ids) = parse_xread(&elems, 1, &cmd_name)?;
+                    let ids = ids
+                        .iter()
+                        .map(|id| match id.as_str() {
+                            "$" => Ok(XReadId::New),
+                            "+" => Ok(XReadId::Last),
+                            _ => StreamId::parse(id, 0).map(XReadId::After).ok_or(INVALID_ID),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(Self::XRead(opts, keys.into_iter().zip(ids).collect()))
+                }
+                "XGROUP" => {
+                    let sub = extract_string(&elems, 1)
+                        .ok_or("XGROUP missing subcommand")?
+                        .to_uppercase();
+                    let arity = match sub.as_str() {
+                        "CREATE" | "SETID" => 5,
+                        "DESTROY" => 4,
+                        "CREATECONSUMER" | "DELCONSUMER" => 5,
+                        _ => {
+                            return Err(format!(
+                                "unknown subcommand '{}'. Try XGROUP HELP.",
+                                sub.to_lowercase()
+                            ));
+                        }
+                    };
+                    let args = strings_from(&elems, 2);
+                    if args.len() + 2 < arity {
+                        return Err(format!(
+                            "wrong number of arguments for 'xgroup|{}' command",
+                            sub.to_lowercase()
+                        ));
+                    }
+                    let (key, group) = (args[0].clone(), args[1].clone());
+
+                    match sub.as_str() {
+                        "CREATE" | "SETID" => {
+                            let id = match args[2].as_str() {
+                                "$" => None,
+                                id => Some(StreamId::parse(id, 0).ok_or(INVALID_ID)?),
+                            };
+                            let mut mkstream = false;
+                            let mut entries_read = None;
+                            let mut i = 3;
+                            while let Some(arg) = args.get(i) {
+                                match arg.to_uppercase().as_str() {
+                                    "MKSTREAM" if sub == "CREATE" => mkstream = true,
+                                    "ENTRIESREAD" => {
+                                        let read: i64 = args
+                                            .get(i + 1)
+                                            .and_then(|n| n.parse().ok())
+                                            .ok_or("value is not an integer or out of range")?;
+                                        if read < -1 {
+                                            return Err(
+                                                "value for ENTRIESREAD must be positive or -1"
+                                                    .to_string(),
+                                            );
+                                        }
+                                        // -1 means unknown.
+                                        entries_read = u64::try_from(read).ok();
+                                        i += 1;
+                                    }
+                                    _ => return Err("syntax error".to_string()),
+                                }
+                                i += 1;
+                            }
+
+                            if sub == "CREATE" {
+                                Ok(Self::XGroupCreate(key, group, id, mkstream, entries_read))
+                            } else {
+                                Ok(Self::XGroupSetId(key, group, id, entries_read))
+                            }
+                        }
+                        _ if args.len() + 2 > arity => Err(format!(
+                            "wrong number of arguments for 'xgroup|{}' command",
+                            sub.to_lowercase()
+                        )),
+                        "DESTROY" => Ok(Self::XGroupDestroy(key, group)),
+                        "CREATECONSUMER" => {
+                            Ok(Self::XGroupCreateConsumer(key, group, args[2].clone()))
+                        }
+                        _ => Ok(Self::XGroupDelConsumer(key, group, args[2].clone())),
+                    }
+                }
+                "XREADGROUP" => {
+                    let flag = extract_string(&elems, 1).unwrap_or_default();
+                    if !flag.eq_ignore_ascii_case("GROUP") {
+                        return Err("Missing GROUP option for XREADGROUP".to_string());
+                    }
+                    let (Some(group), Some(consumer)) =
+                        (extract_string(&elems, 2), extract_string(&elems, 3))
+                    else {
+                        return Err("syntax error".to_string());
+                    };
+
+                    let (opts, keys, ids) = parse_xread(&elems, 4, &cmd_name)?;
+                    let ids = ids
+                        .iter()
+                        .map(|id| match id.as_str() {
+                            ">" => Ok(None),
+                            _ => StreamId::parse(id, 0).map(Some).ok_or(INVALID_ID),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(Self::XReadGroup(
+                        group,
+                        consumer,
+                        opts,
+                        keys.into_iter().zip(ids).collect(),
+                    ))
+                }
+                "XACK" => {
+                    let args = strings_from(&elems, 1);
+                    if args.len() < 3 {
+                        return Err("wrong number of arguments for 'xack' command".to_string());
+                    }
+                    let ids = args[2..]
+                        .iter()
+                        .map(|id| StreamId::parse(id, 0).ok_or(INVALID_ID))
+                        .collect::<Result<_, _>>()?;
+
+                    Ok(Self::XAck(args[0].clone(), args[1].clone(), ids))
+                }
+                "XPENDING" => {
+                    let (Some(key), Some(group)) =
+                        (extract_string(&elems, 1), extract_string(&elems, 2))
+                    else {
+                        return Err("wrong number of arguments for 'xpending' command".to_string());
+                    };
+                    if elems.len() == 3 {
+                        return Ok(Self::XPending(key, group, None));
+                    }
+
+                    let mut i = 3;
+                    let mut min_idle = 0;
+                    let flag = extract_string(&elems, i).unwrap_or_default();
+                    if flag.eq_ignore_ascii_case("IDLE") {
+                        let idle: i64 = extract_integer(&elems, i + 1, "syntax error")?;
+                        min_idle = idle.max(0) as u64;
+                        i += 2;
+                    }
+                    let (Some(start), Some(end)) =
+                        (extract_string(&elems, i), extract_string(&elems, i + 1))
+                    else {
+                        return Err("syntax error".to_string());
+                    };
+                    if elems.len() > i + 4 {
+                        return Err("syntax error".to_string());
+                    }
+
+                    let (start, end) = parse_stream_interval(&start, &end)?;
+                    let count: i64 = extract_integer(&elems, i + 2, "syntax error")?;
+
+                    Ok(Self::XPending(
+                        key,
+                        group,
+                        Some(XPendingRange {
+                            min_idle,
+                            start,
+                            end,
+                            count: count.max(0) as usize,
+                            consumer: extract_string(&elems, i + 3),
+                        }),
+                    ))
+                }
+                "XCLAIM" => {
+                    let args = strings_from(&elems, 1);
+                    if args.len() < 5 {
+                        return Err("wrong number of arguments for 'xclaim' command".to_string());
+                    }
+                    let min_idle = args[3]
+                        .parse::<i64>()
+                        .map_err(|_| "Invalid min-idle-time argument for XCLAIM")?
+                        .max(0) as u64;
+
+                    // IDs run until the first argument that isn't one.
+                    let mut ids = Vec::new();
+                    let mut i = 4;
+                    while let Some(id) = args.get(i).and_then(|id| StreamId::parse(id, 0)) {
+                        ids.push(id);
+                        i += 1;
+                    }
+                    if ids.is_empty() {
+                        return Err(INVALID_ID.to_string());
+                    }
+
+                    let mut opts = XClaimOptions::default();
+                    let now = unix_time_ms();
+                    while let Some(arg) = args.get(i) {
+                        let value = args.get(i + 1);
+                        let int = |name: &str| {
+                            value.and_then(|v| v.parse::<i64>().ok()).ok_or_else(|| {
+                                format!("Invalid {} option argument for XCLAIM", name)
+                            })
+                        };
+                        match arg.to_uppercase().as_str() {
+                            "FORCE" => opts.force = true,
+                            "JUSTID" => opts.justid = true,
+                            "IDLE" => {
+                                let idle = int("IDLE")?.max(0) as u64;
+                                opts.delivery_time = Some(now.saturating_sub(idle));
+                                i += 1;
+                            }
+                            "TIME" => {
+                                opts.delivery_time = Some(int("TIME")?.max(0) as u64);
+                                i += 1;
+                            }
+                            "RETRYCOUNT" => {
+                                opts.retry_count = Some(int("RETRYCOUNT")?.max(0) as u64);
+                                i += 1;
+                            }
+                            "LASTID" => {
+                                let id = value.and_then(|v| StreamId::parse(v, 0));
+                                opts.last_id = Some(id.ok_or(INVALID_ID)?);
+                                i += 1;
+                            }
+                            _ => return Err(format!("Unrecognized XCLAIM option '{}'", arg)),
+                        }
+                        i += 1;
+                    }
+
+                    Ok(Self::XClaim(
+                        args[0].clone(),
+                        args[1].clone(),
+                        args[2].clone(),
+                        min_idle,
+                        ids,
+                        opts,
+                    ))
+                }
+                "XAUTOCLAIM" => {
+                    let args = strings_from(&elems, 1);
+                    if args.len() < 5 {
+                        return Err(
+                            "wrong number of arguments for 'xautoclaim' command".to_string()
+                        );
+                    }
+                    let min_idle = args[3]
+                        .parse::<i64>()
+                        .map_err(|_| "Invalid min-idle-time argument for XAUTOCLAIM")?
+                        .max(0) as u64;
+                    let (start, exclusive) = parse_stream_bound(&args[4], 0)?;
+                    let start = if exclusive {
+                        start.next().ok_or("invalid start ID for the interval")?
+                    } else {
+                        start
+                    };
+
+                    let mut count = 100;
+                    let mut justid = false;
+                    let mut i = 5;
+                    while let Some(arg) = args.get(i) {
+                        match arg.to_uppercase().as_str() {
+                            "COUNT" => {
+                                count = args
+                                    .get(i + 1)
+                                    .and_then(|n| n.parse::<usize>().ok())
+                                    .filter(|&n| n > 0)
+                                    .ok_or("COUNT must be > 0")?;
+                                i += 1;
+                            }
+                            "JUSTID" => justid = true,
+                            _ => return Err("syntax error".to_string()),
+                        }
+                        i += 1;
+                    }
+
+                    Ok(Self::XAutoClaim(
+                        args[0].clone(),
+                        args[1].clone(),
+                        args[2].clone(),
+                        min_idle,
+                        start,
+                        count,
+                        justid,
+                    ))
+                }
+                "XINFO" => {
+                    let sub = extract_string(&elems, 1)
+                        .ok_or("XINFO missing subcommand")?
+                        .to_uppercase();
+                    let args = strings_from(&elems, 2);
+                    let wrong_args = || {
+                        format!(
+                            "wrong number of arguments for 'xinfo|{}' command",
+                            sub.to_lowercase()
+                        )
+                    };
+
+                    match sub.as_str() {
+                        "STREAM" => {
+                            let key = args.first().ok_or_else(wrong_args)?.clone();
+                            let full = match &args[1..] {
+                                [] => None,
+                                [flag] if flag.eq_ignore_ascii_case("FULL") => Some(10),
+                                [flag, count, n]
+                                    if flag.eq_ignore_ascii_case("FULL")
+                                        && count.eq_ignore_ascii_case("COUNT") =>
+                                {
+                                    let n: i64 = n
+                                        .parse()
+                                        .map_err(|_| "value is not an integer or out of range")?;
+                                    Some(n.max(0) as usize)
+                                }
+                                _ => return Err("syntax error".to_string()),
+                            };
+                            Ok(Self::XInfoStream(key, full))
+                        }
+                        "GROUPS" => match args.as_slice() {
+                            [key] => Ok(Self::XInfoGroups(key.clone())),
+                            _ => Err(wrong_args()),
+                        },
+                        "CONSUMERS" => match args.as_slice() {
+                            [key, group] => Ok(Self::XInfoConsumers(key.clone(), group.clone())),
+                            _ => Err(wrong_args()),
+                        },
+                        _ => Err(format!(
+                            "unknown subcommand '{}'. Try XINFO HELP.",
+                            sub.to_lowercase()
+                        )),
+                    }
+                }
+                "XSETID" => {
+                    let (Some(key), Some(id)) =
+                        (extract_string(&elems, 1), extract_string(&elems, 2))
+                    else {
+                        return Err("wrong number of arguments for 'xsetid' command".to_string());
+                    };
+                    let id = StreamId::parse(&id, 0).ok_or(INVALID_ID)?;
+
+                    let mut entries_added = None;
+                    let mut max_deleted_id = None;
+                    let mut i = 3;
+                    while let Some(arg) = extract_string(&elems, i) {
+                        let value = extract_string(&elems, i + 1).ok_or("syntax error")?;
+                        match arg.to_uppercase().as_str() {
+                            "ENTRIESADDED" => {
+                                let added: i64 = value
+                                    .parse()
+                                    .map_err(|_| "value is not an integer or out of range")?;
+                                entries_added = Some(
+                                    u64::try_from(added)
+                                        .map_err(|_| "entries_added must be positive")?,
+                                );
+                            }
+                            "MAXDELETEDID" => {
+                                max_deleted_id =
+                                    Some(StreamId::parse(&value, 0).ok_or(INVALID_ID)?);
+                            }
+                            _ => return Err("syntax error".to_string()),
+                        }
+                        i += 2;
+                    }
+
+                    Ok(Self::XSetId(key, id, entries_added, max_deleted_id))
+                }
+                "XTRIM" => {
+                    let key = extract_string(&elems, 1).ok_or("XTRIM missing key")?;
+
+                    let mut trim = TrimArgs::default();
+                    let mut i = 2;
+                    while i < elems.len() {
+                        if !trim.parse(&elems, &mut i)? {
+                            return Err("syntax error".to_string());
+                        }
+                        i += 1;
+                    }
+
+                    Ok(Self::XTrim(key, trim.finish()?.ok_or("syntax error")?))
+                }
+                "XDEL" => {
+                    let key = extract_string(&elems, 1).ok_or("XDEL missing key")?;
+                    let ids = strings_from(&elems, 2);
+                    if ids.is_empty() {
+                        return Err("wrong number of arguments for 'xdel' command".to_string());
+                    }
+                    let ids = ids
+                        .iter()
+                        .map(|id| StreamId::parse(id, 0).ok_or(INVALID_ID))
+                        .collect::<Result<_, _>>()?;
+
+                    Ok(Self::XDel(key, ids))
+                }
+                "XLEN" => {
+                    let key = extract_string(&elems, 1).ok_or("XLEN missing key")?;
+                    Ok(Self::XLen(key))
+                }
+                "KEYS" => {
+                    let pattern = extract_string(&elems, 1).ok_or("KEYS missing pattern")?;
+                    Ok(Self::Keys(pattern))
+                }
+                "SCAN" => {
+                    let cursor = extract_string(&elems, 1)
+                        .ok_or("SCAN missing cursor")?
+                        .parse::<u64>()
+                        .map_err(|_| "invalid cursor")?;
+                    let opts = ScanOptions::parse(&elems, 2, ScanTarget::Keyspace)?;
+
+                    Ok(Self::Scan(cursor, opts))
+                }
+                "SELECT" => {
+                    let index = extract_string(&elems, 1)
+                        .ok_or("SELECT missing index")?
+                        .parse()
+                        .map_err(|_| "value is not an integer or out of range")?;
+
+                    Ok(Self::Select(index))
+                }
+                "MOVE" => {
+                    let key = extract_string(&elems, 1).ok_or("MOVE missing key")?;
+                    let db = extract_string(&elems, 2)
+                        .ok_or("MOVE missing db")?
+                        .parse()
+                        .map_err(|_| "value is not an integer or out of range")?;
+
+                    Ok(Self::Move(key, db))
+                }
+                "SWAPDB" => {
+                    let first = extract_string(&elems, 1)
+                        .ok_or("SWAPDB missing index")?
+                        .parse()
+                        .map_err(|_| "invalid first DB index")?;
+                    let second = extract_string(&elems, 2)
+                        .ok_or("SWAPDB missing index")?
+                        .parse()
+                        .map_err(|_| "invalid second DB index")?;
+
+                    Ok(Self::SwapDb(first, second))
+                }
+                "FLUSHDB" => Ok(Self::FlushDb(FlushMode::parse(extract_string(&elems, 1))?)),
+                "FLUSHALL" => Ok(Self::FlushAll(FlushMode::parse(extract_string(&elems, 1))?)),
+                "INFO" => Ok(Self::Info(extract_string(&elems, 1))),
+                "RPOP" => {
+                    let key = extract_string(&elems, 1).ok_or("RPOP missing key")?;
+                    let count = match extract_string(&elems, 2) {
+                        Some(s) => Some(
+                            s.parse::<usize>()
+                                .map_err(|_| "value is out of range, must be positive")?,
+                        ),
+                        None => None,
+                    };
+
+                    Ok(Self::RPop(key, count))
+                }
+                "LINDEX" => {
+                    let key = extract_string(&elems, 1).ok_or("LINDEX missing key")?;
+                    let index = extract_integer(&elems, 2, "LINDEX missing index")?;
+
+                    Ok(Self::LIndex(key, index))
+                }
+                "LSET" => {
+                    let key = extract_string(&elems, 1).ok_or("LSET missing key")?;
+                    let index = extract_integer(&elems, 2, "LSET missing index")?;
+                    let val = extract_string(&elems, 3).ok_or("LSET missing value")?;
+
+                    Ok(Self::LSet(key, index, val))
+                }
+                "LINSERT" => {
+                    let key = extract_string(&elems, 1).ok_or("LINSERT missing key")?;
+                    let position = match extract_string(&elems, 2)
+                        .ok_or("LINSERT missing position")?
+                        .to_uppercase()
+                        .as_str()
+                    {
+                        "BEFORE" => Position::Before,
+                        "AFTER" => Position::After,
+                        _ => return Err("syntax error".to_string()),
+                    };
+                    let pivot = extract_string(&elems, 3).ok_or("LINSERT missing pivot")?;
+                    let val = extract_string(&elems, 4).ok_or("LINSERT missing value")?;
+
+                    Ok(Self::LInsert(key, position, pivot, val))
+                }
+                "LREM" => {
+                    let key = extract_string(&elems, 1).ok_or("LREM missing key")?;
+                    let count = extract_integer(&elems, 2, "LREM missing count")?;
+                    let val = extract_string(&elems, 3).ok_or("LREM missing value")?;
+
+                    Ok(Self::LRem(key, count, val))
+                }
+                "LTRIM" => {
+                    let key = extract_string(&elems, 1).ok_or("LTRIM missing key")?;
+                    let start = extract_integer(&elems, 2, "LTRIM missing start")?;
+                    let stop = extract_integer(&elems, 3, "LTRIM missing stop")?;
+
+                    Ok(Self::LTrim(key, start, stop))
+                }
+                "LPOS" => {
+                    let key = extract_string(&elems, 1).ok_or("LPOS missing key")?;
+                    let val = extract_string(&elems, 2).ok_or("LPOS missing element")?;
+
+                    let mut opts = LPosOptions {
+                        rank: 1,
+                        count: None,
+                        maxlen: 0,
+                    };
+                    let mut i = 3;
+                    while i < elems.len() {
+                        let flag = extract_string(&elems, i)
+                            .ok_or("syntax error")?
+                            .to_uppercase();
+                        let arg: i64 = extract_integer(&elems, i + 1, "syntax error")?;
+                        match flag.as_str() {
+                            "RANK" => {
+                                if arg == 0 || arg == i64::MIN {
+                                    return Err("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the last match".to_string());
+                                }
+                                opts.rank = arg;
+                            }
+                            "COUNT" => {
+                                opts.count = Some(
+                                    usize::try_from(arg).map_err(|_| "COUNT can't be negative")?,
+                                );
+                            }
+                            "MAXLEN" => {
+                                opts.maxlen =
+                                    usize::try_from(arg).map_err(|_| "MAXLEN can't be negative")?;
+                            }
+                            _ => return Err("syntax error".to_string()),
+                        }
+                        i += 2;
+                    }
+
+                    Ok(Self::LPos(key, val, opts))
+                }
+                "LPUSHX" | "RPUSHX" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let values: Vec<String> = (2..elems.len())
+                        .filter_map(|i| extract_string(&elems, i))
+                        .collect();
+
+                    if values.is_empty() {
+                        return Err(format!("{} requires at least one value", cmd_name));
+                    }
+
+                    if cmd_name == "LPUSHX" {
+                        Ok(Self::LPushX(key, values))
+                    } else {
+                        Ok(Self::RPushX(key, values))
+                    }
+                }
+                "LMOVE" | "BLMOVE" => {
+                    let src = extract_string(&elems, 1).ok_or("LMOVE missing source")?;
+                    let dst = extract_string(&elems, 2).ok_or("LMOVE missing destination")?;
+                    let from = parse_end(extract_string(&elems, 3))?;
+                    let to = parse_end(extract_string(&elems, 4))?;
+
+                    if cmd_name == "LMOVE" {
+                        Ok(Self::LMove(src, dst, from, to))
+                    } else {
+                        let timeout = parse_timeout(extract_string(&elems, 5))?;
+                        Ok(Self::BLMove(src, dst, from, to, timeout))
+                    }
+                }
+                "RPOPLPUSH" => {
+                    let src = extract_string(&elems, 1).ok_or("RPOPLPUSH missing source")?;
+                    let dst = extract_string(&elems, 2).ok_or("RPOPLPUSH missing destination")?;
+
+                    Ok(Self::LMove(src, dst, End::Right, End::Left))
+                }
+                "BRPOPLPUSH" => {
+                    let src = extract_string(&elems, 1).ok_or("BRPOPLPUSH missing source")?;
+                    let dst = extract_string(&elems, 2).ok_or("BRPOPLPUSH missing destination")?;
+                    let timeout = parse_timeout(extract_string(&elems, 3))?;
+
+                    Ok(Self::BLMove(src, dst, End::Right, End::Left, timeout))
+                }
+                "LMPOP" => {
+                    let (keys, end, count) = parse_mpop(&elems, 1, parse_end)?;
+                    Ok(Self::LMPop(keys, end, count))
+                }
+                "BLMPOP" => {
+                    let timeout = parse_timeout(extract_string(&elems, 1))?;
+                    let (keys, end, count) = parse_mpop(&elems, 2, parse_end)?;
+                    Ok(Self::BLMPop(keys, end, count, timeout))
+                }
+                "CLIENT" => {
+                    let sub = extract_string(&elems, 1)
+                        .ok_or("CLIENT missing subcommand")?
+                        .to_uppercase();
+                    match sub.as_str() {
+                        "ID" => Ok(Self::ClientId),
+                        "UNBLOCK" => {
+                            let id = extract_integer(&elems, 2, "CLIENT UNBLOCK missing id")?;
+                            let mode = match extract_string(&elems, 3)
+                                .map(|s| s.to_uppercase())
+                                .as_deref()
+                            {
+                                None | Some("TIMEOUT") => UnblockMode::Timeout,
+                                Some("ERROR") => UnblockMode::Error,
+                                Some(_) => {
+                                    return Err("CLIENT UNBLOCK reason should be TIMEOUT or ERROR"
+                                        .to_string());
+                                }
+                            };
+                            Ok(Self::ClientUnblock(id, mode))
+                        }
+                        "PAUSE" => {
+                            let ms = extract_string(&elems, 2)
+                                .ok_or("CLIENT PAUSE missing timeout")?
+                                .parse()
+                                .map_err(|_| "timeout is not an integer or out of range")?;
+                            let mode = match extract_string(&elems, 3)
+                                .map(|s| s.to_uppercase())
+                                .as_deref()
+                            {
+                                None | Some("ALL") => PauseMode::All,
+                                Some("WRITE") => PauseMode::Write,
+                                Some(_) => return Err("syntax error".to_string()),
+                            };
+                            Ok(Self::ClientPause(ms, mode))
+                        }
+                        "UNPAUSE" => Ok(Self::ClientUnpause),
+                        _ => Err(format!(
+                            "unknown subcommand '{}'. Try CLIENT HELP.",
+                            sub.to_lowercase()
+                        )),
+                    }
+                }
+                "CONFIG" => {
+                    let sub = extract_string(&elems, 1)
+                        .ok_or("CONFIG missing subcommand")?
+                        .to_uppercase();
+                    match sub.as_str() {
+                        "GET" => {
+                            let patterns = strings_from(&elems, 2);
+                            if patterns.is_empty() {
+                                return Err("wrong number of arguments for 'config|get' command"
+                                    .to_string());
+                            }
+                            Ok(Self::ConfigGet(patterns))
+                        }
+                        "SET" => {
+                            let args = strings_from(&elems, 2);
+                            if args.is_empty() || !args.len().is_multiple_of(2) {
+                                return Err("wrong number of arguments for 'config|set' command"
+                                    .to_string());
+                            }
+                            let pairs = args
+                                .chunks(2)
+                                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                                .collect();
+                            Ok(Self::ConfigSet(pairs))
+                        }
+                        _ => Err(format!(
+                            "unknown subcommand '{}'. Try CONFIG HELP.",
+                            sub.to_lowercase()
+                        )),
+                    }
+                }
+                "OBJECT" => {
+                    let sub = extract_string(&elems, 1)
+                        .ok_or("OBJECT missing subcommand")?
+                        .to_uppercase();
+                    if sub != "ENCODING" {
+                        return Err(format!(
+                            "unknown subcommand '{}'. Try OBJECT HELP.",
+                            sub.to_lowercase()
+                        ));
+                    }
+                    let key = extract_string(&elems, 2).ok_or("OBJECT missing key")?;
+                    Ok(Self::ObjectEncoding(key))
+                }
+                "HSET" => {
+                    let key = extract_string(&elems, 1).ok_or("HSET missing key")?;
+                    let args = strings_from(&elems, 2);
+                    if args.is_empty() || !args.len().is_multiple_of(2) {
+                        return Err("wrong number of arguments for 'hset' command".to_string());
+                    }
+                    let pairs = args
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
 
-                    Ok(Self::XAdd(stream_key, id, key_val))
+                    Ok(Self::HSet(key, pairs))
                 }
+                "HGET" => {
+                    let key = extract_string(&elems, 1).ok_or("HGET missing key")?;
+                    let field = extract_string(&elems, 2).ok_or("HGET missing field")?;
+                    Ok(Self::HGet(key, field))
+                }
+                "HMGET" | "HDEL" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let fields = strings_from(&elems, 2);
+                    if fields.is_empty() {
+                        return Err(format!("{} requires at least one field", cmd_name));
+                    }
+
+                    if cmd_name == "HMGET" {
+                        Ok(Self::HMGet(key, fields))
+                    } else {
+                        Ok(Self::HDel(key, fields))
+                    }
+                }
+                "HGETALL" | "HKEYS" | "HVALS" | "HLEN" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    Ok(match cmd_name.as_str() {
+                        "HGETALL" => Self::HGetAll(key),
+                        "HKEYS" => Self::HKeys(key),
+                        "HVALS" => Self::HVals(key),
+                        _ => Self::HLen(key),
+                    })
+                }
+                "HEXISTS" | "HSTRLEN" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let field = extract_string(&elems, 2)
+                        .ok_or_else(|| format!("{} missing field", cmd_name))?;
+
+                    if cmd_name == "HEXISTS" {
+                        Ok(Self::HExists(key, field))
+                    } else {
+                        Ok(Self::HStrLen(key, field))
+                    }
+                }
+                "HINCRBY" => {
+                    let key = extract_string(&elems, 1).ok_or("HINCRBY missing key")?;
+                    let field = extract_string(&elems, 2).ok_or("HINCRBY missing field")?;
+                    let incr = extract_integer(&elems, 3, "HINCRBY missing increment")?;
+                    Ok(Self::HIncrBy(key, field, incr))
+                }
+                "HINCRBYFLOAT" => {
+                    let key = extract_string(&elems, 1).ok_or("HINCRBYFLOAT missing key")?;
+                    let field = extract_string(&elems, 2).ok_or("HINCRBYFLOAT missing field")?;
+                    let incr = extract_string(&elems, 3)
+                        .ok_or("HINCRBYFLOAT missing increment")?
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|f| f.is_finite())
+                        .ok_or("value is not a valid float")?;
+                    Ok(Self::HIncrByFloat(key, field, incr))
+                }
+                "HSETNX" => {
+                    let key = extract_string(&elems, 1).ok_or("HSETNX missing key")?;
+                    let field = extract_string(&elems, 2).ok_or("HSETNX missing field")?;
+                    let val = extract_string(&elems, 3).ok_or("HSETNX missing value")?;
+                    Ok(Self::HSetNx(key, field, val))
+                }
+                "HRANDFIELD" => {
+                    let key = extract_string(&elems, 1).ok_or("HRANDFIELD missing key")?;
+                    let count = parse_random_count(&elems, "WITHVALUES")?;
+                    Ok(Self::HRandField(key, count))
+                }
+                "HSCAN" => {
+                    let key = extract_string(&elems, 1).ok_or("HSCAN missing key")?;
+                    let cursor = extract_string(&elems, 2)
+                        .ok_or("HSCAN missing cursor")?
+                        .parse::<u64>()
+                        .map_err(|_| "invalid cursor")?;
+                    let opts = ScanOptions::parse(&elems, 3, ScanTarget::Hash)?;
+                    Ok(Self::HScan(key, cursor, opts))
+                }
+                "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let unit = match cmd_name.as_str() {
+                        "HEXPIRE" => "EX",
+                        "HPEXPIRE" => "PX",
+                        "HEXPIREAT" => "EXAT",
+                        _ => "PXAT",
+                    };
+                    let at = parse_expire_at(unit, extract_string(&elems, 2), &cmd_name)?;
+
+                    let mut fields_at = 3;
+                    let cond = match extract_string(&elems, 3)
+                        .map(|s| s.to_uppercase())
+                        .as_deref()
+                    {
+                        Some("NX") => ExpireCondition::Nx,
+                        Some("XX") => ExpireCondition::Xx,
+                        Some("GT") => ExpireCondition::Gt,
+                        Some("LT") => ExpireCondition::Lt,
+                        _ => ExpireCondition::Always,
+                    };
+                    if !matches!(cond, ExpireCondition::Always) {
+                        fields_at += 1;
+                    }
+                    let fields = parse_fields(&elems, fields_at, 1)?;
+
+                    Ok(Self::HExpire(key, at, cond, fields))
+                }
+                "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let fields = parse_fields(&elems, 2, 1)?;
+                    let format = match cmd_name.as_str() {
+                        "HTTL" => TtlFormat::Ttl,
+                        "HPTTL" => TtlFormat::PTtl,
+                        "HEXPIRETIME" => TtlFormat::ExpireTime,
+                        _ => TtlFormat::PExpireTime,
+                    };
+                    Ok(Self::HTtl(key, format, fields))
+                }
+                "HPERSIST" => {
+                    let key = extract_string(&elems, 1).ok_or("HPERSIST missing key")?;
+                    let fields = parse_fields(&elems, 2, 1)?;
+                    Ok(Self::HPersist(key, fields))
+                }
+                "HGETEX" => {
+                    let key = extract_string(&elems, 1).ok_or("HGETEX missing key")?;
+
+                    let mut ttl = FieldTtl::Keep;
+                    let mut i = 2;
+                    while let Some(flag) = extract_string(&elems, i).map(|s| s.to_uppercase()) {
+                        match flag.as_str() {
+                            "FIELDS" => break,
+                            "PERSIST" if matches!(ttl, FieldTtl::Keep) => {
+                                ttl = FieldTtl::Persist;
+                                i += 1;
+                            }
+                            "EX" | "PX" | "EXAT" | "PXAT" if matches!(ttl, FieldTtl::Keep) => {
+                                let at = parse_expire_at(
+                                    &flag,
+                                    extract_string(&elems, i + 1),
+                                    &cmd_name,
+                                )?;
+                                ttl = FieldTtl::ExpireAt(at);
+                                i += 2;
+                            }
+                            _ => return Err("syntax error".to_string()),
+                        }
+                    }
+                    let fields = parse_fields(&elems, i, 1)?;
+
+                    Ok(Self::HGetEx(key, ttl, fields))
+                }
+                "HSETEX" => {
+                    let key = extract_string(&elems, 1).ok_or("HSETEX missing key")?;
+
+                    let mut cond = SetCondition::Always;
+                    let mut ttl = None;
+                    let mut i = 2;
+                    while let Some(flag) = extract_string(&elems, i).map(|s| s.to_uppercase()) {
+                        match flag.as_str() {
+                            "FIELDS" => break,
+                            "FNX" | "FXX" if matches!(cond, SetCondition::Always) => {
+                                cond = if flag == "FNX" {
+                                    SetCondition::Fnx
+                                } else {
+                                    SetCondition::Fxx
+                                };
+                                i += 1;
+                            }
+                            "KEEPTTL" if ttl.is_none() => {
+                                ttl = Some(FieldTtl::Keep);
+                                i += 1;
+                            }
+                            "EX" | "PX" | "EXAT" | "PXAT" if ttl.is_none() => {
+                                let at = parse_expire_at(
+                                    &flag,
+                                    extract_string(&elems, i + 1),
+                                    &cmd_name,
+                                )?;
+                                ttl = Some(FieldTtl::ExpireAt(at));
+                                i += 2;
+                            }
+                            _ => return Err("syntax error".to_string()),
+                        }
+                    }
+                    let pairs = parse_fields(&elems, i, 2)?
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
+
+                    // Like HSET, writing a field clears its TTL unless told otherwise.
+                    Ok(Self::HSetEx(
+                        key,
+                        cond,
+                        ttl.unwrap_or(FieldTtl::Persist),
+                        pairs,
+                    ))
+                }
+                "SADD" | "SREM" | "SMISMEMBER" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let members = strings_from(&elems, 2);
+                    if members.is_empty() {
+                        return Err(format!(
+                            "wrong number of arguments for '{}' command",
+                            cmd_name.to_lowercase()
+                        ));
+                    }
+
+                    Ok(match cmd_name.as_str() {
+                        "SADD" => Self::SAdd(key, members),
+                        "SREM" => Self::SRem(key, members),
+                        _ => Self::SMIsMember(key, members),
+                    })
+                }
+                "SMEMBERS" | "SCARD" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    if cmd_name == "SMEMBERS" {
+                        Ok(Self::SMembers(key))
+                    } else {
+                        Ok(Self::SCard(key))
+                    }
+                }
+                "SISMEMBER" => {
+                    let key = extract_string(&elems, 1).ok_or("SISMEMBER missing key")?;
+                    let member = extract_string(&elems, 2).ok_or("SISMEMBER missing member")?;
+                    Ok(Self::SIsMember(key, member))
+                }
+                "SPOP" => {
+                    let key = extract_string(&elems, 1).ok_or("SPOP missing key")?;
+                    let count = match extract_string(&elems, 2) {
+                        None => None,
+                        Some(_) => {
+                            let count: i64 = extract_integer(&elems, 2, "SPOP missing count")?;
+                            Some(
+                                usize::try_from(count)
+                                    .map_err(|_| "value is out of range, must be positive")?,
+                            )
+                        }
+                    };
+                    if elems.len() > 3 {
+                        return Err("syntax error".to_string());
+                    }
+                    Ok(Self::SPop(key, count))
+                }
+                "SRANDMEMBER" => {
+                    let key = extract_string(&elems, 1).ok_or("SRANDMEMBER missing key")?;
+                    let count = match extract_string(&elems, 2) {
+                        None => None,
+                        Some(_) => Some(extract_integer(&elems, 2, "SRANDMEMBER missing count")?),
+                    };
+                    if elems.len() > 3 {
+                        return Err("syntax error".to_string());
+                    }
+                    Ok(Self::SRandMember(key, count))
+                }
+                "SMOVE" => {
+                    let src = extract_string(&elems, 1).ok_or("SMOVE missing source")?;
+                    let dst = extract_string(&elems, 2).ok_or("SMOVE missing destination")?;
+                    let member = extract_string(&elems, 3).ok_or("SMOVE missing member")?;
+                    Ok(Self::SMove(src, dst, member))
+                }
+                "SSCAN" => {
+                    let key = extract_string(&elems, 1).ok_or("SSCAN missing key")?;
+                    let cursor = extract_string(&elems, 2)
+                        .ok_or("SSCAN missing cursor")?
+                        .parse::<u64>()
+                        .map_err(|_| "invalid cursor")?;
+                    let opts = ScanOptions::parse(&elems, 3, ScanTarget::Set)?;
+                    Ok(Self::SScan(key, cursor, opts))
+                }
+                "SINTER" | "SUNION" | "SDIFF" => {
+                    let keys = strings_from(&elems, 1);
+                    if keys.is_empty() {
+                        return Err(format!(
+                            "wrong number of arguments for '{}' command",
+                            cmd_name.to_lowercase()
+                        ));
+                    }
+
+                    let op = match cmd_name.as_str() {
+                        "SINTER" => SetOp::Inter,
+                        "SUNION" => SetOp::Union,
+                        _ => SetOp::Diff,
+                    };
+                    Ok(Self::SCombine(op, keys))
+                }
+                "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+                    let dst = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing destination", cmd_name))?;
+                    let keys = strings_from(&elems, 2);
+                    if keys.is_empty() {
+                        return Err(format!(
+                            "wrong number of arguments for '{}' command",
+                            cmd_name.to_lowercase()
+                        ));
+                    }
+
+                    let op = match cmd_name.as_str() {
+                        "SINTERSTORE" => SetOp::Inter,
+                        "SUNIONSTORE" => SetOp::Union,
+                        _ => SetOp::Diff,
+                    };
+                    Ok(Self::SCombineStore(op, dst, keys))
+                }
+                "SINTERCARD" | "ZINTERCARD" => {
+                    let numkeys: i64 =
+                        extract_integer(&elems, 1, &format!("{} missing numkeys", cmd_name))?;
+                    if numkeys <= 0 {
+                        return Err("numkeys should be greater than 0".to_string());
+                    }
+                    let numkeys = numkeys as usize;
+                    if numkeys > elems.len().saturating_sub(2) {
+                        return Err(
+                            "Number of keys can't be greater than number of args".to_string()
+                        );
+                    }
+                    let keys = (2..2 + numkeys)
+                        .filter_map(|i| extract_string(&elems, i))
+                        .collect();
+
+                    let mut limit = 0;
+                    let rest = 2 + numkeys;
+                    if rest < elems.len() {
+                        let flag = extract_string(&elems, rest).unwrap_or_default();
+                        if !flag.eq_ignore_ascii_case("LIMIT") || rest + 2 != elems.len() {
+                            return Err("syntax error".to_string());
+                        }
+                        let arg: i64 = extract_integer(&elems, rest + 1, "syntax error")?;
+                        limit = usize::try_from(arg).map_err(|_| "LIMIT can't be negative")?;
+                    }
+
+                    if cmd_name == "SINTERCARD" {
+                        Ok(Self::SInterCard(keys, limit))
+                    } else {
+                        Ok(Self::ZInterCard(keys, limit))
+                    }
+                }
+                "ZADD" => {
+                    let key = extract_string(&elems, 1).ok_or("ZADD missing key")?;
+
+                    let mut opts = ZAddOptions::default();
+                    let mut i = 2;
+                    while let Some(flag) = extract_string(&elems, i).map(|s| s.to_uppercase()) {
+                        match flag.as_str() {
+                            "NX" => opts.nx = true,
+                            "XX" => opts.xx = true,
+                            "GT" => opts.gt = true,
+                            "LT" => opts.lt = true,
+                            "CH" => opts.ch = true,
+                            "INCR" => opts.incr = true,
+                            _ => break,
+                        }
+                        i += 1;
+                    }
+
+                    let args = strings_from(&elems, i);
+                    if args.is_empty() || !args.len().is_multiple_of(2) {
+                        return Err("syntax error".to_string());
+                    }
+                    if opts.nx && opts.xx {
+                        return Err(
+                            "XX and NX options at the same time are not compatible".to_string()
+                        );
+                    }
+                    if (opts.nx && (opts.gt || opts.lt)) || (opts.gt && opts.lt) {
+                        return Err(
+                            "GT, LT, and/or NX options at the same time are not compatible"
+                                .to_string(),
+                        );
+                    }
+                    if opts.incr && args.len() > 2 {
+                        return Err(
+                            "INCR option supports a single increment-element pair".to_string()
+                        );
+                    }
+
+                    let pairs = args
+                        .chunks(2)
+                        .map(|pair| {
+                            let score =
+                                parse_score(&pair[0]).ok_or("value is not a valid float")?;
+                            Ok((score, pair[1].clone()))
+                        })
+                        .collect::<Result<_, String>>()?;
+
+                    Ok(Self::ZAdd(key, opts, pairs))
+                }
+                "ZINCRBY" => {
+                    let key = extract_string(&elems, 1).ok_or("ZINCRBY missing key")?;
+                    let incr = extract_string(&elems, 2)
+                        .ok_or("ZINCRBY missing increment")
+                        .map(|s| parse_score(&s))?
+                        .ok_or("value is not a valid float")?;
+                    let member = extract_string(&elems, 3).ok_or("ZINCRBY missing member")?;
+
+                    let opts = ZAddOptions {
+                        incr: true,
+                        ..Default::default()
+                    };
+                    Ok(Self::ZAdd(key, opts, vec![(incr, member)]))
+                }
+                "ZREM" | "ZMSCORE" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let members = strings_from(&elems, 2);
+                    if members.is_empty() {
+                        return Err(format!(
+                            "wrong number of arguments for '{}' command",
+                            cmd_name.to_lowercase()
+                        ));
+                    }
+
+                    if cmd_name == "ZREM" {
+                        Ok(Self::ZRem(key, members))
+                    } else {
+                        Ok(Self::ZMScore(key, members))
+                    }
+                }
+                "ZSCORE" => {
+                    let key = extract_string(&elems, 1).ok_or("ZSCORE missing key")?;
+                    let member = extract_string(&elems, 2).ok_or("ZSCORE missing member")?;
+                    Ok(Self::ZScore(key, member))
+                }
+                "ZCARD" => {
+                    let key = extract_string(&elems, 1).ok_or("ZCARD missing key")?;
+                    Ok(Self::ZCard(key))
+                }
+                "ZCOUNT" => {
+                    let key = extract_string(&elems, 1).ok_or("ZCOUNT missing key")?;
+                    let min = extract_string(&elems, 2).ok_or("ZCOUNT missing min")?;
+                    let max = extract_string(&elems, 3).ok_or("ZCOUNT missing max")?;
+                    let range = ScoreRange::parse(&min, &max).ok_or("min or max is not a float")?;
+                    Ok(Self::ZCount(key, range))
+                }
+                "ZRANK" | "ZREVRANK" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let member = extract_string(&elems, 2)
+                        .ok_or_else(|| format!("{} missing member", cmd_name))?;
+                    let withscore = match extract_string(&elems, 3) {
+                        None => false,
+                        Some(s) if s.eq_ignore_ascii_case("WITHSCORE") => true,
+                        Some(_) => return Err("syntax error".to_string()),
+                    };
+                    if elems.len() > 4 {
+                        return Err("syntax error".to_string());
+                    }
+
+                    if cmd_name == "ZRANK" {
+                        Ok(Self::ZRank(key, member, withscore))
+                    } else {
+                        Ok(Self::ZRevRank(key, member, withscore))
+                    }
+                }
+                "ZRANDMEMBER" => {
+                    let key = extract_string(&elems, 1).ok_or("ZRANDMEMBER missing key")?;
+                    let count = parse_random_count(&elems, "WITHSCORES")?;
+                    Ok(Self::ZRandMember(key, count))
+                }
+                "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX"
+                | "ZREVRANGEBYLEX" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let implied = match cmd_name.as_str() {
+                        "ZREVRANGE" => Some((RangeKind::Rank, true)),
+                        "ZRANGEBYSCORE" => Some((RangeKind::Score, false)),
+                        "ZREVRANGEBYSCORE" => Some((RangeKind::Score, true)),
+                        "ZRANGEBYLEX" => Some((RangeKind::Lex, false)),
+                        "ZREVRANGEBYLEX" => Some((RangeKind::Lex, true)),
+                        _ => None,
+                    };
+                    let opts = parse_zrange(&elems, 2, implied, !cmd_name.ends_with("BYLEX"))?;
+                    Ok(Self::ZRange(key, opts))
+                }
+                "ZRANGESTORE" => {
+                    let dst = extract_string(&elems, 1).ok_or("ZRANGESTORE missing destination")?;
+                    let src = extract_string(&elems, 2).ok_or("ZRANGESTORE missing source")?;
+                    let opts = parse_zrange(&elems, 3, None, false)?;
+                    Ok(Self::ZRangeStore(dst, src, opts))
+                }
+                "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" | "ZREMRANGEBYLEX" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let min = extract_string(&elems, 2)
+                        .ok_or_else(|| format!("{} missing min", cmd_name))?;
+                    let max = extract_string(&elems, 3)
+                        .ok_or_else(|| format!("{} missing max", cmd_name))?;
+                    if elems.len() > 4 {
+                        return Err("syntax error".to_string());
+                    }
+
+                    let spec = match cmd_name.as_str() {
+                        "ZREMRANGEBYRANK" => RangeSpec::Rank(
+                            extract_integer(&elems, 2, "missing start")?,
+                            extract_integer(&elems, 3, "missing stop")?,
+                        ),
+                        "ZREMRANGEBYSCORE" => RangeSpec::Score(
+                            ScoreRange::parse(&min, &max).ok_or("min or max is not a float")?,
+                        ),
+                        _ => RangeSpec::Lex(
+                            LexRange::parse(&min, &max)
+                                .ok_or("min or max not valid string range item")?,
+                        ),
+                    };
+                    Ok(Self::ZRemRange(key, spec))
+                }
+                "ZLEXCOUNT" => {
+                    let key = extract_string(&elems, 1).ok_or("ZLEXCOUNT missing key")?;
+                    let min = extract_string(&elems, 2).ok_or("ZLEXCOUNT missing min")?;
+                    let max = extract_string(&elems, 3).ok_or("ZLEXCOUNT missing max")?;
+                    let range = LexRange::parse(&min, &max)
+                        .ok_or("min or max not valid string range item")?;
+                    Ok(Self::ZLexCount(key, range))
+                }
+                "ZPOPMIN" | "ZPOPMAX" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing key", cmd_name))?;
+                    let count = match extract_string(&elems, 2) {
+                        None => None,
+                        Some(_) => {
+                            let count: i64 = extract_integer(&elems, 2, "missing count")?;
+                            Some(
+                                usize::try_from(count)
+                                    .map_err(|_| "value is out of range, must be positive")?,
+                            )
+                        }
+                    };
+                    if elems.len() > 3 {
+                        return Err("syntax error".to_string());
+                    }
+
+                    if cmd_name == "ZPOPMIN" {
+                        Ok(Self::ZPopMin(key, count))
+                    } else {
+                        Ok(Self::ZPopMax(key, count))
+                    }
+                }
+                "BZPOPMIN" | "BZPOPMAX" => {
+                    if elems.len() < 3 {
+                        return Err(format!("{} requires a key and a timeout", cmd_name));
+                    }
+                    let keys: Vec<String> = (1..elems.len() - 1)
+                        .filter_map(|i| extract_string(&elems, i))
+                        .collect();
+                    let timeout = parse_timeout(extract_string(&elems, elems.len() - 1))?;
+
+                    if cmd_name == "BZPOPMIN" {
+                        Ok(Self::BZPopMin(keys, timeout))
+                    } else {
+                        Ok(Self::BZPopMax(keys, timeout))
+                    }
+                }
+                "ZMPOP" => {
+                    let (keys, end, count) = parse_mpop(&elems, 1, parse_score_end)?;
+                    Ok(Self::ZMPop(keys, end, count))
+                }
+                "BZMPOP" => {
+                    let timeout = parse_timeout(extract_string(&elems, 1))?;
+                    let (keys, end, count) = parse_mpop(&elems, 2, parse_score_end)?;
+                    Ok(Self::BZMPop(keys, end, count, timeout))
+                }
+                "ZUNION" | "ZINTER" | "ZDIFF" => {
+                    let op = match cmd_name.as_str() {
+                        "ZUNION" => SetOp::Union,
+                        "ZINTER" => SetOp::Inter,
+                        _ => SetOp::Diff,
+                    };
+                    let (keys, opts) = parse_zcombine(&elems, 1, &cmd_name, op, false)?;
+                    Ok(Self::ZCombine(op, keys, opts))
+                }
+                "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                    let dst = extract_string(&elems, 1)
+                        .ok_or_else(|| format!("{} missing destination", cmd_name))?;
+                    let op = match cmd_name.as_str() {
+                        "ZUNIONSTORE" => SetOp::Union,
+                        "ZINTERSTORE" => SetOp::Inter,
+                        _ => SetOp::Diff,
+                    };
+                    let (keys, opts) = parse_zcombine(&elems, 2, &cmd_name, op, true)?;
+                    Ok(Self::ZCombineStore(op, dst, keys, opts))
+                }
+                "PFADD" => {
+                    let key = extract_string(&elems, 1)
+                        .ok_or("wrong number of arguments for 'pfadd' command")?;
+                    Ok(Self::PfAdd(key, strings_from(&elems, 2)))
+                }
+                "PFCOUNT" => {
+                    let keys = strings_from(&elems, 1);
+                    if keys.is_empty() {
+                        return Err("wrong number of arguments for 'pfcount' command".to_string());
+                    }
+                    Ok(Self::PfCount(keys))
+                }
+                "PFMERGE" => {
+                    let dst = extract_string(&elems, 1)
+                        .ok_or("wrong number of arguments for 'pfmerge' command")?;
+                    Ok(Self::PfMerge(dst, strings_from(&elems, 2)))
+                }
+                "PFDEBUG" => {
+                    let (Some(sub), Some(key), 3) = (
+                        extract_string(&elems, 1),
+                        extract_string(&elems, 2),
+                        elems.len(),
+                    ) else {
+                        return Err("wrong number of arguments for 'pfdebug' command".to_string());
+                    };
+                    let op = match sub.to_uppercase().as_str() {
+                        "GETREG" => PfDebugOp::GetReg,
+                        "DECODE" => PfDebugOp::Decode,
+                        "ENCODING" => PfDebugOp::Encoding,
+                        "TODENSE" => PfDebugOp::ToDense,
+                        _ => return Err(format!("Unknown PFDEBUG subcommand '{}'", sub)),
+                    };
+                    Ok(Self::PfDebug(op, key))
+                }
+                "PFSELFTEST" => Ok(Self::PfSelfTest),
                 _ => Err(format!("Unknown command: {}", cmd_name)),
             }
         } else {
@@ -153,3 +1998,437 @@ impl Command {
         }
     }
 }
+
+fn extract_integer<T: std::str::FromStr>(
+    elems: &[RespValue],
+    index: usize,
+    missing: &str,
+) -> Result<T, String> {
+    extract_string(elems, index)
+        .ok_or(missing)?
+        .parse()
+        .map_err(|_| "value is not an integer or out of range".to_string())
+}
+
+fn parse_end(arg: Option<String>) -> Result<End, String> {
+    match arg.map(|s| s.to_uppercase()).as_deref() {
+        Some("LEFT") => Ok(End::Left),
+        Some("RIGHT") => Ok(End::Right),
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+/// Trimming options of `XADD` and `XTRIM`, collected in whatever order they
+/// come before being checked together.
+#[derive(Default)]
+struct TrimArgs {
+    strategy: Option<TrimStrategy>,
+    approx: bool,
+    limit: Option<usize>,
+}
+
+impl TrimArgs {
+    /// Parses the `MAXLEN|MINID [=|~] threshold` or `LIMIT count` option at
+    /// `*i`, leaving `*i` on its last argument. Returns false if the
+    /// argument is not a trimming option.
+    fn parse(&mut self, elems: &[RespValue], i: &mut usize) -> Result<bool, String> {
+        let arg = extract_string(elems, *i).unwrap_or_default().to_uppercase();
+        match arg.as_str() {
+            "MAXLEN" | "MINID" => {
+                let mut threshold = extract_string(elems, *i + 1).ok_or("syntax error")?;
+                *i += 1;
+                if threshold == "=" || threshold == "~" {
+                    self.approx = threshold == "~";
+                    threshold = extract_string(elems, *i + 1).ok_or("syntax error")?;
+                    *i += 1;
+                }
+
+                self.strategy = Some(if arg == "MAXLEN" {
+                    let max: i64 = threshold
+                        .parse()
+                        .map_err(|_| "value is not an integer or out of range")?;
+                    TrimStrategy::MaxLen(
+                        usize::try_from(max).map_err(|_| "The MAXLEN argument must be >= 0.")?,
+                    )
+                } else {
+                    TrimStrategy::MinId(StreamId::parse(&threshold, 0).ok_or(INVALID_ID)?)
+                });
+            }
+            "LIMIT" => {
+                let limit: i64 = extract_integer(elems, *i + 1, "syntax error")?;
+                self.limit =
+                    Some(usize::try_from(limit).map_err(|_| "The LIMIT argument must be >= 0.")?);
+                *i += 1;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn finish(self) -> Result<Option<Trim>, String> {
+        if self.limit.is_some() && !self.approx {
+            return Err(
+                "syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+            );
+        }
+        Ok(self.strategy.map(|strategy| Trim {
+            strategy,
+            approx: self.approx,
+            limit: self.limit.unwrap_or(Trim::DEFAULT_LIMIT),
+        }))
+    }
+}
+
+/// Parses `[COUNT count] [BLOCK ms] [NOACK] STREAMS key [key ...] id
+/// [id ...]` starting at `start`, returning the IDs unparsed since their
+/// special forms vary by command. `NOACK` is only accepted by `XREADGROUP`.
+fn parse_xread(
+    elems: &[RespValue],
+    start: usize,
+    cmd_name: &str,
+) -> Result<(XReadOptions, Vec<String>, Vec<String>), String> {
+    let mut opts = XReadOptions::default();
+    let mut i = start;
+    loop {
+        let arg = extract_string(elems, i).ok_or("syntax error")?;
+        match arg.to_uppercase().as_str() {
+            "COUNT" => {
+                // Like in Redis, a negative count means no limit.
+                let count: i64 = extract_integer(elems, i + 1, "syntax error")?;
+                opts.count = count.max(0) as usize;
+                i += 1;
+            }
+            "BLOCK" => {
+                let ms: i64 = extract_string(elems, i + 1)
+                    .ok_or("syntax error")?
+                    .parse()
+                    .map_err(|_| "timeout is not an integer or out of range")?;
+                if ms < 0 {
+                    return Err("timeout is negative".to_string());
+                }
+                opts.block = Some(ms as f64 / 1000.0);
+                i += 1;
+            }
+            "NOACK" if cmd_name == "XREADGROUP" => opts.noack = true,
+            "STREAMS" => break,
+            _ => return Err("syntax error".to_string()),
+        }
+        i += 1;
+    }
+
+    let mut args = strings_from(elems, i + 1);
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(format!(
+            "Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+            cmd_name.to_lowercase()
+        ));
+    }
+    let ids = args.split_off(args.len() / 2);
+    Ok((opts, args, ids))
+}
+
+/// Parses the `start end` bounds of `XRANGE` and `XPENDING` into an
+/// inclusive interval.
+fn parse_stream_interval(start: &str, end: &str) -> Result<(StreamId, StreamId), String> {
+    let (start, exclusive) = parse_stream_bound(start, 0)?;
+    let start = if exclusive {
+        start.next().ok_or("invalid start ID for the interval")?
+    } else {
+        start
+    };
+    let (end, exclusive) = parse_stream_bound(end, u64::MAX)?;
+    let end = if exclusive {
+        end.prev().ok_or("invalid end ID for the interval")?
+    } else {
+        end
+    };
+    Ok((start, end))
+}
+
+/// Parses an `XRANGE` bound: `-`, `+` or an ID, where a missing sequence
+/// number is taken as `missing_seq`. A leading `(` excludes the ID itself,
+/// which is reported alongside it.
+fn parse_stream_bound(arg: &str, missing_seq: u64) -> Result<(StreamId, bool), String> {
+    let (arg, exclusive) = match arg.strip_prefix('(') {
+        Some(rest) => (rest, true),
+        None => (arg, false),
+    };
+    let id = match arg {
+        "-" => StreamId::MIN,
+        "+" => StreamId::MAX,
+        _ => StreamId::parse(arg, missing_seq).ok_or(INVALID_ID)?,
+    };
+    Ok((id, exclusive))
+}
+
+fn parse_score_end(arg: Option<String>) -> Result<ScoreEnd, String> {
+    match arg.map(|s| s.to_uppercase()).as_deref() {
+        Some("MIN") => Ok(ScoreEnd::Min),
+        Some("MAX") => Ok(ScoreEnd::Max),
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+/// Parses the `numkeys key [key ...] [WEIGHTS ...] [AGGREGATE ...]
+/// [WITHSCORES]` tail of the sorted set algebra commands, starting at
+/// `start`. `ZDIFF` takes neither weights nor an aggregate, and the `STORE`
+/// variants don't reply with scores.
+fn parse_zcombine(
+    elems: &[RespValue],
+    start: usize,
+    cmd_name: &str,
+    op: SetOp,
+    store: bool,
+) -> Result<(Vec<String>, ZCombineOptions), String> {
+    let numkeys: i64 = extract_integer(elems, start, "syntax error")?;
+    if numkeys <= 0 {
+        return Err(format!(
+            "at least 1 input key is needed for '{}' command",
+            cmd_name.to_lowercase()
+        ));
+    }
+    let numkeys = numkeys as usize;
+    if start + numkeys >= elems.len() {
+        return Err("syntax error".to_string());
+    }
+    let keys: Vec<String> = (start + 1..=start + numkeys)
+        .filter_map(|i| extract_string(elems, i))
+        .collect();
+
+    let mut opts = ZCombineOptions {
+        weights: vec![1.0; numkeys],
+        aggregate: Aggregate::Sum,
+        withscores: false,
+    };
+    let weighted = !matches!(op, SetOp::Diff);
+    let mut i = start + numkeys + 1;
+    while let Some(arg) = extract_string(elems, i) {
+        match arg.to_uppercase().as_str() {
+            "WEIGHTS" if weighted && i + numkeys < elems.len() => {
+                for (j, weight) in opts.weights.iter_mut().enumerate() {
+                    *weight = extract_string(elems, i + 1 + j)
+                        .and_then(|w| parse_score(&w))
+                        .ok_or("weight value is not a float")?;
+                }
+                i += numkeys;
+            }
+            "AGGREGATE" if weighted => {
+                opts.aggregate = match extract_string(elems, i + 1)
+                    .map(|s| s.to_uppercase())
+                    .as_deref()
+                {
+                    Some("SUM") => Aggregate::Sum,
+                    Some("MIN") => Aggregate::Min,
+                    Some("MAX") => Aggregate::Max,
+                    _ => return Err("syntax error".to_string()),
+                };
+                i += 1;
+            }
+            "WITHSCORES" if !store => opts.withscores = true,
+            _ => return Err("syntax error".to_string()),
+        }
+        i += 1;
+    }
+
+    Ok((keys, opts))
+}
+
+/// Parses the `numkeys key [key ...] <side> [COUNT count]` tail shared by
+/// `LMPOP`, `ZMPOP` and their blocking variants, starting at `start`. `side`
+/// parses `LEFT|RIGHT` or `MIN|MAX`.
+fn parse_mpop<T>(
+    elems: &[RespValue],
+    start: usize,
+    side: fn(Option<String>) -> Result<T, String>,
+) -> Result<(Vec<String>, T, usize), String> {
+    let numkeys: usize = extract_integer(elems, start, "missing numkeys")?;
+    if numkeys == 0 {
+        return Err("numkeys should be greater than 0".to_string());
+    }
+    let last_key = start
+        .checked_add(numkeys)
+        .filter(|&last| last < elems.len())
+        .ok_or("syntax error")?;
+
+    let keys = (start + 1..=last_key)
+        .filter_map(|i| extract_string(elems, i))
+        .collect();
+    let end = side(extract_string(elems, last_key + 1))?;
+
+    let mut count = 1;
+    let rest = last_key + 2;
+    if rest < elems.len() {
+        let flag = extract_string(elems, rest).unwrap_or_default();
+        if !flag.eq_ignore_ascii_case("COUNT") || rest + 2 != elems.len() {
+            return Err("syntax error".to_string());
+        }
+        count = extract_integer::<i64>(elems, rest + 1, "syntax error")?;
+        if count <= 0 {
+            return Err("count should be greater than 0".to_string());
+        }
+    }
+
+    Ok((keys, end, count as usize))
+}
+
+/// Parses a blocking timeout given in (possibly fractional) seconds.
+fn parse_timeout(arg: Option<String>) -> Result<f64, String> {
+    let timeout: f64 = arg
+        .ok_or("missing timeout")?
+        .parse()
+        .ok()
+        .filter(|t: &f64| t.is_finite())
+        .ok_or("timeout is not a float or out of range")?;
+    if timeout < 0.0 {
+        return Err("timeout is negative".to_string());
+    }
+    Ok(timeout)
+}
+
+/// Parses `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+/// [WITHSCORES]` starting at `at`. The legacy commands pass the range kind
+/// and direction their name implies, and then the keywords selecting those
+/// are not accepted.
+fn parse_zrange(
+    elems: &[RespValue],
+    at: usize,
+    implied: Option<(RangeKind, bool)>,
+    allow_withscores: bool,
+) -> Result<ZRangeOptions, String> {
+    let start = extract_string(elems, at).ok_or("missing start")?;
+    let stop = extract_string(elems, at + 1).ok_or("missing stop")?;
+
+    let (mut kind, mut rev) = implied.unwrap_or((RangeKind::Rank, false));
+    let mut limit = None;
+    let mut withscores = false;
+    let mut i = at + 2;
+    while let Some(flag) = extract_string(elems, i).map(|s| s.to_uppercase()) {
+        match flag.as_str() {
+            "BYSCORE" if implied.is_none() => kind = RangeKind::Score,
+            "BYLEX" if implied.is_none() => kind = RangeKind::Lex,
+            "REV" if implied.is_none() => rev = true,
+            "WITHSCORES" if allow_withscores => withscores = true,
+            "LIMIT" => {
+                let offset = extract_integer(elems, i + 1, "syntax error")?;
+                let count = extract_integer(elems, i + 2, "syntax error")?;
+                limit = Some((offset, count));
+                i += 2;
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+        i += 1;
+    }
+
+    if limit.is_some() && kind == RangeKind::Rank {
+        return Err(
+            "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string(),
+        );
+    }
+    if withscores && kind == RangeKind::Lex {
+        return Err("syntax error, WITHSCORES not supported in combination with BYLEX".to_string());
+    }
+
+    // Score and lex intervals are given from high to low when reversed.
+    let (min, max) = if rev {
+        (&stop, &start)
+    } else {
+        (&start, &stop)
+    };
+    let by = match kind {
+        RangeKind::Rank => {
+            let parse = |s: &str| {
+                s.parse()
+                    .map_err(|_| "value is not an integer or out of range".to_string())
+            };
+            RangeSpec::Rank(parse(&start)?, parse(&stop)?)
+        }
+        RangeKind::Score => {
+            RangeSpec::Score(ScoreRange::parse(min, max).ok_or("min or max is not a float")?)
+        }
+        RangeKind::Lex => RangeSpec::Lex(
+            LexRange::parse(min, max).ok_or("min or max not valid string range item")?,
+        ),
+    };
+
+    Ok(ZRangeOptions {
+        by,
+        rev,
+        limit,
+        withscores,
+    })
+}
+
+/// Parses the optional `count [WITHVALUES|WITHSCORES]` tail of
+/// `HRANDFIELD` and `ZRANDMEMBER`.
+fn parse_random_count(elems: &[RespValue], with: &str) -> Result<Option<(i64, bool)>, String> {
+    if extract_string(elems, 2).is_none() {
+        return Ok(None);
+    }
+
+    let count = extract_integer(elems, 2, "syntax error")?;
+    let with = match extract_string(elems, 3) {
+        None => false,
+        Some(s) if s.eq_ignore_ascii_case(with) => true,
+        Some(_) => return Err("syntax error".to_string()),
+    };
+    if elems.len() > 4 {
+        return Err("syntax error".to_string());
+    }
+    Ok(Some((count, with)))
+}
+
+/// Resolves an `EX`, `PX`, `EXAT` or `PXAT` argument to an absolute unix time
+/// in milliseconds. Relative times are measured from when the command is
+/// parsed.
+fn parse_expire_at(unit: &str, arg: Option<String>, cmd_name: &str) -> Result<u64, String> {
+    let value: i64 = arg
+        .ok_or("syntax error")?
+        .parse()
+        .map_err(|_| "value is not an integer or out of range")?;
+    if value < 0 {
+        return Err("invalid expire time, must be >= 0".to_string());
+    }
+
+    let mut at = Some(value as u64);
+    if matches!(unit, "EX" | "EXAT") {
+        at = at.and_then(|v| v.checked_mul(1000));
+    }
+    if matches!(unit, "EX" | "PX") {
+        at = at.and_then(|v| v.checked_add(unix_time_ms()));
+    }
+    at.filter(|&at| at <= MAX_EXPIRE_MS).ok_or_else(|| {
+        format!(
+            "invalid expire time in '{}' command",
+            cmd_name.to_lowercase()
+        )
+    })
+}
+
+/// Parses the `FIELDS numfields field [field ...]` tail of the hash field
+/// expiration commands, starting at `start`. Each field is followed by
+/// `width - 1` more arguments, e.g. its value for `HSETEX`.
+fn parse_fields(elems: &[RespValue], start: usize, width: usize) -> Result<Vec<String>, String> {
+    if !extract_string(elems, start).is_some_and(|s| s.eq_ignore_ascii_case("FIELDS")) {
+        return Err(
+            "Mandatory argument FIELDS is missing or not at the right position".to_string(),
+        );
+    }
+    let numfields: i64 = extract_integer(elems, start + 1, "missing numfields")?;
+    if numfields <= 0 {
+        return Err("Parameter `numFields` should be greater than 0".to_string());
+    }
+
+    let args = strings_from(elems, start + 2);
+    if !args.len().is_multiple_of(width) || (args.len() / width) as i64 != numfields {
+        return Err("The `numfields` parameter must match the number of arguments".to_string());
+    }
+    Ok(args)
+}
+
+/// Collects every bulk string argument from `start` onwards.
+fn strings_from(elems: &[RespValue], start: usize) -> Vec<String> {
+    (start..elems.len())
+        .filter_map(|i| extract_string(elems, i))
+        .collect()
+}
@@ -0,0 +1,115 @@
+use crate::glob::glob_match;
+
+/// Thresholds under which a collection keeps its compact listpack encoding.
+#[derive(Debug, Clone, Copy)]
+pub struct ListpackLimits {
+    pub max_entries: usize,
+    pub max_value: usize,
+}
+
+impl ListpackLimits {
+    /// Whether a collection of `len` entries, the longest being
+    /// `longest` bytes, still fits the compact encoding.
+    pub fn allows(&self, len: usize, longest: usize) -> bool {
+        len <= self.max_entries && longest <= self.max_value
+    }
+}
+
+/// Runtime parameters exposed through `CONFIG GET` and `CONFIG SET`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub databases: usize,
+    pub hash_listpack: ListpackLimits,
+    pub set_max_intset_entries: usize,
+    pub zset_listpack: ListpackLimits,
+    pub hll_sparse_max_bytes: usize,
+}
+
+impl Config {
+    pub fn new(databases: usize) -> Self {
+        Self {
+            databases,
+            hash_listpack: ListpackLimits {
+                max_entries: 128,
+                max_value: 64,
+            },
+            set_max_intset_entries: 512,
+            zset_listpack: ListpackLimits {
+                max_entries: 128,
+                max_value: 64,
+            },
+            hll_sparse_max_bytes: 3000,
+        }
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("databases", self.databases.to_string()),
+            (
+                "hash-max-listpack-entries",
+                self.hash_listpack.max_entries.to_string(),
+            ),
+            (
+                "hash-max-listpack-value",
+                self.hash_listpack.max_value.to_string(),
+            ),
+            (
+                "set-max-intset-entries",
+                self.set_max_intset_entries.to_string(),
+            ),
+            (
+                "zset-max-listpack-entries",
+                self.zset_listpack.max_entries.to_string(),
+            ),
+            (
+                "zset-max-listpack-value",
+                self.zset_listpack.max_value.to_string(),
+            ),
+            (
+                "hll-sparse-max-bytes",
+                self.hll_sparse_max_bytes.to_string(),
+            ),
+        ]
+    }
+
+    /// Returns every parameter whose name matches `pattern`.
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_lowercase();
+        self.params()
+            .into_iter()
+            .filter(|(name, _)| glob_match(&pattern, name))
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = name.to_lowercase();
+        let failed = |reason: &str| {
+            format!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                name, reason
+            )
+        };
+        let parse = || {
+            value
+                .parse::<usize>()
+                .map_err(|_| failed("argument couldn't be parsed into an integer"))
+        };
+
+        match name.as_str() {
+            "databases" => return Err(failed("can't set immutable config")),
+            "hash-max-listpack-entries" => self.hash_listpack.max_entries = parse()?,
+            "hash-max-listpack-value" => self.hash_listpack.max_value = parse()?,
+            "set-max-intset-entries" => self.set_max_intset_entries = parse()?,
+            "zset-max-listpack-entries" => self.zset_listpack.max_entries = parse()?,
+            "zset-max-listpack-value" => self.zset_listpack.max_value = parse()?,
+            "hll-sparse-max-bytes" => self.hll_sparse_max_bytes = parse()?,
+            _ => {
+                return Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use crate::random;
+
+const MIN_BUCKETS: usize = 4;
+
+/// Chained hash table keyed by strings.
+///
+/// Unlike `std::collections::HashMap` the bucket layout is observable through
+/// [`Dict::scan`], which walks buckets with a reverse-binary cursor. The table
+/// size is always a power of two, so a key that is present for the whole
+/// iteration is visited at least once even if the table grows or shrinks
+/// between two calls.
+#[derive(Debug)]
+pub struct Dict<V> {
+    buckets: Vec<Vec<(String, V)>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn new() -> Self {
+        Self {
+            buckets: Self::empty_buckets(MIN_BUCKETS),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn empty_buckets(n: usize) -> Vec<Vec<(String, V)>> {
+        (0..n).map(|_| Vec::new()).collect()
+    }
+
+    fn mask(&self) -> usize {
+        self.buckets.len() - 1
+    }
+
+    fn bucket_of(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize & self.mask()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.buckets[self.bucket_of(key)]
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let idx = self.bucket_of(key);
+        self.buckets[idx]
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Inserts `value`, returning the previous value stored under `key`.
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(slot) = self.get_mut(&key) {
+            return Some(std::mem::replace(slot, value));
+        }
+        self.insert_new(key, value);
+        None
+    }
+
+    fn insert_new(&mut self, key: String, value: V) -> &mut V {
+        if self.len >= self.buckets.len() {
+            self.resize(self.buckets.len() * 2);
+        }
+        let idx = self.bucket_of(&key);
+        self.len += 1;
+        let bucket = &mut self.buckets[idx];
+        bucket.push((key, value));
+        &mut bucket.last_mut().unwrap().1
+    }
+
+    /// Returns the value under `key`, inserting the result of `f` if absent.
+    pub fn get_or_insert_with(&mut self, key: String, f: impl FnOnce() -> V) -> &mut V {
+        let idx = self.bucket_of(&key);
+        match self.buckets[idx].iter().position(|(k, _)| *k == key) {
+            Some(pos) => &mut self.buckets[idx][pos].1,
+            None => self.insert_new(key, f()),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let idx = self.bucket_of(key);
+        let pos = self.buckets[idx].iter().position(|(k, _)| k == key)?;
+        let (_, value) = self.buckets[idx].swap_remove(pos);
+        self.len -= 1;
+        self.shrink_if_sparse();
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&String, &mut V) -> bool) {
+        for bucket in &mut self.buckets {
+            bucket.retain_mut(|(k, v)| f(k, v));
+        }
+        self.len = self.buckets.iter().map(Vec::len).sum();
+        self.shrink_if_sparse();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.buckets.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    /// Returns a random entry. Buckets are picked uniformly, so entries in
+    /// longer chains are slightly less likely to come up.
+    pub fn random_entry(&self) -> Option<(&String, &V)> {
+        if self.len == 0 {
+            return None;
+        }
+        loop {
+            let bucket = &self.buckets[random::below(self.buckets.len())];
+            if !bucket.is_empty() {
+                let (k, v) = &bucket[random::below(bucket.len())];
+                return Some((k, v));
+            }
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// Visits every entry of the bucket addressed by `cursor` and returns the
+    /// cursor of the next bucket, or 0 once the whole table has been covered.
+    ///
+    /// The cursor is incremented on its reversed bits, so the high bits are
+    /// the ones that change fastest. Buckets that split or merge on resize
+    /// share their low bits, which is what makes the walk resize-safe.
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(&String, &V)) -> u64 {
+        let mask = self.mask() as u64;
+        for (k, v) in &self.buckets[(cursor & mask) as usize] {
+            f(k, v);
+        }
+
+        let mut next = cursor | !mask;
+        next = next.reverse_bits().wrapping_add(1).reverse_bits();
+        next
+    }
+
+    fn shrink_if_sparse(&mut self) {
+        let size = self.buckets.len();
+        if size > MIN_BUCKETS && self.len * 8 < size {
+            self.resize(self.len.next_power_of_two().max(MIN_BUCKETS));
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.buckets, Self::empty_buckets(size));
+        for (key, value) in old.into_iter().flatten() {
+            let idx = self.bucket_of(&key);
+            self.buckets[idx].push((key, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn dict_of(keys: impl IntoIterator<Item = usize>) -> Dict<usize> {
+        let mut dict = Dict::new();
+        for i in keys {
+            dict.insert(format!("key:{}", i), i);
+        }
+        dict
+    }
+
+    /// Runs a full SCAN, calling `between` after every step, and returns
+    /// every key it reported.
+    fn scan_all(
+        dict: &mut Dict<usize>,
+        mut between: impl FnMut(&mut Dict<usize>),
+    ) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |k, _| {
+                seen.insert(k.clone());
+            });
+            if cursor == 0 {
+                return seen;
+            }
+            between(dict);
+        }
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut dict = dict_of(0..100);
+        assert_eq!(dict.len, 100);
+        assert_eq!(dict.insert("key:7".to_string(), 70), Some(7));
+        assert_eq!(dict.get("key:7"), Some(&70));
+        assert_eq!(*dict.get_or_insert_with("key:8".to_string(), || 0), 8);
+        assert_eq!(*dict.get_or_insert_with("new".to_string(), || 1), 1);
+        assert_eq!(dict.len, 101);
+
+        for i in 0..100 {
+            assert!(dict.remove(&format!("key:{}", i)).is_some());
+        }
+        assert_eq!(dict.remove("key:0"), None);
+        assert_eq!(dict.len, 1);
+        assert_eq!(dict.buckets.len(), MIN_BUCKETS);
+    }
+
+    #[test]
+    fn retain_recounts_and_shrinks() {
+        let mut dict = dict_of(0..1000);
+        dict.retain(|_, v| *v % 100 == 0);
+        assert_eq!(dict.len, 10);
+        assert_eq!(dict.keys().count(), 10);
+        assert!(dict.buckets.len() <= 16);
+    }
+
+    #[test]
+    fn random_entry_returns_a_present_key() {
+        assert!(Dict::<usize>::new().random_entry().is_none());
+        let dict = dict_of(0..50);
+        for _ in 0..100 {
+            let (k, v) = dict.random_entry().unwrap();
+            assert_eq!(dict.get(k), Some(v));
+        }
+    }
+
+    #[test]
+    fn scan_visits_every_key() {
+        let mut dict = dict_of(0..1000);
+        let seen = scan_all(&mut dict, |_| {});
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[test]
+    fn scan_misses_no_keys_while_growing() {
+        let mut dict = dict_of(0..100);
+        let mut fresh = 100..2000;
+        let seen = scan_all(&mut dict, |dict| {
+            for i in fresh.by_ref().take(20) {
+                dict.insert(format!("key:{}", i), i);
+            }
+        });
+        assert!(dict.buckets.len() > 128);
+        for i in 0..100 {
+            assert!(seen.contains(&format!("key:{}", i)), "missed key:{}", i);
+        }
+    }
+
+    #[test]
+    fn scan_misses_no_keys_while_shrinking() {
+        let mut dict = dict_of(0..2000);
+        let mut doomed = 100..2000;
+        let seen = scan_all(&mut dict, |dict| {
+            for i in doomed.by_ref().take(50) {
+                dict.remove(&format!("key:{}", i));
+            }
+        });
+        assert!(dict.buckets.len() < 2048);
+        for i in 0..100 {
+            assert!(seen.contains(&format!("key:{}", i)), "missed key:{}", i);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::resp::to_bytes;
+
+/// Redis-compatible glob matching, as used by `KEYS` and `SCAN ... MATCH`.
+///
+/// Supports `*`, `?`, character classes (`[abc]`, `[a-z]`, `[^x]`) and `\`
+/// to escape any of the special characters. Matching works on the raw bytes
+/// the strings were decoded from, so `?` always consumes exactly one byte.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    matches(&to_bytes(pattern), &to_bytes(string))
+}
+
+/// Classic star backtracking: on a mismatch, the most recent `*` absorbs one
+/// more byte and matching resumes right after it. Every other token consumes
+/// exactly one byte, so this runs in O(pattern * string) time rather than
+/// exponentially in the number of stars.
+fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Pattern offset just past the last `*`, and where in the string it
+    // stopped absorbing.
+    let mut backtrack = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+        match match_one(&pattern[p..], string[s]) {
+            Some(len) => {
+                p += len;
+                s += 1;
+            }
+            None => match backtrack {
+                Some((star_p, star_s)) => {
+                    p = star_p;
+                    s = star_s + 1;
+                    backtrack = Some((star_p, s));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches the token at the start of `p` against one byte, returning how
+/// many pattern bytes the token spans.
+fn match_one(p: &[u8], ch: u8) -> Option<usize> {
+    match *p {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', ref class @ ..] => {
+            let (negate, mut rest) = match class {
+                [b'^', rest @ ..] => (true, rest),
+                _ => (false, class),
+            };
+
+            let mut matched = false;
+            loop {
+                match rest {
+                    [] | [b']', ..] => break,
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == ch;
+                        rest = tail;
+                    }
+                    [start, b'-', end, tail @ ..] => {
+                        let (lo, hi) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        matched |= (lo..=hi).contains(&ch);
+                        rest = tail;
+                    }
+                    [other, tail @ ..] => {
+                        matched |= *other == ch;
+                        rest = tail;
+                    }
+                }
+            }
+
+            // Include the closing bracket; an unterminated class simply ends
+            // the pattern.
+            let len = p.len() - rest.len() + usize::from(!rest.is_empty());
+            (matched != negate).then_some(len)
+        }
+        [b'\\', literal, ..] => (literal == ch).then_some(2),
+        [literal, ..] => (literal == ch).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_and_classes() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("h[b-a]llo", "hallo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("[abc", "b"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_raw_byte() {
+        assert!(glob_match("k?y", "k\u{e9}y"));
+        assert!(glob_match("k[\u{e0}-\u{ff}]y", "k\u{e9}y"));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let key = "a".repeat(10_000);
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*b", &key));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*", &key));
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::command::{ExpireCondition, TtlFormat};
+use crate::config::ListpackLimits;
+use crate::dict::Dict;
+use crate::random;
+use crate::resp::byte_len;
+
+/// Hash value with two encodings, mirroring Redis.
+///
+/// Small hashes are kept as a flat vector of field/value pairs (the
+/// "listpack"), which is compact and fast to scan linearly. Once the hash
+/// grows past the configured `hash-max-listpack-*` limits it is converted to
+/// a [`Dict`] for the rest of its life.
+///
+/// Fields may carry their own expiration, stored as an absolute unix time in
+/// milliseconds so it means the same thing after a restart or on a replica.
+#[derive(Debug)]
+pub struct Hash {
+    encoding: Encoding,
+    ttls: HashMap<String, u64>,
+    // Same expirations as `ttls`, ordered so the next one is found quickly.
+    expiry_order: BTreeSet<(u64, String)>,
+}
+
+#[derive(Debug)]
+enum Encoding {
+    Listpack(Vec<(String, String)>),
+    Table(Dict<String>),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self {
+            encoding: Encoding::Listpack(Vec::new()),
+            ttls: HashMap::new(),
+            expiry_order: BTreeSet::new(),
+        }
+    }
+
+    pub fn encoding_name(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Listpack(_) if !self.ttls.is_empty() => "listpackex",
+            Encoding::Listpack(_) => "listpack",
+            Encoding::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(pairs) => pairs.len(),
+            Encoding::Table(dict) => dict.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        match &self.encoding {
+            Encoding::Listpack(pairs) => pairs.iter().find(|(f, _)| f == field).map(|(_, v)| v),
+            Encoding::Table(dict) => dict.get(field),
+        }
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Sets `field` to `value`, returning true if the field is new. Converts
+    /// to a hash table when the listpack limits are exceeded. The field keeps
+    /// its TTL, if any; callers that overwrite it call [`Hash::persist`].
+    pub fn insert(&mut self, field: String, value: String, limits: ListpackLimits) -> bool {
+        if let Encoding::Listpack(pairs) = &mut self.encoding {
+            if let Some(slot) = pairs.iter_mut().find(|(f, _)| *f == field) {
+                slot.1 = value;
+                if !limits.allows(0, byte_len(&slot.1)) {
+                    self.convert_to_table();
+                }
+                return false;
+            }
+
+            if limits.allows(pairs.len() + 1, byte_len(&field).max(byte_len(&value))) {
+                pairs.push((field, value));
+                return true;
+            }
+            self.convert_to_table();
+        }
+
+        match &mut self.encoding {
+            Encoding::Table(dict) => dict.insert(field, value).is_none(),
+            Encoding::Listpack(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, field: &str) -> bool {
+        self.persist(field);
+        match &mut self.encoding {
+            Encoding::Listpack(pairs) => match pairs.iter().position(|(f, _)| f == field) {
+                Some(pos) => {
+                    pairs.remove(pos);
+                    true
+                }
+                None => false,
+            },
+            Encoding::Table(dict) => dict.remove(field).is_some(),
+        }
+    }
+
+    /// The absolute unix time in milliseconds at which `field` expires.
+    pub fn expire_time(&self, field: &str) -> Option<u64> {
+        self.ttls.get(field).copied()
+    }
+
+    /// Sets the expiration of an existing field.
+    pub fn set_expire(&mut self, field: &str, at: u64) {
+        self.persist(field);
+        self.ttls.insert(field.to_string(), at);
+        self.expiry_order.insert((at, field.to_string()));
+    }
+
+    /// One field of `HEXPIRE` and friends: sets `field` to expire at `at` if
+    /// `cond` allows it, deleting it right away when `at` is not after `now`.
+    /// Returns the per-field reply code: -2 for a missing field, 0 when the
+    /// condition fails, 1 when the TTL was set and 2 when the field was
+    /// deleted.
+    pub fn expire_field(&mut self, field: &str, at: u64, cond: ExpireCondition, now: u64) -> i64 {
+        if !self.contains(field) {
+            return -2;
+        }
+        let current = self.expire_time(field);
+        let allowed = match cond {
+            ExpireCondition::Always => true,
+            ExpireCondition::Nx => current.is_none(),
+            ExpireCondition::Xx => current.is_some(),
+            ExpireCondition::Gt => current.is_some_and(|c| at > c),
+            // A field without a TTL counts as expiring never, so any time is
+            // less than it.
+            ExpireCondition::Lt => current.is_none_or(|c| at < c),
+        };
+        if !allowed {
+            0
+        } else if at <= now {
+            self.remove(field);
+            2
+        } else {
+            self.set_expire(field, at);
+            1
+        }
+    }
+
+    /// One field of `HTTL` and friends: -2 for a missing field, -1 for one
+    /// without a TTL, otherwise its expiration in `format`. Seconds are
+    /// rounded up, so a field with any time left never reports 0.
+    pub fn ttl(&self, field: &str, format: TtlFormat, now: u64) -> i64 {
+        if !self.contains(field) {
+            return -2;
+        }
+        let Some(at) = self.expire_time(field) else {
+            return -1;
+        };
+        let at = at as i64;
+        let now = now as i64;
+        match format {
+            TtlFormat::Ttl => (at - now + 999) / 1000,
+            TtlFormat::PTtl => at - now,
+            TtlFormat::ExpireTime => (at + 999) / 1000,
+            TtlFormat::PExpireTime => at,
+        }
+    }
+
+    /// Clears the expiration of `field`, returning whether it had one.
+    pub fn persist(&mut self, field: &str) -> bool {
+        match self.ttls.remove(field) {
+            Some(at) => {
+                self.expiry_order.remove(&(at, field.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the next field is due to expire.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.expiry_order.first().map(|(at, _)| *at)
+    }
+
+    /// Removes every field whose expiration is at or before `now`, returning
+    /// how many were dropped.
+    pub fn remove_expired(&mut self, now: u64) -> usize {
+        let mut removed = 0;
+        while let Some((at, field)) = self.expiry_order.first().cloned() {
+            if at > now {
+                break;
+            }
+            self.remove(&field);
+            removed += 1;
+        }
+        removed
+    }
+
+    fn convert_to_table(&mut self) {
+        if let Encoding::Listpack(pairs) = &mut self.encoding {
+            let mut dict = Dict::new();
+            for (field, value) in pairs.drain(..) {
+                dict.insert(field, value);
+            }
+            self.encoding = Encoding::Table(dict);
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&String, &String)> + '_> {
+        match &self.encoding {
+            Encoding::Listpack(pairs) => Box::new(pairs.iter().map(|(f, v)| (f, v))),
+            Encoding::Table(dict) => Box::new(dict.iter()),
+        }
+    }
+
+    /// One `HSCAN` step. A listpack is small enough to be returned whole,
+    /// so it always completes in a single call with cursor 0.
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(&String, &String)) -> u64 {
+        match &self.encoding {
+            Encoding::Listpack(pairs) => {
+                for (field, value) in pairs {
+                    f(field, value);
+                }
+                0
+            }
+            Encoding::Table(dict) => dict.scan(cursor, f),
+        }
+    }
+
+    pub fn random_entry(&self) -> Option<(&String, &String)> {
+        match &self.encoding {
+            Encoding::Listpack(pairs) if pairs.is_empty() => None,
+            Encoding::Listpack(pairs) => {
+                let (f, v) = &pairs[random::below(pairs.len())];
+                Some((f, v))
+            }
+            Encoding::Table(dict) => dict.random_entry(),
+        }
+    }
+}
+
+/// Largest field expiration accepted, in unix milliseconds (2^48 - 1).
+pub const MAX_EXPIRE_MS: u64 = (1 << 48) - 1;
+
+/// Current wall-clock time in unix milliseconds, the clock field expirations
+/// are measured against.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ListpackLimits = ListpackLimits {
+        max_entries: 4,
+        max_value: 16,
+    };
+
+    /// A hash with a field `plain` without a TTL and a field `timed` that
+    /// expires at 5000.
+    fn hash_with_ttl() -> Hash {
+        let mut hash = Hash::new();
+        hash.insert("plain".to_string(), "1".to_string(), LIMITS);
+        hash.insert("timed".to_string(), "2".to_string(), LIMITS);
+        hash.set_expire("timed", 5000);
+        hash
+    }
+
+    #[test]
+    fn field_ttls_survive_updates_and_conversion() {
+        let mut hash = hash_with_ttl();
+        assert_eq!(hash.encoding_name(), "listpackex");
+        assert_eq!(hash.next_expiry(), Some(5000));
+
+        // Moving a TTL leaves no stale entry in the expiry order.
+        hash.set_expire("timed", 7000);
+        hash.set_expire("plain", 6000);
+        assert_eq!(hash.expiry_order.len(), 2);
+        assert_eq!(hash.next_expiry(), Some(6000));
+
+        for i in 0..4 {
+            hash.insert(format!("f{}", i), "v".to_string(), LIMITS);
+        }
+        assert_eq!(hash.encoding_name(), "hashtable");
+        assert_eq!(hash.expire_time("timed"), Some(7000));
+
+        assert!(hash.persist("plain"));
+        assert!(!hash.persist("plain"));
+        assert!(hash.remove("timed"));
+        assert!(hash.ttls.is_empty());
+        assert!(hash.expiry_order.is_empty());
+        assert_eq!(hash.next_expiry(), None);
+    }
+
+    #[test]
+    fn remove_expired_drops_due_fields_only() {
+        let mut hash = Hash::new();
+        for (i, at) in [3000, 1000, 2000, 4000].into_iter().enumerate() {
+            let field = format!("f{}", i);
+            hash.insert(field.clone(), "v".to_string(), LIMITS);
+            hash.set_expire(&field, at);
+        }
+        hash.insert("kept".to_string(), "v".to_string(), LIMITS);
+
+        assert_eq!(hash.remove_expired(999), 0);
+        assert_eq!(hash.remove_expired(2000), 2);
+        assert!(!hash.contains("f1") && !hash.contains("f2"));
+        assert_eq!(hash.next_expiry(), Some(3000));
+        assert_eq!(hash.remove_expired(u64::MAX), 2);
+        assert_eq!(hash.len(), 1);
+        assert!(hash.ttls.is_empty());
+    }
+
+    #[test]
+    fn hexpire_conditions_pick_the_reply_code() {
+        let now = 1000;
+        let cases = [
+            (ExpireCondition::Always, "plain", 3000, 1),
+            (ExpireCondition::Always, "missing", 3000, -2),
+            (ExpireCondition::Nx, "plain", 3000, 1),
+            (ExpireCondition::Nx, "timed", 3000, 0),
+            (ExpireCondition::Xx, "plain", 3000, 0),
+            (ExpireCondition::Xx, "timed", 3000, 1),
+            // No TTL counts as never expiring: nothing is greater than it,
+            // anything is less.
+            (ExpireCondition::Gt, "plain", 9000, 0),
+            (ExpireCondition::Gt, "timed", 6000, 1),
+            (ExpireCondition::Gt, "timed", 5000, 0),
+            (ExpireCondition::Lt, "plain", 9000, 1),
+            (ExpireCondition::Lt, "timed", 4000, 1),
+            (ExpireCondition::Lt, "timed", 5000, 0),
+        ];
+        for (cond, field, at, code) in cases {
+            let mut hash = hash_with_ttl();
+            let before = hash.expire_time(field);
+            assert_eq!(
+                hash.expire_field(field, at, cond, now),
+                code,
+                "{:?} {}",
+                cond,
+                field
+            );
+            let expected = if code == 1 { Some(at) } else { before };
+            assert_eq!(hash.expire_time(field), expected);
+        }
+    }
+
+    #[test]
+    fn hexpire_in_the_past_deletes_unless_the_condition_fails() {
+        let mut hash = hash_with_ttl();
+        assert_eq!(hash.expire_field("timed", 0, ExpireCondition::Nx, 1000), 0);
+        assert!(hash.contains("timed"));
+        assert_eq!(
+            hash.expire_field("timed", 1000, ExpireCondition::Xx, 1000),
+            2
+        );
+        assert_eq!(
+            hash.expire_field("plain", 0, ExpireCondition::Always, 1000),
+            2
+        );
+        assert!(hash.is_empty());
+        assert!(hash.ttls.is_empty());
+    }
+
+    #[test]
+    fn httl_rounds_seconds_up() {
+        let mut hash = hash_with_ttl();
+        hash.set_expire("timed", 10_001);
+        assert_eq!(hash.ttl("timed", TtlFormat::Ttl, 9_000), 2);
+        assert_eq!(hash.ttl("timed", TtlFormat::Ttl, 8_001), 2);
+        assert_eq!(hash.ttl("timed", TtlFormat::Ttl, 8_000), 3);
+        assert_eq!(hash.ttl("timed", TtlFormat::Ttl, 10_000), 1);
+        assert_eq!(hash.ttl("timed", TtlFormat::PTtl, 9_000), 1001);
+        assert_eq!(hash.ttl("timed", TtlFormat::ExpireTime, 9_000), 11);
+        assert_eq!(hash.ttl("timed", TtlFormat::PExpireTime, 9_000), 10_001);
+
+        assert_eq!(hash.ttl("plain", TtlFormat::Ttl, 9_000), -1);
+        assert_eq!(hash.ttl("missing", TtlFormat::PTtl, 9_000), -2);
+    }
+}
@@ -0,0 +1,648 @@
+use crate::random;
+
+/// Number of bits of the hash used to pick a register.
+const P: u32 = 14;
+/// Number of bits left to count leading zeros in.
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+
+const HEADER_LEN: usize = 16;
+const MAGIC: &[u8; 4] = b"HYLL";
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+const DENSE_LEN: usize = HEADER_LEN + (REGISTERS * REGISTER_BITS).div_ceil(8);
+/// Set in the last byte of the cached cardinality when it is stale.
+const CACHE_INVALID: u8 = 1 << 7;
+
+// Sparse opcodes: ZERO is 00xxxxxx (up to 64 empty registers), XZERO is
+// 01xxxxxx yyyyyyyy (up to 16384 empty registers) and VAL is 1vvvvvxx (up
+// to 4 registers set to a value of at most 32).
+const ZERO_MAX_LEN: usize = 64;
+const XZERO_MAX_LEN: usize = 16384;
+const VAL_MAX_LEN: usize = 4;
+const VAL_MAX_VALUE: u8 = 32;
+
+const HASH_SEED: u64 = 0xadc83b19;
+/// 0.5 / ln(2), the bias correction constant as the register count grows.
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+/// The string is not a HyperLogLog, or its sparse opcodes do not add up to
+/// the register count.
+#[derive(Debug)]
+pub struct Corrupted;
+
+/// HyperLogLog with 16384 6-bit registers, giving a standard error of
+/// 0.81%.
+///
+/// It works directly on the byte layout Redis stores in string values, so
+/// the raw value can be moved between servers with `GET` and `SET`: a 16
+/// byte header (the `HYLL` magic, the encoding and a cached cardinality)
+/// followed by either the packed registers ("dense") or a run-length
+/// encoding of them ("sparse"). New HyperLogLogs start sparse and are
+/// promoted to dense once a register exceeds 32 or the encoding outgrows
+/// `hll-sparse-max-bytes`.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    /// An empty sparse HyperLogLog, with a valid cached cardinality of 0.
+    pub fn new() -> Self {
+        let mut bytes = header(SPARSE);
+        bytes.extend(encode_sparse(&[0; REGISTERS]));
+        Self { bytes }
+    }
+
+    /// Wraps the raw string value, checking only the header like Redis does;
+    /// sparse opcodes are validated as they are decoded.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return None;
+        }
+        match bytes[4] {
+            DENSE if bytes.len() == DENSE_LEN => Some(Self { bytes }),
+            SPARSE => Some(Self { bytes }),
+            _ => None,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.bytes[4] == SPARSE
+    }
+
+    /// Adds an element, returning whether any register changed.
+    pub fn add(&mut self, element: &[u8], sparse_max_bytes: usize) -> Result<bool, Corrupted> {
+        let (index, count) = pattern_len(element);
+        if self.is_sparse() && count > VAL_MAX_VALUE {
+            self.make_dense()?;
+        }
+        if self.is_sparse() {
+            if !self.sparse_set(index, count, sparse_max_bytes)? {
+                return Ok(false);
+            }
+        } else {
+            let dense = &mut self.bytes[HEADER_LEN..];
+            if dense_get(dense, index) >= count {
+                return Ok(false);
+            }
+            dense_set(dense, index, count);
+        }
+        self.invalidate_cache();
+        Ok(true)
+    }
+
+    /// Raises register `index` to `count` like Redis' `hllSparseSet`: only
+    /// the opcode covering the register is rewritten, as up to three
+    /// opcodes, and equal VAL opcodes around it are merged back together.
+    /// Promotes to dense if the result would outgrow `sparse_max_bytes`.
+    fn sparse_set(
+        &mut self,
+        index: usize,
+        count: u8,
+        sparse_max_bytes: usize,
+    ) -> Result<bool, Corrupted> {
+        let mut pos = HEADER_LEN;
+        let mut prev = None;
+        let mut first = 0;
+        let (op_len, value, run) = loop {
+            let (op, op_len) = read_op(self.bytes.get(pos..).ok_or(Corrupted)?)?;
+            let (value, run) = match op {
+                Op::Zero(len) | Op::XZero(len) => (0, len),
+                Op::Val(value, len) => (value, len),
+            };
+            if index < first + run {
+                break (op_len, value, run);
+            }
+            first += run;
+            prev = Some(pos);
+            pos += op_len;
+        };
+        if value >= count {
+            return Ok(false);
+        }
+
+        let mut ops = Vec::with_capacity(5);
+        encode_run(value, index - first, &mut ops);
+        encode_run(count, 1, &mut ops);
+        encode_run(value, first + run - index - 1, &mut ops);
+        if self.bytes.len() - op_len + ops.len() > sparse_max_bytes {
+            self.make_dense()?;
+            dense_set(&mut self.bytes[HEADER_LEN..], index, count);
+            return Ok(true);
+        }
+        self.bytes.splice(pos..pos + op_len, ops);
+
+        // Merge adjacent VAL opcodes with the same value, scanning from the
+        // opcode before the edit through the few that replaced it.
+        let mut pos = prev.unwrap_or(pos);
+        let mut scan = 5;
+        while scan > 0 && pos < self.bytes.len() {
+            let op = self.bytes[pos];
+            if let Some(&next) = self.bytes.get(pos + 1)
+                && op & 0x80 != 0
+                && next & 0x80 != 0
+                && (op >> 2) & 0x1f == (next >> 2) & 0x1f
+            {
+                let len = (op & 0x3) as usize + (next & 0x3) as usize + 2;
+                if len <= VAL_MAX_LEN {
+                    self.bytes[pos] = val_op(((op >> 2) & 0x1f) + 1, len);
+                    self.bytes.remove(pos + 1);
+                    continue;
+                }
+            }
+            pos += if op & 0xc0 == 0x40 { 2 } else { 1 };
+            scan -= 1;
+        }
+        Ok(true)
+    }
+
+    /// The register values, whatever the encoding.
+    pub fn registers(&self) -> Result<Vec<u8>, Corrupted> {
+        if self.is_sparse() {
+            decode_sparse(&self.bytes[HEADER_LEN..])
+        } else {
+            let dense = &self.bytes[HEADER_LEN..];
+            Ok((0..REGISTERS).map(|i| dense_get(dense, i)).collect())
+        }
+    }
+
+    /// Replaces the registers, using the sparse encoding when the current
+    /// one is sparse and the registers still fit it.
+    fn store(&mut self, registers: &[u8], sparse_max_bytes: usize) {
+        self.bytes.truncate(HEADER_LEN);
+        if self.is_sparse() && registers.iter().all(|&r| r <= VAL_MAX_VALUE) {
+            let ops = encode_sparse(registers);
+            if HEADER_LEN + ops.len() <= sparse_max_bytes {
+                self.bytes.extend(ops);
+                return;
+            }
+        }
+        self.bytes[4] = DENSE;
+        self.bytes.extend(encode_dense(registers));
+    }
+
+    /// Raises every register to at least its value in `registers`, as
+    /// `PFMERGE` does.
+    pub fn merge(&mut self, registers: &[u8], sparse_max_bytes: usize) -> Result<(), Corrupted> {
+        let mut merged = self.registers()?;
+        for (dst, &src) in merged.iter_mut().zip(registers) {
+            *dst = (*dst).max(src);
+        }
+        self.store(&merged, sparse_max_bytes);
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    /// Converts to the dense encoding, returning whether it was sparse.
+    pub fn make_dense(&mut self) -> Result<bool, Corrupted> {
+        if !self.is_sparse() {
+            return Ok(false);
+        }
+        let registers = self.registers()?;
+        self.store(&registers, 0);
+        Ok(true)
+    }
+
+    /// The estimated cardinality, served from and saved to the header cache.
+    pub fn count(&mut self) -> Result<u64, Corrupted> {
+        let cache: [u8; 8] = self.bytes[8..HEADER_LEN].try_into().unwrap();
+        if cache[7] & CACHE_INVALID == 0 {
+            return Ok(u64::from_le_bytes(cache));
+        }
+        let count = estimate(&self.registers()?);
+        self.bytes[8..HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+        Ok(count)
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.bytes[HEADER_LEN - 1] |= CACHE_INVALID;
+    }
+
+    /// Human readable sparse opcodes, as printed by `PFDEBUG DECODE`.
+    pub fn decode(&self) -> Result<String, Corrupted> {
+        let mut ops = Vec::new();
+        for_each_op(&self.bytes[HEADER_LEN..], |op| {
+            ops.push(match op {
+                Op::Zero(len) => format!("z:{}", len),
+                Op::XZero(len) => format!("Z:{}", len),
+                Op::Val(value, len) => format!("v:{},{}", value, len),
+            })
+        })?;
+        Ok(ops.join(" "))
+    }
+}
+
+fn header(encoding: u8) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend([encoding, 0, 0, 0]);
+    bytes.extend([0; 8]);
+    bytes
+}
+
+/// MurmurHash64A, the hash Redis uses so registers match across servers.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The register an element maps to, and the length of the run of zero bits
+/// after the index bits plus one.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HASH_SEED);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // The sentinel bit caps the count at Q + 1.
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(dense: &[u8], index: usize) -> u8 {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let low = dense[byte] as u16;
+    let high = dense.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((high << 8 | low) >> shift) as u8) & REGISTER_MAX
+}
+
+fn dense_set(dense: &mut [u8], index: usize, value: u8) {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let mask = (REGISTER_MAX as u16) << shift;
+    let bits = (value as u16) << shift;
+    dense[byte] = (dense[byte] & !(mask as u8)) | bits as u8;
+    if let Some(next) = dense.get_mut(byte + 1) {
+        *next = (*next & !((mask >> 8) as u8)) | (bits >> 8) as u8;
+    }
+}
+
+fn encode_dense(registers: &[u8]) -> Vec<u8> {
+    let mut dense = vec![0; DENSE_LEN - HEADER_LEN];
+    for (i, &value) in registers.iter().enumerate() {
+        dense_set(&mut dense, i, value);
+    }
+    dense
+}
+
+enum Op {
+    Zero(usize),
+    XZero(usize),
+    Val(u8, usize),
+}
+
+/// Reads the opcode at the start of `bytes`, returning it with its size.
+fn read_op(bytes: &[u8]) -> Result<(Op, usize), Corrupted> {
+    let &b = bytes.first().ok_or(Corrupted)?;
+    Ok(match b >> 6 {
+        0b00 => (Op::Zero((b & 0x3f) as usize + 1), 1),
+        0b01 => {
+            let &low = bytes.get(1).ok_or(Corrupted)?;
+            let len = (((b & 0x3f) as usize) << 8 | low as usize) + 1;
+            (Op::XZero(len), 2)
+        }
+        _ => (Op::Val(((b >> 2) & 0x1f) + 1, (b & 0x3) as usize + 1), 1),
+    })
+}
+
+fn val_op(value: u8, len: usize) -> u8 {
+    0x80 | (value - 1) << 2 | (len - 1) as u8
+}
+
+/// Walks the sparse opcodes, failing unless they cover exactly the
+/// register count.
+fn for_each_op(mut sparse: &[u8], mut f: impl FnMut(Op)) -> Result<(), Corrupted> {
+    let mut covered = 0;
+    while !sparse.is_empty() {
+        let (op, op_len) = read_op(sparse)?;
+        sparse = &sparse[op_len..];
+        covered += match op {
+            Op::Zero(len) | Op::XZero(len) | Op::Val(_, len) => len,
+        };
+        if covered > REGISTERS {
+            return Err(Corrupted);
+        }
+        f(op);
+    }
+    if covered == REGISTERS {
+        Ok(())
+    } else {
+        Err(Corrupted)
+    }
+}
+
+fn decode_sparse(sparse: &[u8]) -> Result<Vec<u8>, Corrupted> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    for_each_op(sparse, |op| match op {
+        Op::Zero(len) | Op::XZero(len) => registers.resize(registers.len() + len, 0),
+        Op::Val(value, len) => registers.resize(registers.len() + len, value),
+    })?;
+    Ok(registers)
+}
+
+/// Run-length encodes registers, all of which must be at most 32.
+fn encode_sparse(registers: &[u8]) -> Vec<u8> {
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|&&r| r == value).count();
+        encode_run(value, run, &mut ops);
+        i += run;
+    }
+    ops
+}
+
+/// Appends the opcodes for `run` registers holding `value`.
+fn encode_run(value: u8, mut run: usize, ops: &mut Vec<u8>) {
+    while run > 0 {
+        if value != 0 {
+            let len = run.min(VAL_MAX_LEN);
+            ops.push(val_op(value, len));
+            run -= len;
+        } else if run > ZERO_MAX_LEN {
+            let len = run.min(XZERO_MAX_LEN) - 1;
+            ops.extend([0x40 | (len >> 8) as u8, len as u8]);
+            run -= len + 1;
+        } else {
+            ops.push((run - 1) as u8);
+            run = 0;
+        }
+    }
+}
+
+/// Cardinality estimate from the register histogram, using Ertl's improved
+/// estimator ("New cardinality estimation algorithms for HyperLogLog
+/// sketches") like Redis 5 and later.
+fn estimate(registers: &[u8]) -> u64 {
+    let m = REGISTERS as f64;
+    let mut histogram = [0u32; 64];
+    for &r in registers {
+        histogram[r as usize] += 1;
+    }
+
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for &count in histogram[1..=Q as usize].iter().rev() {
+        z += count as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if z == prev {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == prev {
+            return z / 3.0;
+        }
+    }
+}
+
+/// The checks behind `PFSELFTEST`: dense registers read back what was
+/// written, sparse and dense HyperLogLogs agree with each other, and the
+/// estimates stay within five standard errors as elements are added.
+pub fn self_test(sparse_max_bytes: usize) -> Result<(), String> {
+    let mut dense = vec![0; DENSE_LEN - HEADER_LEN];
+    for _ in 0..1000 {
+        let values: Vec<u8> = (0..REGISTERS)
+            .map(|_| (random::next_u64() & REGISTER_MAX as u64) as u8)
+            .collect();
+        for (i, &value) in values.iter().enumerate() {
+            dense_set(&mut dense, i, value);
+        }
+        for (i, &value) in values.iter().enumerate() {
+            if dense_get(&dense, i) != value {
+                return Err(format!("TESTFAILED Register error at {}", i));
+            }
+        }
+    }
+
+    let mut sparse = HyperLogLog::new();
+    let mut dense = HyperLogLog::new();
+    dense.make_dense().unwrap();
+    let relative_error = 1.04 / (REGISTERS as f64).sqrt();
+    let mut checkpoint = 1;
+    let seed = random::next_u64();
+    for j in 1..=1_000_000u64 {
+        let element = (seed ^ j).to_le_bytes();
+        sparse.add(&element, sparse_max_bytes).unwrap();
+        dense.add(&element, sparse_max_bytes).unwrap();
+        if j != checkpoint {
+            continue;
+        }
+        checkpoint *= 10;
+
+        let sparse_count = sparse.count().unwrap();
+        let dense_count = dense.count().unwrap();
+        if sparse_count != dense_count {
+            return Err(format!(
+                "TESTFAILED dense/sparse disagree: {} != {}",
+                dense_count, sparse_count
+            ));
+        }
+        // Small cardinalities are nearly exact; allow some slack anyway.
+        let max_error = (relative_error * 5.0 * j as f64).max(1.0);
+        let error = (j as f64 - dense_count as f64).abs();
+        if error > max_error {
+            return Err(format!(
+                "TESTFAILED Too big error. card:{} abserr:{}",
+                j, error
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(i: u64) -> [u8; 8] {
+        i.to_le_bytes()
+    }
+
+    #[test]
+    fn new_is_a_single_xzero() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.bytes.len(), HEADER_LEN + 2);
+        assert_eq!(hll.decode().unwrap(), "Z:16384");
+        assert_eq!(hll.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_small_sets_exactly() {
+        let mut hll = HyperLogLog::new();
+        for e in ["a", "b", "c", "d", "e", "f", "g"] {
+            hll.add(e.as_bytes(), 3000).unwrap();
+        }
+        assert_eq!(hll.count().unwrap(), 7);
+    }
+
+    #[test]
+    fn sparse_set_in_place_matches_reference_registers() {
+        let mut hll = HyperLogLog::new();
+        let mut expected = vec![0; REGISTERS];
+        for i in 0..3000 {
+            let e = element(random::next_u64());
+            let (index, count) = pattern_len(&e);
+            let changed = hll.add(&e, usize::MAX).unwrap();
+            assert_eq!(changed, expected[index] < count);
+            expected[index] = expected[index].max(count);
+            if !hll.is_sparse() {
+                break;
+            }
+            if i % 100 == 0 {
+                assert_eq!(hll.registers().unwrap(), expected);
+            }
+        }
+        assert_eq!(hll.registers().unwrap(), expected);
+    }
+
+    #[test]
+    fn sparse_and_dense_agree() {
+        let mut sparse = HyperLogLog::new();
+        let mut dense = HyperLogLog::new();
+        dense.make_dense().unwrap();
+        for i in 0..2000 {
+            sparse.add(&element(i), 3000).unwrap();
+            dense.add(&element(i), 3000).unwrap();
+        }
+        assert_eq!(sparse.registers().unwrap(), dense.registers().unwrap());
+        assert_eq!(sparse.count().unwrap(), dense.count().unwrap());
+    }
+
+    #[test]
+    fn promotes_when_outgrowing_sparse_max_bytes() {
+        let mut hll = HyperLogLog::new();
+        let mut i = 0;
+        while hll.is_sparse() {
+            assert!(hll.bytes.len() <= 300);
+            hll.add(&element(i), 300).unwrap();
+            i += 1;
+        }
+        assert_eq!(hll.bytes.len(), DENSE_LEN);
+    }
+
+    #[test]
+    fn estimate_is_within_a_few_standard_errors() {
+        let mut hll = HyperLogLog::new();
+        let n = 200_000;
+        for i in 0..n {
+            hll.add(&element(i), 3000).unwrap();
+        }
+        let error = (hll.count().unwrap() as f64 - n as f64).abs() / n as f64;
+        assert!(error < 5.0 * 0.0081, "relative error {}", error);
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        for _ in 0..20 {
+            let registers: Vec<u8> = (0..REGISTERS)
+                .map(|_| match random::below(4) {
+                    0 => (random::below(VAL_MAX_VALUE as usize) + 1) as u8,
+                    _ => 0,
+                })
+                .collect();
+            assert_eq!(
+                decode_sparse(&encode_sparse(&registers)).unwrap(),
+                registers
+            );
+
+            let dense = encode_dense(&registers);
+            assert_eq!(dense.len(), DENSE_LEN - HEADER_LEN);
+            for (i, &r) in registers.iter().enumerate() {
+                assert_eq!(dense_get(&dense, i), r);
+            }
+        }
+
+        let mut dense = vec![0; DENSE_LEN - HEADER_LEN];
+        dense_set(&mut dense, REGISTERS - 1, REGISTER_MAX);
+        dense_set(&mut dense, REGISTERS - 2, 1);
+        assert_eq!(dense_get(&dense, REGISTERS - 1), REGISTER_MAX);
+        assert_eq!(dense_get(&dense, REGISTERS - 2), 1);
+    }
+
+    #[test]
+    fn cached_count_survives_unchanged_adds() {
+        let mut hll = HyperLogLog::new();
+        hll.add(b"a", 3000).unwrap();
+        assert_eq!(hll.count().unwrap(), 1);
+        assert_eq!(hll.bytes[HEADER_LEN - 1] & CACHE_INVALID, 0);
+        assert!(!hll.add(b"a", 3000).unwrap());
+        assert_eq!(hll.bytes[HEADER_LEN - 1] & CACHE_INVALID, 0);
+        assert!(hll.add(b"b", 3000).unwrap());
+        assert_ne!(hll.bytes[HEADER_LEN - 1] & CACHE_INVALID, 0);
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_corrupted_opcodes() {
+        assert!(HyperLogLog::from_bytes(b"HYLL".to_vec()).is_none());
+        assert!(HyperLogLog::from_bytes(header(DENSE)).is_none());
+        assert!(HyperLogLog::from_bytes(header(2)).is_none());
+
+        for ops in [&[0x7f][..], &[0x7f, 0xfe], &[0x7f, 0xff, 0x00], &[]] {
+            let mut bytes = header(SPARSE);
+            bytes.extend(ops);
+            let hll = HyperLogLog::from_bytes(bytes).unwrap();
+            assert!(hll.registers().is_err());
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+
+/// Maximum number of elements stored in a single node, matching the default
+/// `list-max-listpack-size` of Redis' quicklist.
+const NODE_CAPACITY: usize = 128;
+
+/// Adjacent nodes both holding fewer elements than this are merged, so that
+/// deletions do not leave a long list of nearly empty nodes behind.
+const MERGE_BELOW: usize = NODE_CAPACITY / 2;
+
+/// Which end of a list a push or pop operates on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+/// Quicklist-style list: a deque of bounded nodes.
+///
+/// Pushes and pops at either end touch only the outermost node, so they are
+/// O(1) and never move more than `NODE_CAPACITY` elements. Short lists live
+/// in a single small node, which keeps their overhead close to a plain
+/// vector, while long lists never need one huge contiguous allocation.
+#[derive(Debug, Default)]
+pub struct List {
+    nodes: VecDeque<VecDeque<String>>,
+    len: usize,
+}
+
+impl List {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, end: End, value: String) {
+        match end {
+            End::Left => self.push_front(value),
+            End::Right => self.push_back(value),
+        }
+    }
+
+    pub fn pop(&mut self, end: End) -> Option<String> {
+        match end {
+            End::Left => self.pop_front(),
+            End::Right => self.pop_back(),
+        }
+    }
+
+    pub fn push_front(&mut self, value: String) {
+        match self.nodes.front_mut() {
+            Some(node) if node.len() < NODE_CAPACITY => node.push_front(value),
+            _ => self.nodes.push_front(VecDeque::from([value])),
+        }
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, value: String) {
+        match self.nodes.back_mut() {
+            Some(node) if node.len() < NODE_CAPACITY => node.push_back(value),
+            _ => self.nodes.push_back(VecDeque::from([value])),
+        }
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        let node = self.nodes.front_mut()?;
+        let value = node.pop_front()?;
+        if node.is_empty() {
+            self.nodes.pop_front();
+        } else {
+            self.merge_pair(0);
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        let node = self.nodes.back_mut()?;
+        let value = node.pop_back()?;
+        if node.is_empty() {
+            self.nodes.pop_back();
+        } else if self.nodes.len() > 1 {
+            self.merge_pair(self.nodes.len() - 2);
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Finds the node holding `index` and the offset inside it, walking from
+    /// whichever end of the list is closer.
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+
+        if index < self.len / 2 {
+            let mut offset = index;
+            for (i, node) in self.nodes.iter().enumerate() {
+                if offset < node.len() {
+                    return Some((i, offset));
+                }
+                offset -= node.len();
+            }
+        } else {
+            let mut from_back = self.len - 1 - index;
+            for (i, node) in self.nodes.iter().enumerate().rev() {
+                if from_back < node.len() {
+                    return Some((i, node.len() - 1 - from_back));
+                }
+                from_back -= node.len();
+            }
+        }
+        None
+    }
+
+    pub fn get(&self, index: usize) -> Option<&String> {
+        let (node, offset) = self.locate(index)?;
+        self.nodes[node].get(offset)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut String> {
+        let (node, offset) = self.locate(index)?;
+        self.nodes[node].get_mut(offset)
+    }
+
+    /// Inserts `value` so that it ends up at `index`, splitting the target
+    /// node in half when it is already full.
+    pub fn insert(&mut self, index: usize, value: String) {
+        if index >= self.len {
+            self.push_back(value);
+            return;
+        }
+
+        let (mut node, mut offset) = self.locate(index).unwrap();
+        if self.nodes[node].len() >= NODE_CAPACITY {
+            let half = self.nodes[node].len() / 2;
+            let tail = self.nodes[node].split_off(half);
+            self.nodes.insert(node + 1, tail);
+            if offset >= half {
+                node += 1;
+                offset -= half;
+            }
+        }
+        self.nodes[node].insert(offset, value);
+        self.len += 1;
+        self.merge_around(node);
+    }
+
+    /// Removes up to `limit` elements equal to `value`, scanning from `end`.
+    /// A limit of 0 removes every occurrence.
+    pub fn remove_matching(&mut self, value: &str, limit: usize, end: End) -> usize {
+        let mut removed = 0;
+        let limit = if limit == 0 { usize::MAX } else { limit };
+
+        match end {
+            End::Left => {
+                for node in self.nodes.iter_mut() {
+                    node.retain(|v| {
+                        if removed < limit && v == value {
+                            removed += 1;
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                }
+            }
+            End::Right => {
+                'outer: for node in self.nodes.iter_mut().rev() {
+                    for i in (0..node.len()).rev() {
+                        if removed == limit {
+                            break 'outer;
+                        }
+                        if node[i] == value {
+                            node.remove(i);
+                            removed += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.nodes.retain(|node| !node.is_empty());
+        let mut node = 0;
+        while node + 1 < self.nodes.len() {
+            if !self.merge_pair(node) {
+                node += 1;
+            }
+        }
+        self.len -= removed;
+        removed
+    }
+
+    /// Keeps only the elements in `start..=stop`, dropping whole nodes where
+    /// possible. Indexes must already be clamped to the list bounds.
+    pub fn trim(&mut self, start: usize, stop: usize) {
+        let mut from_front = start;
+        let mut from_back = self.len - 1 - stop;
+
+        while let Some(node) = self.nodes.front_mut() {
+            if from_front == 0 {
+                break;
+            }
+            if node.len() <= from_front {
+                from_front -= node.len();
+                self.nodes.pop_front();
+            } else {
+                node.drain(..from_front);
+                from_front = 0;
+            }
+        }
+        while let Some(node) = self.nodes.back_mut() {
+            if from_back == 0 {
+                break;
+            }
+            if node.len() <= from_back {
+                from_back -= node.len();
+                self.nodes.pop_back();
+            } else {
+                node.truncate(node.len() - from_back);
+                from_back = 0;
+            }
+        }
+
+        self.len = stop - start + 1;
+        if let Some(last) = self.nodes.len().checked_sub(1) {
+            self.merge_around(last);
+            self.merge_around(0);
+        }
+    }
+
+    /// Merges the node after `left` into it when both are under
+    /// [`MERGE_BELOW`]. Returns whether they were merged.
+    fn merge_pair(&mut self, left: usize) -> bool {
+        let small = |node: Option<&VecDeque<String>>| node.is_some_and(|n| n.len() < MERGE_BELOW);
+        if !small(self.nodes.get(left)) || !small(self.nodes.get(left + 1)) {
+            return false;
+        }
+        let right = self.nodes.remove(left + 1).unwrap();
+        self.nodes[left].extend(right);
+        true
+    }
+
+    /// Merges node `node` with whichever neighbours it can be merged with.
+    fn merge_around(&mut self, node: usize) {
+        self.merge_pair(node);
+        if node > 0 {
+            self.merge_pair(node - 1);
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &String> {
+        self.nodes.iter().flatten()
+    }
+
+    /// Iterates the elements starting at `index`, seeking node by node
+    /// rather than element by element.
+    pub fn iter_from(&self, index: usize) -> impl Iterator<Item = &String> {
+        let mut node_idx = 0;
+        let mut offset = index;
+        while node_idx < self.nodes.len() && offset >= self.nodes[node_idx].len() {
+            offset -= self.nodes[node_idx].len();
+            node_idx += 1;
+        }
+
+        self.nodes.range(node_idx..).flatten().skip(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_sizes(list: &List) -> Vec<usize> {
+        list.nodes.iter().map(|node| node.len()).collect()
+    }
+
+    /// `0..n` pushed to the back, as strings.
+    fn numbers(n: usize) -> List {
+        let mut list = List::new();
+        for i in 0..n {
+            list.push_back(i.to_string());
+        }
+        list
+    }
+
+    #[test]
+    fn pushes_open_a_node_only_when_the_outer_one_is_full() {
+        let mut list = numbers(300);
+        assert_eq!(node_sizes(&list), [128, 128, 44]);
+        for i in 0..10 {
+            list.push_front(format!("-{}", i));
+        }
+        assert_eq!(node_sizes(&list), [10, 128, 128, 44]);
+        assert_eq!(list.len(), 310);
+    }
+
+    #[test]
+    fn pop_front_drops_drained_nodes() {
+        let mut list = numbers(130);
+        for i in 0..128 {
+            assert_eq!(list.pop_front(), Some(i.to_string()));
+        }
+        assert_eq!(node_sizes(&list), [2]);
+        assert_eq!(list.pop_front().as_deref(), Some("128"));
+        assert_eq!(list.pop_front().as_deref(), Some("129"));
+        assert_eq!(list.pop_front(), None);
+        assert!(list.is_empty());
+        assert!(list.nodes.is_empty());
+    }
+
+    #[test]
+    fn iter_from_seeks_across_nodes() {
+        let list = numbers(300);
+        for start in [0, 1, 127, 128, 129, 255, 256, 299, 300, 400] {
+            let expected: Vec<String> = (start..300).map(|i| i.to_string()).collect();
+            assert_eq!(list.iter_from(start).cloned().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn get_walks_from_the_nearer_end() {
+        // Uneven nodes: 5 pushed to the front, then 250 to the back.
+        let mut list = numbers(250);
+        for i in 1..=5 {
+            list.push_front(format!("-{}", i));
+        }
+        assert_eq!(node_sizes(&list), [5, 128, 122]);
+        let expected: Vec<String> = list.iter().cloned().collect();
+        for (i, value) in expected.iter().enumerate() {
+            assert_eq!(list.get(i), Some(value));
+        }
+        assert_eq!(list.get(255), None);
+
+        *list.get_mut(200).unwrap() = "x".to_string();
+        assert_eq!(list.get(200).map(String::as_str), Some("x"));
+    }
+
+    #[test]
+    fn insert_splits_a_full_node() {
+        let mut list = numbers(128);
+        list.insert(100, "a".to_string());
+        assert_eq!(node_sizes(&list), [64, 65]);
+        assert_eq!(list.get(100).map(String::as_str), Some("a"));
+        assert_eq!(list.get(101).map(String::as_str), Some("100"));
+
+        list.insert(10, "b".to_string());
+        assert_eq!(node_sizes(&list), [65, 65]);
+        assert_eq!(list.get(10).map(String::as_str), Some("b"));
+
+        list.insert(list.len(), "c".to_string());
+        assert_eq!(list.iter().last().map(String::as_str), Some("c"));
+        assert_eq!(list.len(), 131);
+    }
+
+    #[test]
+    fn remove_matching_honours_limit_and_direction() {
+        let mut list = List::new();
+        for i in 0..300 {
+            list.push_back(if i % 3 == 0 { "x" } else { "y" }.to_string());
+        }
+        list.push_back("z".to_string());
+
+        assert_eq!(list.remove_matching("x", 2, End::Left), 2);
+        assert_eq!(list.get(0).map(String::as_str), Some("y"));
+        assert_eq!(list.remove_matching("x", 1, End::Right), 1);
+        assert_eq!(list.get(list.len() - 3).map(String::as_str), Some("y"));
+        assert_eq!(list.remove_matching("x", 0, End::Left), 97);
+        assert_eq!(list.remove_matching("x", 0, End::Left), 0);
+        assert_eq!(list.len(), 201);
+
+        assert_eq!(list.remove_matching("y", 0, End::Right), 200);
+        assert_eq!(node_sizes(&list), [1]);
+    }
+
+    #[test]
+    fn trim_keeps_the_inclusive_range() {
+        let mut list = numbers(300);
+        list.trim(130, 260);
+        assert_eq!(list.len(), 131);
+        let kept: Vec<String> = list.iter().cloned().collect();
+        let expected: Vec<String> = (130..=260).map(|i| i.to_string()).collect();
+        assert_eq!(kept, expected);
+        assert_eq!(node_sizes(&list), [126, 5]);
+    }
+
+    #[test]
+    fn deletions_merge_small_neighbours() {
+        let mut list = List::new();
+        for i in 0..384 {
+            list.push_back(if i % 4 == 0 {
+                i.to_string()
+            } else {
+                "x".to_string()
+            });
+        }
+        assert_eq!(list.remove_matching("x", 0, End::Left), 288);
+        assert_eq!(node_sizes(&list), [64, 32]);
+        let kept: Vec<String> = list.iter().cloned().collect();
+        let expected: Vec<String> = (0..384).step_by(4).map(|i| i.to_string()).collect();
+        assert_eq!(kept, expected);
+
+        let mut list = numbers(256);
+        for _ in 0..70 {
+            list.pop_front();
+        }
+        assert_eq!(node_sizes(&list), [58, 128]);
+        for _ in 0..70 {
+            list.pop_back();
+        }
+        assert_eq!(node_sizes(&list), [116]);
+        assert_eq!(list.get(0).map(String::as_str), Some("70"));
+        assert_eq!(list.get(115).map(String::as_str), Some("185"));
+
+        let mut list = numbers(384);
+        list.trim(100, 160);
+        assert_eq!(node_sizes(&list), [61]);
+        assert_eq!(list.iter().next().map(String::as_str), Some("100"));
+    }
+}
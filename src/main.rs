@@ -1,48 +1,100 @@
+mod blocking;
 mod command;
+mod config;
+mod dict;
+mod glob;
+mod hash;
+mod hyperloglog;
+mod list;
+mod random;
 mod resp;
+mod set;
+mod skiplist;
 mod storage;
+mod stream;
+mod zset;
 
 use crate::{
     command::Command,
-    resp::{RespValue, parse_resp},
-    storage::{Db, execute_command},
+    resp::{RespParser, RespValue},
+    storage::{Client, Db, Store, execute_command},
 };
 
 use std::{
-    collections::HashMap,
     io::{Read, Write},
     net::TcpListener,
-    sync::{Arc, Condvar, Mutex},
+    sync::{Arc, Mutex},
     thread,
+    time::Duration,
 };
 
+const DEFAULT_DATABASES: usize = 16;
+/// How often expired hash fields are reaped in the background (Redis' `hz`).
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+
 fn main() {
-    let db: Db = Arc::new((Mutex::new(HashMap::new()), Condvar::new()));
+    let mut databases = DEFAULT_DATABASES;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--databases" {
+            databases = args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0)
+                .expect("--databases requires a positive integer");
+        }
+    }
+
+    let db: Db = Arc::new(Mutex::new(Store::new(databases)));
     let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
 
+    let cron_db = db.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(ACTIVE_EXPIRE_PERIOD);
+            cron_db.lock().unwrap().active_expire_cycle();
+        }
+    });
+
+    let mut next_client_id = 0;
     for stream in listener.incoming() {
         match stream {
             Ok(mut stream) => {
                 let db_clone = db.clone();
+                next_client_id += 1;
+                let client_id = next_client_id;
 
                 thread::spawn(move || {
+                    let mut client = Client::new(client_id, stream.try_clone().ok());
+
+                    let mut parser = RespParser::default();
+                    let mut buffer = [0; 4096];
+
                     loop {
-                        let mut buffer = [0; 512];
-                        let bytes_read = stream.read(&mut buffer);
-
-                        match bytes_read {
-                            Ok(0) => break,
-                            Ok(n) => {
-                                let input = String::from_utf8_lossy(&buffer[..n]);
-                                if let Some(resp_data) = parse_resp(&input) {
-                                    let response_to_send = match Command::from_resp(resp_data) {
-                                        Ok(cmd) => execute_command(cmd, &db_clone),
-                                        Err(e) => RespValue::Error(e),
-                                    };
-                                    let _ = stream.write_all(&response_to_send.serialize());
+                        match stream.read(&mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => parser.feed(&buffer[..n]),
+                        }
+
+                        // A single read may carry several pipelined commands,
+                        // or only part of one.
+                        let mut open = true;
+                        while open {
+                            let response_to_send = match parser.next_frame() {
+                                Ok(Some(resp_data)) => match Command::from_resp(resp_data) {
+                                    Ok(cmd) => execute_command(cmd, &db_clone, &mut client),
+                                    Err(e) => RespValue::Error(e),
+                                },
+                                Ok(None) => break,
+                                Err(e) => {
+                                    open = false;
+                                    RespValue::Error(e)
                                 }
-                            }
-                            Err(_) => break,
+                            };
+                            open &= stream.write_all(&response_to_send.serialize()).is_ok();
+                        }
+                        if !open {
+                            break;
                         }
                     }
                 });
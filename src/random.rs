@@ -0,0 +1,107 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+thread_local! {
+    // Seeded from std's per-process random keys; must never be zero.
+    static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+/// Fast xorshift64* generator for sampling commands such as `HRANDFIELD`.
+/// Not suitable for anything security related.
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// Returns a value in `0..n`. `n` must not be zero.
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
+
+/// Shuffles `items` in place (Fisher-Yates).
+pub fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, below(i + 1));
+    }
+}
+
+/// Picks entries the way `HRANDFIELD`-style commands do. A non-negative
+/// `count` yields distinct entries, at most `len` of them; a negative count
+/// yields exactly `-count` entries that may repeat. `all` lists every entry
+/// and `pick` draws a single random one.
+///
+/// Returns `None` when a negative count asks for more entries than can be
+/// allocated; callers reply "value is out of range" rather than aborting
+/// with the store locked.
+pub fn try_sample<T: Copy + Eq + Hash>(
+    count: i64,
+    len: usize,
+    all: impl FnOnce() -> Vec<T>,
+    mut pick: impl FnMut() -> T,
+) -> Option<Vec<T>> {
+    if count < 0 {
+        let count = usize::try_from(count.unsigned_abs()).ok()?;
+        let mut picked = Vec::new();
+        picked.try_reserve_exact(count).ok()?;
+        picked.extend((0..count).map(|_| pick()));
+        return Some(picked);
+    }
+
+    let count = count as usize;
+    if count >= len {
+        return Some(all());
+    }
+    // Asking for a large share of the entries: shuffling everything is
+    // cheaper than rejecting duplicates.
+    if count * 3 > len {
+        let mut entries = all();
+        shuffle(&mut entries);
+        entries.truncate(count);
+        return Some(entries);
+    }
+
+    let mut seen = HashSet::new();
+    let mut picked = Vec::with_capacity(count);
+    while picked.len() < count {
+        let entry = pick();
+        if seen.insert(entry) {
+            picked.push(entry);
+        }
+    }
+    Some(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positive_counts_pick_distinct_entries() {
+        for count in [0, 1, 3, 9, 10, 50] {
+            let picked = try_sample(count, 10, || (0..10).collect(), || below(10)).unwrap();
+            assert_eq!(picked.len(), (count as usize).min(10));
+            let distinct: HashSet<_> = picked.iter().collect();
+            assert_eq!(distinct.len(), picked.len());
+        }
+    }
+
+    #[test]
+    fn negative_counts_may_repeat() {
+        let picked = try_sample(-25, 2, || vec![0, 1], || below(2)).unwrap();
+        assert_eq!(picked.len(), 25);
+    }
+
+    #[test]
+    fn unallocatable_counts_are_rejected() {
+        assert!(try_sample(i64::MIN, 2, || vec![0, 1], || below(2)).is_none());
+        assert!(try_sample(-i64::MAX, 2, || vec![0, 1], || below(2)).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum RespValue {
@@ -13,9 +15,16 @@ pub enum RespValue {
 impl RespValue {
     pub fn serialize(self) -> Vec<u8> {
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
-            RespValue::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
-            RespValue::Error(msg) => format!("-ERR {}\r\n", msg).into_bytes(),
+            RespValue::SimpleString(s) => to_bytes(&format!("+{}\r\n", s)),
+            RespValue::BulkString(s) => {
+                let data = to_bytes(&s);
+                let mut out = format!("${}\r\n", data.len()).into_bytes();
+                out.extend_from_slice(&data);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            RespValue::Error(msg) if has_error_code(&msg) => to_bytes(&format!("-{}\r\n", msg)),
+            RespValue::Error(msg) => to_bytes(&format!("-ERR {}\r\n", msg)),
             RespValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
             RespValue::Null => b"$-1\r\n".to_vec(),
             RespValue::NullArray => b"*-1\r\n".to_vec(),
@@ -30,29 +39,355 @@ impl RespValue {
     }
 }
 
-pub fn parse_resp(input: &str) -> Option<RespValue> {
-    let mut lines = input.lines();
-    let first_line = lines.next()?;
+/// Decodes wire bytes into a string holding one `char` per byte (Latin-1),
+/// so that arbitrary binary values survive the trip through the keyspace.
+pub fn from_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Inverse of [`from_bytes`]. Every string that reaches the wire was either
+/// decoded by it or is plain ASCII, so no char is wider than a byte.
+pub fn to_bytes(s: &str) -> Vec<u8> {
+    s.chars().map(|c| c as u8).collect()
+}
+
+/// Length of `s` on the wire. Bytes of 0x80 and above take two bytes in the
+/// `String`, so `str::len` over-counts them.
+pub fn byte_len(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// Error codes that messages may already start with. Anything else is sent
+/// with the generic `ERR` code.
+const ERROR_CODES: &[&str] = &[
+    "ERR",
+    "WRONGTYPE",
+    "UNBLOCKED",
+    "NOGROUP",
+    "BUSYGROUP",
+    "INVALIDOBJ",
+];
+
+fn has_error_code(msg: &str) -> bool {
+    let code = msg.split(' ').next().unwrap_or_default();
+    ERROR_CODES.contains(&code)
+}
+
+/// Longest header line accepted before its `\r\n` arrives, as Redis'
+/// `PROTO_INLINE_MAX_SIZE`.
+const MAX_LINE: usize = 64 * 1024;
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Incremental RESP reader for one connection.
+///
+/// Bytes are [`feed`](Self::feed) in as they are read from the socket and
+/// complete frames taken out with [`next_frame`](Self::next_frame). A frame
+/// that is only partly buffered keeps its progress (the arguments read so
+/// far, a pending bulk length, how far the current line was searched), so
+/// every byte is looked at once however the frame is split across reads.
+#[derive(Default)]
+pub struct RespParser {
+    buf: Vec<u8>,
+    // Start of the bytes not parsed yet.
+    pos: usize,
+    // Offset from which to keep searching for the current line's `\r\n`.
+    scanned: usize,
+    multibulk: Option<Multibulk>,
+    // Length of a bulk string whose `$` header was read but not its data.
+    bulk_len: Option<usize>,
+}
+
+struct Multibulk {
+    len: usize,
+    args: Vec<RespValue>,
+}
+
+impl RespParser {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        // Drop parsed bytes once they make up half the buffer, so the copy
+        // is paid for by the bytes that were consumed.
+        if self.pos == self.buf.len() || self.pos > self.buf.len() / 2 {
+            self.buf.drain(..self.pos);
+            self.scanned = self.scanned.saturating_sub(self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Takes the next complete frame, or `None` if more input is needed.
+    /// An error leaves the parser unusable; the connection must be closed.
+    pub fn next_frame(&mut self) -> Result<Option<RespValue>, String> {
+        loop {
+            if let Some(mut multibulk) = self.multibulk.take() {
+                while multibulk.args.len() < multibulk.len {
+                    let Some(arg) = self.read_bulk()? else {
+                        self.multibulk = Some(multibulk);
+                        return Ok(None);
+                    };
+                    multibulk.args.push(RespValue::BulkString(arg));
+                }
+                return Ok(Some(RespValue::Array(multibulk.args)));
+            }
+            if self.bulk_len.is_some() {
+                return Ok(self.read_bulk()?.map(RespValue::BulkString));
+            }
+
+            match self.buf.get(self.pos) {
+                Some(b'*') => {
+                    let Some(line) =
+                        self.read_line("Protocol error: too big mbulk count string")?
+                    else {
+                        return Ok(None);
+                    };
+                    let len = std::str::from_utf8(&self.buf[line.start + 1..line.end])
+                        .ok()
+                        .and_then(|digits| digits.parse::<i64>().ok())
+                        .filter(|&len| len <= MAX_MULTIBULK_LEN)
+                        .ok_or("Protocol error: invalid multibulk length")?;
+                    // Like Redis, `*0` and `*-1` are skipped without a reply.
+                    if len > 0 {
+                        let len = len as usize;
+                        self.multibulk = Some(Multibulk {
+                            len,
+                            args: Vec::with_capacity(len.min(1024)),
+                        });
+                    }
+                }
+                Some(b'+') => {
+                    let Some(line) = self.read_line("Protocol error: too big inline request")?
+                    else {
+                        return Ok(None);
+                    };
+                    let text = from_bytes(&self.buf[line.start + 1..line.end]);
+                    return Ok(Some(RespValue::SimpleString(text)));
+                }
+                Some(b'$') => return Ok(self.read_bulk()?.map(RespValue::BulkString)),
+                Some(&b) => {
+                    return Err(format!("Protocol error: expected '*', got '{}'", b as char));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
 
-    match &first_line[0..1] {
-        "*" => {
-            let len = first_line[1..].parse::<usize>().ok()?;
-            let mut elements = Vec::with_capacity(len);
+    /// Splits off the `\r\n` terminated line at the read position, returning
+    /// its range without the terminator. `too_long` is the error raised when
+    /// no terminator shows up within [`MAX_LINE`] bytes.
+    fn read_line(&mut self, too_long: &str) -> Result<Option<Range<usize>>, String> {
+        let from = self.scanned.max(self.pos);
+        match self.buf[from..].windows(2).position(|w| w == b"\r\n") {
+            Some(offset) => {
+                let line = self.pos..from + offset;
+                self.pos = line.end + 2;
+                Ok(Some(line))
+            }
+            None if self.buf.len() - self.pos > MAX_LINE => Err(too_long.to_string()),
+            None => {
+                // The last byte may be the `\r` of a terminator split in two.
+                self.scanned = self.buf.len().saturating_sub(1);
+                Ok(None)
+            }
+        }
+    }
 
-            for _ in 0..len {
-                let prefix = lines.next()?;
-                if prefix.starts_with("$") {
-                    let data = lines.next()?;
-                    elements.push(RespValue::BulkString(data.to_string()));
+    /// Reads a `$<len>\r\n<data>\r\n` bulk string. Once the header is read
+    /// the length is kept, and only the buffered size is checked until the
+    /// whole of the data is in.
+    fn read_bulk(&mut self) -> Result<Option<String>, String> {
+        let len = match self.bulk_len {
+            Some(len) => len,
+            None => {
+                let Some(line) = self.read_line("Protocol error: too big bulk count string")?
+                else {
+                    return Ok(None);
+                };
+                let header = &self.buf[line];
+                match header.first() {
+                    Some(b'$') => {}
+                    Some(&b) => {
+                        return Err(format!("Protocol error: expected '$', got '{}'", b as char));
+                    }
+                    None => return Err("Protocol error: expected '$', got ''".to_string()),
                 }
+                let len = parse_length(&header[1..], MAX_BULK_LEN)
+                    .ok_or("Protocol error: invalid bulk length")?;
+                self.bulk_len = Some(len);
+                len
+            }
+        };
+
+        let end = self.pos + len;
+        match self.buf.get(end..end + 2) {
+            Some(b"\r\n") => {
+                let data = from_bytes(&self.buf[self.pos..end]);
+                self.pos = end + 2;
+                self.bulk_len = None;
+                Ok(Some(data))
             }
-            Some(RespValue::Array(elements))
+            Some(_) => Err("Protocol error: invalid bulk length".to_string()),
+            None => Ok(None),
         }
-        "+" => Some(RespValue::SimpleString(first_line[1..].to_string())),
-        "$" => {
-            let data = lines.next()?;
-            Some(RespValue::BulkString(data.to_string()))
+    }
+}
+
+fn parse_length(digits: &[u8], max: usize) -> Option<usize> {
+    std::str::from_utf8(digits)
+        .ok()?
+        .parse::<usize>()
+        .ok()
+        .filter(|&len| len <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `input` in one go and takes the frames it completes, as command
+    /// arguments.
+    fn parse(input: &[u8]) -> Result<Vec<Vec<String>>, String> {
+        let mut parser = RespParser::default();
+        parser.feed(input);
+        frames(&mut parser)
+    }
+
+    fn frames(parser: &mut RespParser) -> Result<Vec<Vec<String>>, String> {
+        let mut frames = Vec::new();
+        while let Some(frame) = parser.next_frame()? {
+            frames.push(args(frame));
+        }
+        Ok(frames)
+    }
+
+    fn args(frame: RespValue) -> Vec<String> {
+        match frame {
+            RespValue::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    RespValue::BulkString(arg) => arg,
+                    other => panic!("unexpected argument {:?}", other),
+                })
+                .collect(),
+            RespValue::BulkString(data) => vec![data],
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_byte_round_trips_through_a_string() {
+        let all: Vec<u8> = (0..=255).collect();
+        let s = from_bytes(&all);
+        assert_eq!(s.chars().count(), 256);
+        assert_eq!(to_bytes(&s), all);
+        assert_eq!(to_bytes("plain ascii"), b"plain ascii");
+        assert_eq!(byte_len(&s), 256);
+        assert!(s.len() > 256);
+    }
+
+    #[test]
+    fn partial_frames_wait_for_more_input() {
+        let frame = b"*2\r\n$4\r\nECHO\r\n$5\r\nhello\r\n";
+        for split in 0..frame.len() {
+            let mut parser = RespParser::default();
+            parser.feed(&frame[..split]);
+            assert!(
+                frames(&mut parser).unwrap().is_empty(),
+                "complete at {}",
+                split
+            );
+            parser.feed(&frame[split..]);
+            assert_eq!(frames(&mut parser).unwrap(), [["ECHO", "hello"]]);
+        }
+
+        let mut parser = RespParser::default();
+        for &byte in &frame[..frame.len() - 1] {
+            parser.feed(&[byte]);
+            assert!(parser.next_frame().unwrap().is_none());
+        }
+        parser.feed(b"\n");
+        assert_eq!(frames(&mut parser).unwrap(), [["ECHO", "hello"]]);
+    }
+
+    #[test]
+    fn pipelined_frames_are_taken_one_at_a_time() {
+        let input = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4";
+        let mut parser = RespParser::default();
+        parser.feed(input);
+        assert_eq!(args(parser.next_frame().unwrap().unwrap()), ["PING"]);
+        assert_eq!(args(parser.next_frame().unwrap().unwrap()), ["GET", "k"]);
+        assert!(parser.next_frame().unwrap().is_none());
+        parser.feed(b"\r\nQUIT\r\n");
+        assert_eq!(frames(&mut parser).unwrap(), [["QUIT"]]);
+    }
+
+    #[test]
+    fn bulk_data_may_hold_crlf_and_any_byte() {
+        let frames = parse(b"*2\r\n$6\r\na\r\n\x00\xffb\r\n$0\r\n\r\n").unwrap();
+        assert_eq!(to_bytes(&frames[0][0]), b"a\r\n\x00\xffb");
+        assert_eq!(frames[0][1], "");
+
+        assert_eq!(parse(b"$4\r\n\r\n\r\n\r\n").unwrap(), [["\r\n\r\n"]]);
+    }
+
+    #[test]
+    fn empty_multibulks_are_skipped() {
+        assert!(parse(b"*0\r\n").unwrap().is_empty());
+        assert_eq!(
+            parse(b"*0\r\n*-1\r\n*1\r\n$4\r\nPING\r\n*-5\r\n").unwrap(),
+            [["PING"]]
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_protocol_errors() {
+        // The data runs past its length, or isn't followed by CRLF.
+        assert!(parse(b"*1\r\n$3\r\nabcd\r\n").is_err());
+        assert!(parse(b"*1\r\n$3\r\nabc\n\n").is_err());
+        assert!(parse(b"*1\r\n$3\r\nabc").unwrap().is_empty());
+        assert!(parse(b"*1\r\n$x\r\n").is_err());
+        assert!(parse(b"*1\r\n$-1\r\n").is_err());
+        assert!(parse(b"*1\r\n\r\n").is_err());
+
+        assert!(parse(b"*1\r\n:3\r\n").is_err());
+        assert!(parse(b"*x\r\n").is_err());
+        assert!(parse(b"?\r\n").is_err());
+    }
+
+    #[test]
+    fn lengths_are_bounded_like_redis() {
+        assert!(parse(b"*1048576\r\n").unwrap().is_empty());
+        assert!(parse(b"*1048577\r\n").is_err());
+        assert!(parse(b"*1\r\n$536870912\r\n").unwrap().is_empty());
+        assert!(parse(b"*1\r\n$536870913\r\n").is_err());
+
+        let mut header = b"*1".to_vec();
+        header.resize(MAX_LINE + 1, b'0');
+        assert!(parse(&header[..MAX_LINE]).unwrap().is_empty());
+        assert!(parse(&header).is_err());
+        header[0] = b'$';
+        assert!(parse(&header).is_err());
+    }
+
+    #[test]
+    fn large_frames_arrive_in_small_reads() {
+        let count = 100_000;
+        let mut input = format!("*{}\r\n", count).into_bytes();
+        for i in 0..count {
+            let arg = i.to_string();
+            input.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        input.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        let mut parser = RespParser::default();
+        let mut received = Vec::new();
+        for chunk in input.chunks(4096) {
+            parser.feed(chunk);
+            received.extend(frames(&mut parser).unwrap());
         }
-        _ => None,
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].len(), count);
+        assert_eq!(received[0][count - 1], (count - 1).to_string());
+        assert_eq!(received[1], ["PING"]);
+        assert!(parser.buf.len() < 2 * 4096);
     }
 }
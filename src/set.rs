@@ -0,0 +1,165 @@
+use crate::dict::Dict;
+use crate::random;
+
+/// Unordered set of strings with two encodings, mirroring Redis.
+///
+/// Sets made only of integers are kept as a sorted vector of `i64` (the
+/// "intset"), which is compact and answers membership with a binary search.
+/// The first non-integer member, or growing past `set-max-intset-entries`,
+/// converts the set to a [`Dict`] for the rest of its life.
+#[derive(Debug)]
+pub struct Set {
+    encoding: Encoding,
+}
+
+#[derive(Debug)]
+enum Encoding {
+    IntSet(Vec<i64>),
+    Table(Dict<()>),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses `member` as an intset element. Only the canonical form counts, so
+/// "007" or "+7" stay strings and round-trip unchanged.
+fn as_int(member: &str) -> Option<i64> {
+    member
+        .parse::<i64>()
+        .ok()
+        .filter(|n| n.to_string() == member)
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self {
+            encoding: Encoding::IntSet(Vec::new()),
+        }
+    }
+
+    pub fn encoding_name(&self) -> &'static str {
+        match self.encoding {
+            Encoding::IntSet(_) => "intset",
+            Encoding::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::IntSet(ints) => ints.len(),
+            Encoding::Table(dict) => dict.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match &self.encoding {
+            Encoding::IntSet(ints) => {
+                as_int(member).is_some_and(|n| ints.binary_search(&n).is_ok())
+            }
+            Encoding::Table(dict) => dict.get(member).is_some(),
+        }
+    }
+
+    /// Adds `member`, returning true if it was not already present. Converts
+    /// to a hash table once the intset can no longer hold the member.
+    pub fn insert(&mut self, member: String, max_intset_entries: usize) -> bool {
+        if let Encoding::IntSet(ints) = &mut self.encoding {
+            if let Some(n) = as_int(&member) {
+                match ints.binary_search(&n) {
+                    Ok(_) => return false,
+                    Err(pos) if ints.len() < max_intset_entries => {
+                        ints.insert(pos, n);
+                        return true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            self.convert_to_table();
+        }
+
+        match &mut self.encoding {
+            Encoding::Table(dict) => dict.insert(member, ()).is_none(),
+            Encoding::IntSet(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match &mut self.encoding {
+            Encoding::IntSet(ints) => {
+                match as_int(member).and_then(|n| ints.binary_search(&n).ok()) {
+                    Some(pos) => {
+                        ints.remove(pos);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Encoding::Table(dict) => dict.remove(member).is_some(),
+        }
+    }
+
+    fn convert_to_table(&mut self) {
+        if let Encoding::IntSet(ints) = &self.encoding {
+            let mut dict = Dict::new();
+            for n in ints {
+                dict.insert(n.to_string(), ());
+            }
+            self.encoding = Encoding::Table(dict);
+        }
+    }
+
+    /// Iterates the members. Intset members are formatted on the fly.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = String> + '_> {
+        match &self.encoding {
+            Encoding::IntSet(ints) => Box::new(ints.iter().map(|n| n.to_string())),
+            Encoding::Table(dict) => Box::new(dict.keys().cloned()),
+        }
+    }
+
+    /// One `SSCAN` step. An intset is small enough to be returned whole, so
+    /// it always completes in a single call with cursor 0.
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(String)) -> u64 {
+        match &self.encoding {
+            Encoding::IntSet(ints) => {
+                for n in ints {
+                    f(n.to_string());
+                }
+                0
+            }
+            Encoding::Table(dict) => dict.scan(cursor, |member, _| f(member.clone())),
+        }
+    }
+
+    /// Picks members with the `SRANDMEMBER` count semantics of
+    /// [`random::try_sample`], which also decides when `count` is out of
+    /// range. The set must not be empty.
+    pub fn random_members(&self, count: i64) -> Option<Vec<String>> {
+        Some(match &self.encoding {
+            Encoding::IntSet(ints) => random::try_sample(
+                count,
+                ints.len(),
+                || ints.clone(),
+                || ints[random::below(ints.len())],
+            )?
+            .iter()
+            .map(|n| n.to_string())
+            .collect(),
+            Encoding::Table(dict) => random::try_sample(
+                count,
+                dict.len(),
+                || dict.keys().collect(),
+                || dict.random_entry().unwrap().0,
+            )?
+            .into_iter()
+            .cloned()
+            .collect(),
+        })
+    }
+}
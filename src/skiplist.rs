@@ -0,0 +1,382 @@
+use crate::random;
+
+/// Maximum tower height, enough for 2^64 elements with p = 1/4.
+const MAX_LEVEL: usize = 32;
+
+/// Index of the header node, which holds no element.
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Level {
+    forward: Option<usize>,
+    // Number of elements the link skips over, so ranks can be computed while
+    // descending the towers.
+    span: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// Skiplist ordered by `(score, member)`, as used by Redis sorted sets.
+///
+/// Nodes live in an arena and link to each other by index, so there is no
+/// unsafe code and freed slots are recycled. Every link records its span,
+/// which makes rank lookups and access by rank O(log n) as well.
+#[derive(Debug)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    level: usize,
+    len: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `node` sorts strictly before `(score, member)`.
+fn before(node: &Node, score: f64, member: &str) -> bool {
+    node.score < score || (node.score == score && node.member.as_str() < member)
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && random::next_u64() & 3 == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                member: String::new(),
+                score: 0.0,
+                backward: None,
+                levels: vec![Level::default(); MAX_LEVEL],
+            }],
+            free: Vec::new(),
+            tail: None,
+            level: 1,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// For every level, the last node that sorts before `(score, member)`.
+    fn predecessors(&self, score: f64, member: &str) -> [usize; MAX_LEVEL] {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i)
+                && before(&self.nodes[next], score, member)
+            {
+                x = next;
+            }
+            update[i] = x;
+        }
+        update
+    }
+
+    /// Inserts an element. The member must not already be in the list.
+    pub fn insert(&mut self, score: f64, member: String) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(x, i)
+                && before(&self.nodes[next], score, &member)
+            {
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![Level::default(); level],
+        });
+        for i in 0..level {
+            let prev = update[i];
+            let prev_span = self.nodes[prev].levels[i].span;
+            self.nodes[new].levels[i] = Level {
+                forward: self.nodes[prev].levels[i].forward,
+                span: prev_span - (rank[0] - rank[i]),
+            };
+            self.nodes[prev].levels[i] = Level {
+                forward: Some(new),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        self.nodes[new].backward = (update[0] != HEAD).then_some(update[0]);
+        match self.forward(new, 0) {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+        self.len += 1;
+    }
+
+    /// Removes the element with exactly this score and member, returning
+    /// whether it was found.
+    pub fn remove(&mut self, score: f64, member: &str) -> bool {
+        let update = self.predecessors(score, member);
+        let Some(x) = self.forward(update[0], 0) else {
+            return false;
+        };
+        if self.nodes[x].score != score || self.nodes[x].member != member {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let level = self.nodes[prev].levels[i];
+            if level.forward == Some(x) {
+                let removed = self.nodes[x].levels[i];
+                self.nodes[prev].levels[i] = Level {
+                    forward: removed.forward,
+                    span: level.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward,
+        }
+        while self.level > 1 && self.forward(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = String::new();
+        self.nodes[x].levels = Vec::new();
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Counts the leading elements for which `before` holds. `before` must
+    /// be monotonic: true for a prefix of the list and false afterwards.
+    pub fn count_while(&self, before: impl Fn(f64, &str) -> bool) -> usize {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i)
+                && before(self.nodes[next].score, &self.nodes[next].member)
+            {
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        rank
+    }
+
+    /// Finds the node at 0-based `rank` by following spans.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i)
+                && traversed + self.nodes[x].levels[i].span <= target
+            {
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// Iterates the elements with ranks in `start..end`, in descending order
+    /// if `rev` is set.
+    pub fn range(
+        &self,
+        start: usize,
+        end: usize,
+        rev: bool,
+    ) -> impl Iterator<Item = (&String, f64)> {
+        let end = end.min(self.len);
+        let count = end.saturating_sub(start);
+        let mut cursor = match count {
+            0 => None,
+            _ if rev && end == self.len => self.tail,
+            _ if rev => self.node_at(end - 1),
+            _ => self.node_at(start),
+        };
+
+        (0..count).map_while(move |_| {
+            let node = &self.nodes[cursor?];
+            cursor = if rev {
+                node.backward
+            } else {
+                node.levels[0].forward
+            };
+            Some((&node.member, node.score))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Walks the list checking links, order and spans, and returns its
+    /// elements in order.
+    fn walk(list: &SkipList) -> Vec<(f64, String)> {
+        let mut elements: Vec<(f64, String)> = Vec::new();
+        let mut rank = HashMap::from([(HEAD, 0)]);
+        let mut prev = None;
+        let mut x = HEAD;
+        while let Some(next) = list.forward(x, 0) {
+            let node = &list.nodes[next];
+            assert_eq!(node.backward, prev);
+            if let Some((score, member)) = elements.last() {
+                assert!(!before(node, *score, member), "out of order");
+            }
+            elements.push((node.score, node.member.clone()));
+            rank.insert(next, elements.len());
+            prev = Some(next);
+            x = next;
+        }
+        assert_eq!(list.tail, prev);
+        assert_eq!(list.len(), elements.len());
+
+        for (&node, &from) in &rank {
+            let levels = if node == HEAD {
+                list.level
+            } else {
+                list.nodes[node].levels.len()
+            };
+            for i in 0..levels {
+                let level = list.nodes[node].levels[i];
+                if let Some(to) = level.forward {
+                    assert_eq!(level.span, rank[&to] - from, "bad span at level {}", i);
+                }
+            }
+        }
+        for i in list.level..MAX_LEVEL {
+            assert!(list.forward(HEAD, i).is_none());
+        }
+        elements
+    }
+
+    #[test]
+    fn random_inserts_and_removes_keep_ranks_consistent() {
+        let mut list = SkipList::new();
+        let mut expected: Vec<(f64, String)> = Vec::new();
+        for i in 0..2000 {
+            if expected.is_empty() || random::below(3) != 0 {
+                // Few distinct scores, so ties are ordered by member.
+                let score = random::below(20) as f64;
+                let member = format!("m{}", i);
+                list.insert(score, member.clone());
+                let pos =
+                    expected.partition_point(|(s, m)| *s < score || (*s == score && *m < member));
+                expected.insert(pos, (score, member));
+            } else {
+                let (score, member) = expected.remove(random::below(expected.len()));
+                assert!(list.remove(score, &member));
+                assert!(!list.remove(score, &member));
+            }
+
+            if i % 50 == 0 {
+                assert_eq!(walk(&list), expected);
+            }
+        }
+        assert_eq!(walk(&list), expected);
+
+        for (rank, (score, member)) in expected.iter().enumerate() {
+            assert_eq!(
+                list.count_while(|s, m| s < *score || (s == *score && m < member)),
+                rank
+            );
+        }
+    }
+
+    #[test]
+    fn range_by_rank_in_both_directions() {
+        let mut list = SkipList::new();
+        for i in 0..300 {
+            list.insert(i as f64, format!("m{:03}", i));
+        }
+        let members = |start, end, rev| {
+            list.range(start, end, rev)
+                .map(|(m, _)| m.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(members(10, 13, false), ["m010", "m011", "m012"]);
+        assert_eq!(members(10, 13, true), ["m012", "m011", "m010"]);
+        assert_eq!(members(298, 400, false), ["m298", "m299"]);
+        assert_eq!(members(298, 400, true), ["m299", "m298"]);
+        assert!(members(5, 5, false).is_empty());
+        assert!(members(400, 500, true).is_empty());
+        assert_eq!(members(0, 300, false).len(), 300);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut list = SkipList::new();
+        for i in 0..100 {
+            list.insert(i as f64, i.to_string());
+        }
+        for i in 0..50 {
+            assert!(list.remove(i as f64, &i.to_string()));
+        }
+        for i in 100..150 {
+            list.insert(i as f64, i.to_string());
+        }
+        assert_eq!(list.nodes.len(), 101);
+        assert_eq!(walk(&list).len(), 100);
+    }
+}
@@ -1,9 +1,29 @@
 use crate::Command;
-use crate::resp::RespValue;
+use crate::blocking::BlockedClients;
+use crate::command::{
+    Aggregate, FieldTtl, FlushMode, PauseMode, PfDebugOp, Position, RangeSpec, ScoreEnd,
+    SetCondition, SetOp, UnblockMode, XClaimOptions, XPendingRange, XReadId, XReadOptions,
+    ZAddOptions, ZCombineOptions,
+};
+use crate::config::{Config, ListpackLimits};
+use crate::dict::Dict;
+use crate::glob::glob_match;
+use crate::hash::{Hash, unix_time_ms};
+use crate::hyperloglog::{self, HyperLogLog};
+use crate::list::{End, List};
+use crate::random;
+use crate::resp::{RespValue, byte_len, from_bytes, to_bytes};
+use crate::set::Set;
+use crate::stream::{ConsumerGroup, Fields, Stream, StreamId};
+use crate::zset::{ZSet, format_score};
 
-use std::collections::HashMap;
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::Instant;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct DbEntry {
@@ -11,17 +31,182 @@ pub struct DbEntry {
     expires_at: Option<Instant>,
 }
 
+impl DbEntry {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expiry| Instant::now() > expiry)
+    }
+}
+
 #[derive(Debug)]
 pub enum DbData {
     String(String),
-    List(Vec<String>),
-    Stream(String, HashMap<String, String>),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl DbData {
+    /// The `OBJECT ENCODING` name of the value's internal representation.
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            DbData::String(s) if byte_len(s) <= 20 && s.parse::<i64>().is_ok() => "int",
+            DbData::String(s) if byte_len(s) <= 44 => "embstr",
+            DbData::String(_) => "raw",
+            DbData::List(_) => "quicklist",
+            DbData::Hash(hash) => hash.encoding_name(),
+            DbData::Set(set) => set.encoding_name(),
+            DbData::ZSet(zset) => zset.encoding_name(),
+            DbData::Stream(_) => "stream",
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DbData::String(_) => "string",
+            DbData::List(_) => "list",
+            DbData::Hash(_) => "hash",
+            DbData::Set(_) => "set",
+            DbData::ZSet(_) => "zset",
+            DbData::Stream(_) => "stream",
+        }
+    }
+}
+
+/// All logical databases of the server, guarded by a single lock.
+pub struct Store {
+    dbs: Vec<Dict<DbEntry>>,
+    // Per database, (when, key) hints for hashes with fields to expire.
+    // Hints may be stale; the active expire cycle just skips those.
+    volatile_hashes: Vec<BTreeSet<(u64, String)>>,
+    config: Config,
+    blocked: BlockedClients,
+    pause: Option<Pause>,
+    // Shared so a paused client can wait on it while handing the guard over.
+    unpaused: Arc<Condvar>,
+}
+
+/// An active `CLIENT PAUSE`.
+#[derive(Debug, Clone, Copy)]
+struct Pause {
+    until: Instant,
+    mode: PauseMode,
+}
+
+impl Store {
+    pub fn new(databases: usize) -> Self {
+        Self {
+            dbs: (0..databases).map(|_| Dict::new()).collect(),
+            volatile_hashes: vec![BTreeSet::new(); databases],
+            config: Config::new(databases),
+            blocked: BlockedClients::default(),
+            pause: None,
+            unpaused: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// Signals that `key` received data and serves whoever is blocked on it.
+    fn wake(&mut self, db: usize, key: &str) {
+        self.blocked.signal(db, key);
+        self.serve_blocked();
+    }
+
+    /// Retries the clients blocked on every key signalled as ready, oldest
+    /// waiter first. Attempts may push to other keys and signal them in
+    /// turn, so this runs until no ready keys are left.
+    pub fn serve_blocked(&mut self) {
+        while let Some((db, key)) = self.blocked.next_ready() {
+            for id in self.blocked.waiting_on(db, &key) {
+                // A client that hung up must not take data it can no longer
+                // receive; its thread notices the dropped channel and exits.
+                if self.blocked.is_disconnected(id) {
+                    self.blocked.unblock(id);
+                    continue;
+                }
+                let Some(mut attempt) = self.blocked.take_attempt(id) else {
+                    continue;
+                };
+                match attempt(self) {
+                    Some(reply) => {
+                        self.blocked.complete(id, reply);
+                    }
+                    None => self.blocked.restore_attempt(id, attempt),
+                }
+            }
+        }
+    }
+
+    /// Records when the hash at `key` next has a field to expire, so the
+    /// active expire cycle finds it without walking the keyspace.
+    fn track_field_expiry(&mut self, db: usize, key: &str) {
+        if let Some(DbEntry {
+            data: DbData::Hash(hash),
+            ..
+        }) = self.dbs[db].get(key)
+            && let Some(at) = hash.next_expiry()
+        {
+            self.volatile_hashes[db].insert((at, key.to_string()));
+        }
+    }
+
+    /// Drops hash fields whose TTL has passed, even if nobody reads them.
+    /// Visits at most `ACTIVE_EXPIRE_KEYS` hashes per database so a single
+    /// cycle never holds the lock for long.
+    pub fn active_expire_cycle(&mut self) {
+        let now = unix_time_ms();
+        for db in 0..self.dbs.len() {
+            for _ in 0..ACTIVE_EXPIRE_KEYS {
+                if self.volatile_hashes[db]
+                    .first()
+                    .is_none_or(|(at, _)| *at > now)
+                {
+                    break;
+                }
+                let (_, key) = self.volatile_hashes[db].pop_first().unwrap();
+                expire_hash_fields(&mut self.dbs[db], &key);
+                self.track_field_expiry(db, &key);
+            }
+        }
+    }
+
+    /// Validates a client-supplied database index.
+    fn db_index(&self, index: i64) -> Option<usize> {
+        usize::try_from(index).ok().filter(|&i| i < self.dbs.len())
+    }
+}
+
+/// Drops `value` on a background thread so that freeing a large keyspace
+/// does not stall the caller.
+fn free_async<T: Send + 'static>(value: T) {
+    thread::spawn(move || drop(value));
+}
+
+pub type Db = Arc<Mutex<Store>>;
+
+/// Most hashes a single active expire cycle reaps in each database.
+const ACTIVE_EXPIRE_KEYS: usize = 20;
+
+/// How often a blocked client checks whether its peer has hung up.
+const DISCONNECT_POLL: Duration = Duration::from_millis(100);
+
+/// Per-connection state that outlives a single command.
+#[derive(Debug, Default)]
+pub struct Client {
+    pub id: u64,
+    pub db: usize,
+    conn: Option<TcpStream>,
 }
 
-pub type Db = Arc<(Mutex<HashMap<String, DbEntry>>, Condvar)>;
+impl Client {
+    pub fn new(id: u64, conn: Option<TcpStream>) -> Self {
+        Self { id, db: 0, conn }
+    }
+}
 
-pub fn execute_command(cmd: Command, db: &Db) -> RespValue {
-    let (lock, cvar) = &**db;
+pub fn execute_command(cmd: Command, db: &Db, client: &mut Client) -> RespValue {
+    let mut store = wait_while_paused(db, &cmd);
 
     match cmd {
         Command::Ping(msg) => match msg {
@@ -30,8 +215,8 @@ pub fn execute_command(cmd: Command, db: &Db) -> RespValue {
         },
         Command::Echo(msg) => RespValue::BulkString(msg),
         Command::Set(key, val, px) => {
-            let mut map = lock.lock().unwrap();
-            let expires_at = px.map(|ms| Instant::now() + std::time::Duration::from_millis(ms));
+            let map = &mut store.dbs[client.db];
+            let expires_at = px.map(|ms| Instant::now() + Duration::from_millis(ms));
 
             map.insert(
                 key,
@@ -40,82 +225,71 @@ pub fn execute_command(cmd: Command, db: &Db) -> RespValue {
                     expires_at,
                 },
             );
-            cvar.notify_all();
             RespValue::SimpleString("OK".to_string())
         }
         Command::Get(key) => {
-            let mut map = lock.lock().unwrap();
+            let map = &mut store.dbs[client.db];
 
             if let Some(entry) = map.get(&key) {
-                if let Some(expiry) = entry.expires_at {
-                    if Instant::now() > expiry {
-                        map.remove(&key);
-                        return RespValue::Null;
-                    }
+                if entry.is_expired() {
+                    map.remove(&key);
+                    return RespValue::Null;
                 }
 
                 match &entry.data {
                     DbData::String(s) => RespValue::BulkString(s.clone()),
-                    DbData::List(_) | DbData::Stream(_, _) => RespValue::Error(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value"
-                            .to_string(),
-                    ),
+                    _ => wrong_type(),
                 }
             } else {
                 RespValue::Null
             }
         }
         Command::RPush(key, values) => {
-            let mut map = lock.lock().unwrap();
+            let map = &mut store.dbs[client.db];
 
-            let entry = map.entry(key).or_insert(DbEntry {
-                data: DbData::List(Vec::new()),
+            let entry = map.get_or_insert_with(key.clone(), || DbEntry {
+                data: DbData::List(List::new()),
                 expires_at: None,
             });
 
             if let DbData::List(ref mut list) = entry.data {
                 for val in values {
-                    list.push(val);
+                    list.push_back(val);
                 }
-                cvar.notify_all();
-                RespValue::Integer(list.len() as i64)
+                let len = list.len();
+                store.wake(client.db, &key);
+                RespValue::Integer(len as i64)
             } else {
-                RespValue::Error(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                )
+                wrong_type()
             }
         }
         Command::LPush(key, values) => {
-            let mut map = lock.lock().unwrap();
+            let map = &mut store.dbs[client.db];
 
-            let entry = map.entry(key).or_insert(DbEntry {
-                data: DbData::List(Vec::new()),
+            let entry = map.get_or_insert_with(key.clone(), || DbEntry {
+                data: DbData::List(List::new()),
                 expires_at: None,
             });
 
             if let DbData::List(ref mut list) = entry.data {
                 for val in values {
-                    list.push(val);
+                    list.push_front(val);
                 }
-                list.reverse();
-                RespValue::Integer(list.len() as i64)
+                let len = list.len();
+                store.wake(client.db, &key);
+                RespValue::Integer(len as i64)
             } else {
-                RespValue::Error(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                )
+                wrong_type()
             }
         }
         Command::LRange(key, (start, stop)) => {
-            let map = lock.lock().unwrap();
+            let map = &store.dbs[client.db];
 
             let list = match map.get(&key) {
                 Some(entry) => match &entry.data {
                     DbData::List(l) => l,
                     _ => {
-                        return RespValue::Error(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        );
+                        return wrong_type();
                     }
                 },
                 None => return RespValue::Array(vec![]),
@@ -137,160 +311,3208 @@ pub fn execute_command(cmd: Command, db: &Db) -> RespValue {
                 return RespValue::Array(vec![]);
             }
 
-            let result = list[start_idx as usize..=stop_idx as usize]
-                .iter()
+            let result = list
+                .iter_from(start_idx as usize)
+                .take((stop_idx - start_idx + 1) as usize)
                 .map(|s| RespValue::BulkString(s.clone()))
                 .collect();
 
             RespValue::Array(result)
         }
         Command::LLen(key) => {
-            let mut map = lock.lock().unwrap();
+            let map = &store.dbs[client.db];
 
-            let entry = map.entry(key).or_insert(DbEntry {
-                data: DbData::List(Vec::new()),
-                expires_at: None,
-            });
+            let entry = match map.get(&key) {
+                Some(e) => e,
+                None => return RespValue::Integer(0),
+            };
 
-            if let DbData::List(ref mut list) = entry.data {
+            if let DbData::List(ref list) = entry.data {
                 RespValue::Integer(list.len() as i64)
             } else {
-                RespValue::Error(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                )
+                wrong_type()
             }
         }
-        Command::LPop(key, count) => {
-            let mut map = lock.lock().unwrap();
+        Command::LPop(key, count) => pop_list(&mut store.dbs[client.db], &key, count, End::Left),
+        Command::RPop(key, count) => pop_list(&mut store.dbs[client.db], &key, count, End::Right),
+        Command::BLPop(keys, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                blocking_pop(store, db_index, &keys, End::Left)
+            })
+        }
+        Command::BRPop(keys, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                blocking_pop(store, db_index, &keys, End::Right)
+            })
+        }
+        Command::Type(key) => {
+            let map = &store.dbs[client.db];
 
-            let entry = match map.get_mut(&key) {
-                Some(e) => e,
-                None => return RespValue::Null,
+            if let Some(entry) = map.get(&key) {
+                RespValue::SimpleString(entry.data.type_name().to_string())
+            } else {
+                RespValue::SimpleString("none".to_string())
+            }
+        }
+        Command::XAdd(stream_key, opts, id, fields) => {
+            let map = &mut store.dbs[client.db];
+
+            // The ID is validated before the key is created, so a rejected
+            // XADD never creates a stream or disturbs an existing one.
+            let now = unix_time_ms();
+            let id = match get_stream(map, &stream_key) {
+                Ok(None) if opts.nomkstream => return RespValue::Null,
+                Ok(Some(stream)) => stream.next_id(id, now),
+                Ok(None) => Stream::new().next_id(id, now),
+                Err(e) => return e,
+            };
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => return RespValue::Error(e.to_string()),
             };
 
-            if let DbData::List(ref mut list) = entry.data {
-                if list.is_empty() {
-                    return RespValue::Null;
+            let stream = get_or_create_stream(map, &stream_key).unwrap();
+            stream.append(id, fields);
+            if let Some(trim) = &opts.trim {
+                stream.trim(trim);
+            }
+            store.wake(client.db, &stream_key);
+            RespValue::BulkString(id.to_string())
+        }
+        Command::XRange(key, start, end, count) => {
+            xrange(&store.dbs[client.db], &key, start, end, count, false)
+        }
+        Command::XRevRange(key, start, end, count) => {
+            xrange(&store.dbs[client.db], &key, start, end, count, true)
+        }
+        Command::XRead(opts, streams) => {
+            let db_index = client.db;
+
+            // Resolve `$` and `+` once up front, so a blocked read only
+            // returns entries added after it started.
+            let streams = {
+                let map = &store.dbs[db_index];
+                let mut resolved = Vec::with_capacity(streams.len());
+                for (key, id) in streams {
+                    // `+` reads the last live entry, which may sit well
+                    // before the last ID if the newest entries were deleted.
+                    let (last_id, last_entry) = match get_stream(map, &key) {
+                        Ok(Some(s)) => (s.last_id(), s.last_entry().map(|(id, _)| id)),
+                        Ok(None) => (StreamId::MIN, None),
+                        Err(e) => return e,
+                    };
+                    let after = match id {
+                        XReadId::After(id) => id,
+                        XReadId::New => last_id,
+                        XReadId::Last => last_entry.and_then(|id| id.prev()).unwrap_or(last_id),
+                    };
+                    resolved.push((key, after));
                 }
+                resolved
+            };
 
-                match count {
-                    None => {
-                        let val = list.remove(0);
-                        if list.is_empty() {
-                            map.remove(&key);
-                        }
-                        RespValue::BulkString(val)
-                    }
-                    Some(n) => {
-                        let take_n = std::cmp::min(n, list.len());
-                        let removed_elements: Vec<RespValue> = list
-                            .drain(0..take_n)
-                            .map(|s| RespValue::BulkString(s))
-                            .collect();
-
-                        if list.is_empty() {
-                            map.remove(&key);
-                        }
-                        RespValue::Array(removed_elements)
-                    }
+            match opts.block {
+                None => xread(&store.dbs[db_index], &streams, opts.count)
+                    .unwrap_or(RespValue::NullArray),
+                Some(timeout) => {
+                    let keys = streams.iter().map(|(key, _)| key.clone()).collect();
+                    block_until(store, db, client, keys, timeout, move |store| {
+                        xread(&store.dbs[db_index], &streams, opts.count)
+                    })
                 }
-            } else {
-                RespValue::Error(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                )
             }
         }
-        Command::BLPop(key, timeout) => {
-            let mut map = lock.lock().unwrap();
+        Command::XTrim(key, trim) => match get_stream_mut(&mut store.dbs[client.db], &key) {
+            Ok(Some(stream)) => RespValue::Integer(stream.trim(&trim) as i64),
+            Ok(None) => RespValue::Integer(0),
+            Err(e) => e,
+        },
+        Command::XDel(key, ids) => match get_stream_mut(&mut store.dbs[client.db], &key) {
+            Ok(Some(stream)) => {
+                let deleted = ids.into_iter().filter(|&id| stream.delete(id)).count();
+                RespValue::Integer(deleted as i64)
+            }
+            Ok(None) => RespValue::Integer(0),
+            Err(e) => e,
+        },
+        Command::XGroupCreate(key, group, id, mkstream, entries_read) => {
+            let map = &mut store.dbs[client.db];
+
+            match get_stream(map, &key) {
+                Ok(None) if !mkstream => return RespValue::Error(XGROUP_NO_KEY.to_string()),
+                Err(e) => return e,
+                _ => {}
+            }
+            let stream = get_or_create_stream(map, &key).unwrap();
+            if stream.groups.contains_key(&group) {
+                return RespValue::Error(
+                    "BUSYGROUP Consumer Group name already exists".to_string(),
+                );
+            }
+
+            let id = id.unwrap_or(stream.last_id());
+            stream
+                .groups
+                .insert(group, ConsumerGroup::new(id, entries_read));
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::XGroupSetId(key, group, id, entries_read) => {
+            let stream = match get_xgroup_stream(&mut store.dbs[client.db], &key, &group) {
+                Ok(stream) => stream,
+                Err(e) => return e,
+            };
+            let id = id.unwrap_or(stream.last_id());
+            let cg = stream.groups.get_mut(&group).unwrap();
+            cg.last_id = id;
+            cg.entries_read = entries_read;
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::XGroupDestroy(key, group) => {
+            let destroyed = match get_stream_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(stream)) => stream.groups.remove(&group).is_some(),
+                Ok(None) => return RespValue::Error(XGROUP_NO_KEY.to_string()),
+                Err(e) => return e,
+            };
+            // Clients blocked reading from the group get their error now.
+            if destroyed {
+                store.wake(client.db, &key);
+            }
+            RespValue::Integer(destroyed as i64)
+        }
+        Command::XGroupCreateConsumer(key, group, consumer) => {
+            let stream = match get_xgroup_stream(&mut store.dbs[client.db], &key, &group) {
+                Ok(stream) => stream,
+                Err(e) => return e,
+            };
+            let cg = stream.groups.get_mut(&group).unwrap();
+            if cg.consumers.contains_key(&consumer) {
+                return RespValue::Integer(0);
+            }
+            cg.consumer(&consumer, unix_time_ms());
+            RespValue::Integer(1)
+        }
+        Command::XGroupDelConsumer(key, group, consumer) => {
+            let stream = match get_xgroup_stream(&mut store.dbs[client.db], &key, &group) {
+                Ok(stream) => stream,
+                Err(e) => return e,
+            };
+            let cg = stream.groups.get_mut(&group).unwrap();
+            RespValue::Integer(cg.delete_consumer(&consumer).unwrap_or(0) as i64)
+        }
+        Command::XReadGroup(group, consumer, opts, streams) => {
+            let db_index = client.db;
+            // Only reads of new entries wait for them; history is returned
+            // right away, even if empty.
+            let blocking = opts.block.is_some() && streams.iter().all(|(_, id)| id.is_none());
+            let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+            let attempt = move |store: &mut Store| {
+                xreadgroup(&mut store.dbs[db_index], &group, &consumer, &streams, opts)
+            };
+
+            match opts.block {
+                Some(timeout) if blocking => block_until(store, db, client, keys, timeout, attempt),
+                _ => attempt(&mut store).unwrap_or(RespValue::NullArray),
+            }
+        }
+        Command::XAck(key, group, ids) => {
+            let cg = match get_stream_mut(&mut store.dbs[client.db], &key) {
+                Ok(stream) => stream.and_then(|s| s.groups.get_mut(&group)),
+                Err(e) => return e,
+            };
+            let acked = match cg {
+                Some(cg) => ids.into_iter().filter(|&id| cg.ack(id)).count(),
+                None => 0,
+            };
+            RespValue::Integer(acked as i64)
+        }
+        Command::XPending(key, group, range) => {
+            let cg = match get_stream(&store.dbs[client.db], &key) {
+                Ok(stream) => stream.and_then(|s| s.groups.get(&group)),
+                Err(e) => return e,
+            };
+            let Some(cg) = cg else {
+                return RespValue::Error(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}'",
+                    key, group
+                ));
+            };
+
+            match range {
+                Some(range) => xpending_range(cg, &range),
+                None => xpending_summary(cg),
+            }
+        }
+        Command::XClaim(key, group, consumer, min_idle, ids, opts) => {
+            let stream = match get_stream_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(stream)) if stream.groups.contains_key(&group) => stream,
+                Ok(_) => {
+                    return RespValue::Error(format!(
+                        "NOGROUP No such key '{}' or consumer group '{}'",
+                        key, group
+                    ));
+                }
+                Err(e) => return e,
+            };
+            xclaim(stream, &group, &consumer, min_idle, &ids, opts)
+        }
+        Command::XAutoClaim(key, group, consumer, min_idle, start, count, justid) => {
+            let stream = match get_stream_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(stream)) if stream.groups.contains_key(&group) => stream,
+                Ok(_) => {
+                    return RespValue::Error(format!(
+                        "NOGROUP No such key '{}' or consumer group '{}'",
+                        key, group
+                    ));
+                }
+                Err(e) => return e,
+            };
+            xautoclaim(stream, &group, &consumer, min_idle, start, count, justid)
+        }
+        Command::XInfoStream(key, full) => match get_stream(&store.dbs[client.db], &key) {
+            Ok(Some(stream)) => xinfo_stream(stream, full),
+            Ok(None) => RespValue::Error("no such key".to_string()),
+            Err(e) => e,
+        },
+        Command::XInfoGroups(key) => {
+            let stream = match get_stream(&store.dbs[client.db], &key) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return RespValue::Error("no such key".to_string()),
+                Err(e) => return e,
+            };
+            RespValue::Array(
+                stream
+                    .groups
+                    .iter()
+                    .map(|(name, cg)| {
+                        RespValue::Array(vec![
+                            RespValue::BulkString("name".to_string()),
+                            RespValue::BulkString(name.clone()),
+                            RespValue::BulkString("consumers".to_string()),
+                            RespValue::Integer(cg.consumers.len() as i64),
+                            RespValue::BulkString("pending".to_string()),
+                            RespValue::Integer(cg.pending.len() as i64),
+                            RespValue::BulkString("last-delivered-id".to_string()),
+                            RespValue::BulkString(cg.last_id.to_string()),
+                            RespValue::BulkString("entries-read".to_string()),
+                            optional_integer(cg.entries_read),
+                            RespValue::BulkString("lag".to_string()),
+                            optional_integer(stream.lag(cg)),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        Command::XInfoConsumers(key, group) => {
+            let stream = match get_stream(&store.dbs[client.db], &key) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return RespValue::Error("no such key".to_string()),
+                Err(e) => return e,
+            };
+            let Some(cg) = stream.groups.get(&group) else {
+                return RespValue::Error(format!(
+                    "NOGROUP No such consumer group '{}' for key name '{}'",
+                    group, key
+                ));
+            };
+
+            let now = unix_time_ms();
+            RespValue::Array(
+                cg.consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        let inactive = consumer
+                            .active_time
+                            .map_or(-1, |at| now.saturating_sub(at) as i64);
+                        RespValue::Array(vec![
+                            RespValue::BulkString("name".to_string()),
+                            RespValue::BulkString(name.clone()),
+                            RespValue::BulkString("pending".to_string()),
+                            RespValue::Integer(consumer.pending.len() as i64),
+                            RespValue::BulkString("idle".to_string()),
+                            RespValue::Integer(now.saturating_sub(consumer.seen_time) as i64),
+                            RespValue::BulkString("inactive".to_string()),
+                            RespValue::Integer(inactive),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        Command::XSetId(key, id, entries_added, max_deleted_id) => {
+            match get_stream_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(stream)) => match stream.set_id(id, entries_added, max_deleted_id) {
+                    Ok(()) => RespValue::SimpleString("OK".to_string()),
+                    Err(e) => RespValue::Error(e.to_string()),
+                },
+                Ok(None) => RespValue::Error("no such key".to_string()),
+                Err(e) => e,
+            }
+        }
+        Command::XLen(key) => match get_stream(&store.dbs[client.db], &key) {
+            Ok(stream) => RespValue::Integer(stream.map_or(0, |s| s.len()) as i64),
+            Err(e) => e,
+        },
+        Command::Keys(pattern) => {
+            let map = &mut store.dbs[client.db];
+
+            map.retain(|_, entry| !entry.is_expired());
+            let keys = map
+                .keys()
+                .filter(|key| glob_match(&pattern, key))
+                .map(|key| RespValue::BulkString(key.clone()))
+                .collect();
 
-            let timeout_duration = std::time::Duration::from_secs_f32(timeout);
-            let start_time = std::time::Instant::now();
+            RespValue::Array(keys)
+        }
+        Command::Scan(cursor, opts) => {
+            let map = &mut store.dbs[client.db];
 
+            // Like Redis, visit at most COUNT * 10 buckets per call so that a
+            // sparse table cannot turn a single SCAN into a full walk.
+            let mut visited = Vec::new();
+            let mut cursor = cursor;
+            let mut budget = opts.count.saturating_mul(10);
             loop {
-                if let Some(entry) = map.get_mut(&key) {
-                    match &mut entry.data {
-                        DbData::List(list) => {
-                            if !list.is_empty() {
-                                let val = list.remove(0);
-                                let result = vec![
-                                    RespValue::BulkString(key.clone()),
-                                    RespValue::BulkString(val),
-                                ];
-
-                                return RespValue::Array(result);
-                            }
-                        }
-                        _ => {
-                            return RespValue::Error(
-                                "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                    .to_string(),
-                            );
-                        }
-                    }
+                cursor = map.scan(cursor, |key, entry| {
+                    visited.push((key.clone(), entry.is_expired(), entry.data.type_name()));
+                });
+                budget -= 1;
+                if cursor == 0 || budget == 0 || visited.len() >= opts.count {
+                    break;
                 }
+            }
 
-                if timeout == 0.0 {
-                    map = cvar.wait(map).unwrap();
-                } else {
-                    let elapsed = start_time.elapsed();
+            let mut keys = Vec::new();
+            for (key, expired, type_name) in visited {
+                if expired {
+                    map.remove(&key);
+                    continue;
+                }
+                if let Some(pattern) = &opts.pattern
+                    && !glob_match(pattern, &key)
+                {
+                    continue;
+                }
+                if let Some(wanted) = &opts.type_filter
+                    && wanted != type_name
+                {
+                    continue;
+                }
+                keys.push(RespValue::BulkString(key));
+            }
 
-                    if elapsed >= timeout_duration {
-                        return RespValue::Null;
-                    }
+            RespValue::Array(vec![
+                RespValue::BulkString(cursor.to_string()),
+                RespValue::Array(keys),
+            ])
+        }
+        Command::Select(index) => match store.db_index(index) {
+            Some(index) => {
+                client.db = index;
+                RespValue::SimpleString("OK".to_string())
+            }
+            None => RespValue::Error("DB index is out of range".to_string()),
+        },
+        Command::Move(key, target) => {
+            let Some(target) = store.db_index(target) else {
+                return RespValue::Error("DB index is out of range".to_string());
+            };
+            if target == client.db {
+                return RespValue::Error("source and destination objects are the same".to_string());
+            }
 
-                    let remaining = timeout_duration - elapsed;
+            let live = |db: &Dict<DbEntry>| db.get(&key).is_some_and(|e| !e.is_expired());
+            if !live(&store.dbs[client.db]) || live(&store.dbs[target]) {
+                return RespValue::Integer(0);
+            }
 
-                    let (new_map, result) = cvar.wait_timeout(map, remaining).unwrap();
-                    map = new_map;
+            let entry = store.dbs[client.db].remove(&key).unwrap();
+            store.dbs[target].insert(key.clone(), entry);
+            store.track_field_expiry(target, &key);
+            store.wake(target, &key);
+            RespValue::Integer(1)
+        }
+        Command::SwapDb(first, second) => {
+            let (Some(first), Some(second)) = (store.db_index(first), store.db_index(second))
+            else {
+                return RespValue::Error("DB index is out of range".to_string());
+            };
 
-                    if result.timed_out() {
-                        return RespValue::NullArray;
+            store.dbs.swap(first, second);
+            store.volatile_hashes.swap(first, second);
+            // Clients blocked on either side may now see a non-empty key.
+            store.blocked.signal_db(first);
+            store.blocked.signal_db(second);
+            store.serve_blocked();
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::FlushDb(mode) => {
+            let old = std::mem::take(&mut store.dbs[client.db]);
+            store.volatile_hashes[client.db].clear();
+            if let FlushMode::Async = mode {
+                free_async(old);
+            }
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::FlushAll(mode) => {
+            let old: Vec<_> = store.dbs.iter_mut().map(std::mem::take).collect();
+            store.volatile_hashes.iter_mut().for_each(BTreeSet::clear);
+            if let FlushMode::Async = mode {
+                free_async(old);
+            }
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::Info(section) => {
+            let section = section.map(|s| s.to_lowercase());
+            let mut info = String::new();
+            if matches!(
+                section.as_deref(),
+                None | Some("keyspace" | "default" | "all" | "everything")
+            ) {
+                info.push_str("# Keyspace\r\n");
+                let now = Instant::now();
+                for (index, db) in store.dbs.iter().enumerate() {
+                    if db.is_empty() {
+                        continue;
                     }
+                    // Remaining TTLs in milliseconds; keys already past their
+                    // expiry but not yet reaped count as 0.
+                    let ttls: Vec<u128> = db
+                        .iter()
+                        .filter_map(|(_, e)| e.expires_at)
+                        .map(|at| at.saturating_duration_since(now).as_millis())
+                        .collect();
+                    let avg_ttl = ttls.iter().sum::<u128>() / ttls.len().max(1) as u128;
+                    let _ = write!(
+                        info,
+                        "db{}:keys={},expires={},avg_ttl={}\r\n",
+                        index,
+                        db.len(),
+                        ttls.len(),
+                        avg_ttl
+                    );
                 }
             }
+
+            RespValue::BulkString(info)
         }
-        Command::Type(key) => {
-            let map = lock.lock().unwrap();
+        Command::LIndex(key, index) => match get_list(&store.dbs[client.db], &key) {
+            Ok(Some(list)) => match normalize_index(index, list.len()).and_then(|i| list.get(i)) {
+                Some(val) => RespValue::BulkString(val.clone()),
+                None => RespValue::Null,
+            },
+            Ok(None) => RespValue::Null,
+            Err(e) => e,
+        },
+        Command::LSet(key, index, val) => {
+            let list = match get_list_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return RespValue::Error("no such key".to_string()),
+                Err(e) => return e,
+            };
 
-            if let Some(entry) = map.get(&key) {
-                match &entry.data {
-                    DbData::String(_) => RespValue::SimpleString("string".to_string()),
-                    DbData::Stream(_i, _h) => RespValue::SimpleString("stream".to_string()),
-                    DbData::List(_) => RespValue::SimpleString(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value"
-                            .to_string(),
-                    ),
+            match normalize_index(index, list.len()).and_then(|i| list.get_mut(i)) {
+                Some(slot) => {
+                    *slot = val;
+                    RespValue::SimpleString("OK".to_string())
                 }
+                None => RespValue::Error("index out of range".to_string()),
+            }
+        }
+        Command::LInsert(key, position, pivot, val) => {
+            let list = match get_list_mut(&mut store.dbs[client.db], &key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+
+            let Some(pivot_idx) = list.iter().position(|v| *v == pivot) else {
+                return RespValue::Integer(-1);
+            };
+            let index = match position {
+                Position::Before => pivot_idx,
+                Position::After => pivot_idx + 1,
+            };
+            list.insert(index, val);
+            RespValue::Integer(list.len() as i64)
+        }
+        Command::LRem(key, count, val) => {
+            let map = &mut store.dbs[client.db];
+
+            let list = match get_list_mut(map, &key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+
+            let end = if count < 0 { End::Right } else { End::Left };
+            let limit = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+            let removed = list.remove_matching(&val, limit, end);
+            if list.is_empty() {
+                map.remove(&key);
+            }
+            RespValue::Integer(removed as i64)
+        }
+        Command::LTrim(key, start, stop) => {
+            let map = &mut store.dbs[client.db];
+
+            let list = match get_list_mut(map, &key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return RespValue::SimpleString("OK".to_string()),
+                Err(e) => return e,
+            };
+
+            let len = list.len() as i64;
+            let start = if start < 0 {
+                (len + start).max(0)
             } else {
-                RespValue::SimpleString("none".to_string())
+                start
+            };
+            let stop = if stop < 0 {
+                len + stop
+            } else {
+                stop.min(len - 1)
+            };
+
+            if start > stop || start >= len {
+                map.remove(&key);
+            } else {
+                list.trim(start as usize, stop as usize);
             }
+            RespValue::SimpleString("OK".to_string())
         }
-        Command::XAdd(stream_key, id, key_value_pair) => {
-            let mut map = lock.lock().unwrap();
+        Command::LPos(key, val, opts) => {
+            let list = match get_list(&store.dbs[client.db], &key) {
+                Ok(Some(list)) => list,
+                Ok(None) if opts.count.is_some() => return RespValue::Array(vec![]),
+                Ok(None) => return RespValue::Null,
+                Err(e) => return e,
+            };
 
-            // TODO: Implement stream entry ID validation
-            // 1. Parse the 'id' string into '<ms>-<seq>' integers safely.
-            // 2. Validate that the ID is not "0-0" (which is invalid for Redis streams).
-            // 3. Check if the stream already exists in the map to get its last inserted ID.
-            // 4. Ensure the new ID is strictly greater than the previous one.
-            // 5. If validation fails, return RespValue::Error("ERR The ID specified in XADD is equal or smaller than the target stream top item").
+            let len = list.len();
+            let scanned: Box<dyn Iterator<Item = (usize, &String)>> = if opts.rank > 0 {
+                Box::new(list.iter().enumerate())
+            } else {
+                Box::new(list.iter().rev().enumerate().map(|(i, v)| (len - 1 - i, v)))
+            };
+            let maxlen = if opts.maxlen == 0 { len } else { opts.maxlen };
+            let wanted = match opts.count {
+                Some(0) => usize::MAX,
+                Some(n) => n,
+                None => 1,
+            };
 
-            // FIXME: Currently this just overwrites the stream instead of appending to it.
-            map.insert(
-                stream_key,
-                DbEntry {
-                    data: DbData::Stream(id.clone(), key_value_pair),
-                    expires_at: None,
-                },
-            );
-            RespValue::BulkString(id)
+            let mut skip = opts.rank.unsigned_abs() - 1;
+            let mut found = Vec::new();
+            for (index, item) in scanned.take(maxlen) {
+                if *item != val {
+                    continue;
+                }
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+                found.push(RespValue::Integer(index as i64));
+                if found.len() == wanted {
+                    break;
+                }
+            }
+
+            match opts.count {
+                Some(_) => RespValue::Array(found),
+                None => found.pop().unwrap_or(RespValue::Null),
+            }
         }
-    }
-}
+        Command::LPushX(key, values) => {
+            push_existing(&mut store.dbs[client.db], &key, values, End::Left)
+        }
+        Command::RPushX(key, values) => {
+            push_existing(&mut store.dbs[client.db], &key, values, End::Right)
+        }
+        Command::LMPop(keys, end, count) => {
+            match pop_first_nonempty(&mut store.dbs[client.db], &keys, end, count) {
+                Ok(Some((key, vals))) => mpop_reply(key, vals),
+                Ok(None) => RespValue::NullArray,
+                Err(e) => e,
+            }
+        }
+        Command::BLMPop(keys, end, count, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                match pop_first_nonempty(&mut store.dbs[db_index], &keys, end, count) {
+                    Ok(Some((key, vals))) => Some(mpop_reply(key, vals)),
+                    Ok(None) => None,
+                    Err(e) => Some(e),
+                }
+            })
+        }
+        Command::ClientId => RespValue::Integer(client.id as i64),
+        Command::ClientUnblock(id, mode) => {
+            let reply = match mode {
+                UnblockMode::Timeout => RespValue::NullArray,
+                UnblockMode::Error => {
+                    RespValue::Error("UNBLOCKED client unblocked via CLIENT UNBLOCK".to_string())
+                }
+            };
+            RespValue::Integer(store.blocked.complete(id, reply) as i64)
+        }
+        Command::ClientPause(ms, mode) => {
+            // An active pause can be extended or widened to ALL, never
+            // shortened or narrowed.
+            let now = Instant::now();
+            let mut pause = Pause {
+                until: now + Duration::from_millis(ms),
+                mode,
+            };
+            if let Some(active) = store.pause.filter(|p| p.until > now) {
+                pause.until = pause.until.max(active.until);
+                if let PauseMode::All = active.mode {
+                    pause.mode = PauseMode::All;
+                }
+            }
+            store.pause = Some(pause);
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::ClientUnpause => {
+            store.pause = None;
+            store.unpaused.notify_all();
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::ConfigGet(patterns) => {
+            let mut reply = Vec::new();
+            let mut seen = Vec::new();
+            for pattern in patterns {
+                for (name, value) in store.config.get(&pattern) {
+                    if !seen.contains(&name) {
+                        seen.push(name);
+                        reply.push(RespValue::BulkString(name.to_string()));
+                        reply.push(RespValue::BulkString(value));
+                    }
+                }
+            }
+            RespValue::Array(reply)
+        }
+        Command::ConfigSet(pairs) => {
+            // Apply to a copy first so a bad pair leaves the config untouched.
+            let mut config = store.config.clone();
+            for (name, value) in pairs {
+                if let Err(e) = config.set(&name, &value) {
+                    return RespValue::Error(e);
+                }
+            }
+            store.config = config;
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::ObjectEncoding(key) => match store.dbs[client.db].get(&key) {
+            Some(entry) => RespValue::BulkString(entry.data.encoding_name().to_string()),
+            None => RespValue::Null,
+        },
+        Command::HSet(key, pairs) => {
+            let limits = store.config.hash_listpack;
 
-pub fn extract_string(elems: &[RespValue], index: usize) -> Option<String> {
-    match elems.get(index) {
-        Some(RespValue::BulkString(s)) => Some(s.clone()),
-        _ => None,
+            let hash = match get_or_create_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            let added = pairs
+                .into_iter()
+                .filter(|(field, val)| {
+                    let added = hash.insert(field.clone(), val.clone(), limits);
+                    hash.persist(field);
+                    added
+                })
+                .count();
+            RespValue::Integer(added as i64)
+        }
+        Command::HGet(key, field) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(Some(hash)) => match hash.get(&field) {
+                Some(val) => RespValue::BulkString(val.clone()),
+                None => RespValue::Null,
+            },
+            Ok(None) => RespValue::Null,
+            Err(e) => e,
+        },
+        Command::HMGet(key, fields) => {
+            let hash = match get_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            let vals = fields
+                .iter()
+                .map(|field| match hash.and_then(|h| h.get(field)) {
+                    Some(val) => RespValue::BulkString(val.clone()),
+                    None => RespValue::Null,
+                })
+                .collect();
+            RespValue::Array(vals)
+        }
+        Command::HDel(key, fields) => {
+            let map = &mut store.dbs[client.db];
+
+            let hash = match get_hash_mut(map, &key) {
+                Ok(Some(hash)) => hash,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+            let removed = fields.iter().filter(|field| hash.remove(field)).count();
+            if hash.is_empty() {
+                map.remove(&key);
+            }
+            RespValue::Integer(removed as i64)
+        }
+        Command::HGetAll(key) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(Some(hash)) => RespValue::Array(
+                hash.iter()
+                    .flat_map(|(f, v)| [f, v])
+                    .map(|s| RespValue::BulkString(s.clone()))
+                    .collect(),
+            ),
+            Ok(None) => RespValue::Array(vec![]),
+            Err(e) => e,
+        },
+        Command::HKeys(key) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(Some(hash)) => RespValue::Array(
+                hash.iter()
+                    .map(|(f, _)| RespValue::BulkString(f.clone()))
+                    .collect(),
+            ),
+            Ok(None) => RespValue::Array(vec![]),
+            Err(e) => e,
+        },
+        Command::HVals(key) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(Some(hash)) => RespValue::Array(
+                hash.iter()
+                    .map(|(_, v)| RespValue::BulkString(v.clone()))
+                    .collect(),
+            ),
+            Ok(None) => RespValue::Array(vec![]),
+            Err(e) => e,
+        },
+        Command::HLen(key) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(hash) => RespValue::Integer(hash.map_or(0, |h| h.len()) as i64),
+            Err(e) => e,
+        },
+        Command::HExists(key, field) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(hash) => RespValue::Integer(hash.is_some_and(|h| h.contains(&field)) as i64),
+            Err(e) => e,
+        },
+        Command::HStrLen(key, field) => match get_hash(&mut store.dbs[client.db], &key) {
+            Ok(hash) => {
+                let len = hash.and_then(|h| h.get(&field)).map_or(0, |v| byte_len(v));
+                RespValue::Integer(len as i64)
+            }
+            Err(e) => e,
+        },
+        Command::HIncrBy(key, field, incr) => {
+            let limits = store.config.hash_listpack;
+
+            let hash = match get_or_create_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            let current = match hash.get(&field).map(|v| v.parse::<i64>()) {
+                None => 0,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    return RespValue::Error("hash value is not an integer".to_string());
+                }
+            };
+            let Some(updated) = current.checked_add(incr) else {
+                return RespValue::Error("increment or decrement would overflow".to_string());
+            };
+            hash.insert(field, updated.to_string(), limits);
+            RespValue::Integer(updated)
+        }
+        Command::HIncrByFloat(key, field, incr) => {
+            let limits = store.config.hash_listpack;
+
+            let hash = match get_or_create_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            let current = match hash.get(&field).map(|v| v.parse::<f64>()) {
+                None => 0.0,
+                Some(Ok(n)) if n.is_finite() => n,
+                Some(_) => return RespValue::Error("hash value is not a float".to_string()),
+            };
+            let updated = current + incr;
+            if !updated.is_finite() {
+                return RespValue::Error("increment would produce NaN or Infinity".to_string());
+            }
+            let updated = format_score(updated);
+            hash.insert(field, updated.clone(), limits);
+            RespValue::BulkString(updated)
+        }
+        Command::HSetNx(key, field, val) => {
+            let limits = store.config.hash_listpack;
+
+            let hash = match get_or_create_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            if hash.contains(&field) {
+                return RespValue::Integer(0);
+            }
+            hash.insert(field, val, limits);
+            RespValue::Integer(1)
+        }
+        Command::HRandField(key, count) => {
+            let hash = match get_hash(&mut store.dbs[client.db], &key) {
+                Ok(Some(hash)) => hash,
+                Ok(None) if count.is_some() => return RespValue::Array(vec![]),
+                Ok(None) => return RespValue::Null,
+                Err(e) => return e,
+            };
+
+            let Some((count, withvalues)) = count else {
+                let (field, _) = hash.random_entry().unwrap();
+                return RespValue::BulkString(field.clone());
+            };
+            let Some(picked) = random::try_sample(
+                count,
+                hash.len(),
+                || hash.iter().collect(),
+                || hash.random_entry().unwrap(),
+            ) else {
+                return count_out_of_range();
+            };
+
+            let mut reply = Vec::new();
+            for (field, val) in picked {
+                reply.push(RespValue::BulkString(field.clone()));
+                if withvalues {
+                    reply.push(RespValue::BulkString(val.clone()));
+                }
+            }
+            RespValue::Array(reply)
+        }
+        Command::HScan(key, cursor, opts) => {
+            let hash = match get_hash(&mut store.dbs[client.db], &key) {
+                Ok(Some(hash)) => hash,
+                Ok(None) => {
+                    return RespValue::Array(vec![
+                        RespValue::BulkString("0".to_string()),
+                        RespValue::Array(vec![]),
+                    ]);
+                }
+                Err(e) => return e,
+            };
+
+            let mut found = Vec::new();
+            let mut cursor = cursor;
+            let mut budget = opts.count.saturating_mul(10);
+            loop {
+                cursor = hash.scan(cursor, |field, val| {
+                    if opts.pattern.as_ref().is_none_or(|p| glob_match(p, field)) {
+                        found.push(RespValue::BulkString(field.clone()));
+                        if !opts.novalues {
+                            found.push(RespValue::BulkString(val.clone()));
+                        }
+                    }
+                });
+                budget -= 1;
+                if cursor == 0 || budget == 0 || found.len() >= opts.count {
+                    break;
+                }
+            }
+
+            RespValue::Array(vec![
+                RespValue::BulkString(cursor.to_string()),
+                RespValue::Array(found),
+            ])
+        }
+        Command::HExpire(key, at, cond, fields) => {
+            let map = &mut store.dbs[client.db];
+
+            let hash = match get_hash_mut(map, &key) {
+                Ok(Some(hash)) => hash,
+                Ok(None) => return field_statuses(fields.iter().map(|_| -2)),
+                Err(e) => return e,
+            };
+
+            let now = unix_time_ms();
+            let codes: Vec<i64> = fields
+                .iter()
+                .map(|field| hash.expire_field(field, at, cond, now))
+                .collect();
+
+            if hash.is_empty() {
+                map.remove(&key);
+            }
+            store.track_field_expiry(client.db, &key);
+            field_statuses(codes)
+        }
+        Command::HTtl(key, format, fields) => {
+            let hash = match get_hash(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+
+            let now = unix_time_ms();
+            field_statuses(fields.iter().map(|field| match hash {
+                Some(hash) => hash.ttl(field, format, now),
+                None => -2,
+            }))
+        }
+        Command::HPersist(key, fields) => {
+            let hash = match get_hash_mut(&mut store.dbs[client.db], &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            let Some(hash) = hash else {
+                return field_statuses(fields.iter().map(|_| -2));
+            };
+            field_statuses(fields.iter().map(|field| {
+                if !hash.contains(field) {
+                    -2
+                } else if hash.persist(field) {
+                    1
+                } else {
+                    -1
+                }
+            }))
+        }
+        Command::HGetEx(key, ttl, fields) => {
+            let map = &mut store.dbs[client.db];
+
+            let hash = match get_hash_mut(map, &key) {
+                Ok(Some(hash)) => hash,
+                Ok(None) => {
+                    return RespValue::Array(fields.iter().map(|_| RespValue::Null).collect());
+                }
+                Err(e) => return e,
+            };
+
+            let vals = fields
+                .iter()
+                .map(|field| match hash.get(field) {
+                    Some(val) => RespValue::BulkString(val.clone()),
+                    None => RespValue::Null,
+                })
+                .collect();
+            for field in &fields {
+                if hash.contains(field) {
+                    apply_field_ttl(hash, field, ttl);
+                }
+            }
+
+            if hash.is_empty() {
+                map.remove(&key);
+            }
+            store.track_field_expiry(client.db, &key);
+            RespValue::Array(vals)
+        }
+        Command::HSetEx(key, cond, ttl, pairs) => {
+            let limits = store.config.hash_listpack;
+            let map = &mut store.dbs[client.db];
+
+            let existing = match get_hash(map, &key) {
+                Ok(hash) => pairs
+                    .iter()
+                    .filter(|(field, _)| hash.is_some_and(|h| h.contains(field)))
+                    .count(),
+                Err(e) => return e,
+            };
+            match cond {
+                SetCondition::Fnx if existing > 0 => return RespValue::Integer(0),
+                SetCondition::Fxx if existing < pairs.len() => return RespValue::Integer(0),
+                _ => {}
+            }
+
+            let hash = match get_or_create_hash(map, &key) {
+                Ok(hash) => hash,
+                Err(e) => return e,
+            };
+            for (field, val) in pairs {
+                hash.insert(field.clone(), val, limits);
+                apply_field_ttl(hash, &field, ttl);
+            }
+
+            if hash.is_empty() {
+                map.remove(&key);
+            }
+            store.track_field_expiry(client.db, &key);
+            RespValue::Integer(1)
+        }
+        Command::SAdd(key, members) => {
+            let max_intset = store.config.set_max_intset_entries;
+
+            let set = match get_or_create_set(&mut store.dbs[client.db], &key) {
+                Ok(set) => set,
+                Err(e) => return e,
+            };
+            let added = members
+                .into_iter()
+                .filter(|member| set.insert(member.clone(), max_intset))
+                .count();
+            RespValue::Integer(added as i64)
+        }
+        Command::SRem(key, members) => {
+            let map = &mut store.dbs[client.db];
+
+            let set = match get_set_mut(map, &key) {
+                Ok(Some(set)) => set,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+            let removed = members.iter().filter(|member| set.remove(member)).count();
+            if set.is_empty() {
+                map.remove(&key);
+            }
+            RespValue::Integer(removed as i64)
+        }
+        Command::SMembers(key) => match get_set(&store.dbs[client.db], &key) {
+            Ok(Some(set)) => RespValue::Array(set.iter().map(RespValue::BulkString).collect()),
+            Ok(None) => RespValue::Array(vec![]),
+            Err(e) => e,
+        },
+        Command::SIsMember(key, member) => match get_set(&store.dbs[client.db], &key) {
+            Ok(set) => RespValue::Integer(set.is_some_and(|s| s.contains(&member)) as i64),
+            Err(e) => e,
+        },
+        Command::SMIsMember(key, members) => match get_set(&store.dbs[client.db], &key) {
+            Ok(set) => RespValue::Array(
+                members
+                    .iter()
+                    .map(|m| RespValue::Integer(set.is_some_and(|s| s.contains(m)) as i64))
+                    .collect(),
+            ),
+            Err(e) => e,
+        },
+        Command::SCard(key) => match get_set(&store.dbs[client.db], &key) {
+            Ok(set) => RespValue::Integer(set.map_or(0, |s| s.len()) as i64),
+            Err(e) => e,
+        },
+        Command::SPop(key, count) => {
+            let map = &mut store.dbs[client.db];
+
+            let set = match get_set_mut(map, &key) {
+                Ok(Some(set)) => set,
+                Ok(None) if count.is_some() => return RespValue::Array(vec![]),
+                Ok(None) => return RespValue::Null,
+                Err(e) => return e,
+            };
+
+            let Some(popped) = set.random_members(count.unwrap_or(1) as i64) else {
+                return count_out_of_range();
+            };
+            for member in &popped {
+                set.remove(member);
+            }
+            if set.is_empty() {
+                map.remove(&key);
+            }
+
+            match count {
+                Some(_) => {
+                    RespValue::Array(popped.into_iter().map(RespValue::BulkString).collect())
+                }
+                None => RespValue::BulkString(popped.into_iter().next().unwrap()),
+            }
+        }
+        Command::SRandMember(key, count) => {
+            let set = match get_set(&store.dbs[client.db], &key) {
+                Ok(Some(set)) => set,
+                Ok(None) if count.is_some() => return RespValue::Array(vec![]),
+                Ok(None) => return RespValue::Null,
+                Err(e) => return e,
+            };
+
+            match count {
+                Some(count) => match set.random_members(count) {
+                    Some(members) => {
+                        RespValue::Array(members.into_iter().map(RespValue::BulkString).collect())
+                    }
+                    None => count_out_of_range(),
+                },
+                None => RespValue::BulkString(set.random_members(1).unwrap().pop().unwrap()),
+            }
+        }
+        Command::SMove(src, dst, member) => {
+            let max_intset = store.config.set_max_intset_entries;
+            let map = &mut store.dbs[client.db];
+
+            // Both keys are type checked before anything is modified.
+            if let Err(e) = get_set(map, &dst) {
+                return e;
+            }
+            let set = match get_set_mut(map, &src) {
+                Ok(Some(set)) => set,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+            if src == dst {
+                return RespValue::Integer(set.contains(&member) as i64);
+            }
+            if !set.remove(&member) {
+                return RespValue::Integer(0);
+            }
+            if set.is_empty() {
+                map.remove(&src);
+            }
+
+            match get_or_create_set(map, &dst) {
+                Ok(set) => set.insert(member, max_intset),
+                Err(e) => return e,
+            };
+            RespValue::Integer(1)
+        }
+        Command::SScan(key, cursor, opts) => {
+            let set = match get_set(&store.dbs[client.db], &key) {
+                Ok(Some(set)) => set,
+                Ok(None) => {
+                    return RespValue::Array(vec![
+                        RespValue::BulkString("0".to_string()),
+                        RespValue::Array(vec![]),
+                    ]);
+                }
+                Err(e) => return e,
+            };
+
+            let mut found = Vec::new();
+            let mut cursor = cursor;
+            let mut budget = opts.count.saturating_mul(10);
+            loop {
+                cursor = set.scan(cursor, |member| {
+                    if opts.pattern.as_ref().is_none_or(|p| glob_match(p, &member)) {
+                        found.push(RespValue::BulkString(member));
+                    }
+                });
+                budget -= 1;
+                if cursor == 0 || budget == 0 || found.len() >= opts.count {
+                    break;
+                }
+            }
+
+            RespValue::Array(vec![
+                RespValue::BulkString(cursor.to_string()),
+                RespValue::Array(found),
+            ])
+        }
+        Command::SCombine(op, keys) => match get_sets(&store.dbs[client.db], &keys) {
+            Ok(sets) => RespValue::Array(
+                combine_sets(&sets, op, 0)
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+            Err(e) => e,
+        },
+        Command::SCombineStore(op, dst, keys) => {
+            let max_intset = store.config.set_max_intset_entries;
+            let map = &mut store.dbs[client.db];
+
+            let members = match get_sets(map, &keys) {
+                Ok(sets) => combine_sets(&sets, op, 0),
+                Err(e) => return e,
+            };
+
+            let len = members.len();
+            if members.is_empty() {
+                map.remove(&dst);
+            } else {
+                let mut set = Set::new();
+                for member in members {
+                    set.insert(member, max_intset);
+                }
+                map.insert(
+                    dst,
+                    DbEntry {
+                        data: DbData::Set(set),
+                        expires_at: None,
+                    },
+                );
+            }
+            RespValue::Integer(len as i64)
+        }
+        Command::SInterCard(keys, limit) => match get_sets(&store.dbs[client.db], &keys) {
+            Ok(sets) => RespValue::Integer(combine_sets(&sets, SetOp::Inter, limit).len() as i64),
+            Err(e) => e,
+        },
+        Command::ZCombine(op, keys, opts) => {
+            let entries = match get_scored_sets(&store.dbs[client.db], &keys) {
+                Ok(sets) => combine_zsets(&sets, op, &opts, 0),
+                Err(e) => return e,
+            };
+
+            let mut reply = Vec::new();
+            for (member, score) in entries {
+                reply.push(RespValue::BulkString(member));
+                if opts.withscores {
+                    reply.push(RespValue::BulkString(format_score(score)));
+                }
+            }
+            RespValue::Array(reply)
+        }
+        Command::ZCombineStore(op, dst, keys, opts) => {
+            let limits = store.config.zset_listpack;
+            let map = &mut store.dbs[client.db];
+
+            let entries = match get_scored_sets(map, &keys) {
+                Ok(sets) => combine_zsets(&sets, op, &opts, 0),
+                Err(e) => return e,
+            };
+
+            let len = entries.len();
+            store_zset(map, dst.clone(), entries, limits);
+            store.wake(client.db, &dst);
+            RespValue::Integer(len as i64)
+        }
+        Command::ZInterCard(keys, limit) => {
+            let opts = ZCombineOptions {
+                weights: vec![1.0; keys.len()],
+                aggregate: Aggregate::Sum,
+                withscores: false,
+            };
+            match get_scored_sets(&store.dbs[client.db], &keys) {
+                Ok(sets) => {
+                    RespValue::Integer(combine_zsets(&sets, SetOp::Inter, &opts, limit).len() as i64)
+                }
+                Err(e) => e,
+            }
+        }
+        Command::ZAdd(key, opts, pairs) => {
+            let limits = store.config.zset_listpack;
+            let map = &mut store.dbs[client.db];
+
+            // XX never creates the key, so don't leave an empty one behind.
+            match get_zset(map, &key) {
+                Ok(None) if opts.xx => {
+                    return if opts.incr {
+                        RespValue::Null
+                    } else {
+                        RespValue::Integer(0)
+                    };
+                }
+                Err(e) => return e,
+                _ => {}
+            }
+            let zset = get_or_create_zset(map, &key).unwrap();
+
+            let reply = zadd(zset, opts, pairs, limits);
+            if zset.is_empty() {
+                map.remove(&key);
+            } else {
+                store.wake(client.db, &key);
+            }
+            reply
+        }
+        Command::ZRem(key, members) => {
+            let map = &mut store.dbs[client.db];
+
+            let zset = match get_zset_mut(map, &key) {
+                Ok(Some(zset)) => zset,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+            let removed = members.iter().filter(|member| zset.remove(member)).count();
+            if zset.is_empty() {
+                map.remove(&key);
+            }
+            RespValue::Integer(removed as i64)
+        }
+        Command::ZScore(key, member) => match get_zset(&store.dbs[client.db], &key) {
+            Ok(zset) => match zset.and_then(|z| z.score(&member)) {
+                Some(score) => RespValue::BulkString(format_score(score)),
+                None => RespValue::Null,
+            },
+            Err(e) => e,
+        },
+        Command::ZMScore(key, members) => match get_zset(&store.dbs[client.db], &key) {
+            Ok(zset) => RespValue::Array(
+                members
+                    .iter()
+                    .map(|member| match zset.and_then(|z| z.score(member)) {
+                        Some(score) => RespValue::BulkString(format_score(score)),
+                        None => RespValue::Null,
+                    })
+                    .collect(),
+            ),
+            Err(e) => e,
+        },
+        Command::ZCard(key) => match get_zset(&store.dbs[client.db], &key) {
+            Ok(zset) => RespValue::Integer(zset.map_or(0, |z| z.len()) as i64),
+            Err(e) => e,
+        },
+        Command::ZCount(key, range) => match get_zset(&store.dbs[client.db], &key) {
+            Ok(zset) => {
+                let count = zset.map_or(0, |z| {
+                    let (start, end) = z.score_range(&range);
+                    end.saturating_sub(start)
+                });
+                RespValue::Integer(count as i64)
+            }
+            Err(e) => e,
+        },
+        Command::ZRank(key, member, withscore) => {
+            zrank(&store.dbs[client.db], &key, &member, withscore, false)
+        }
+        Command::ZRevRank(key, member, withscore) => {
+            zrank(&store.dbs[client.db], &key, &member, withscore, true)
+        }
+        Command::ZRandMember(key, count) => {
+            let zset = match get_zset(&store.dbs[client.db], &key) {
+                Ok(Some(zset)) => zset,
+                Ok(None) if count.is_some() => return RespValue::Array(vec![]),
+                Ok(None) => return RespValue::Null,
+                Err(e) => return e,
+            };
+
+            let Some((count, withscores)) = count else {
+                let (member, _) = zset.random_members(1).unwrap().pop().unwrap();
+                return RespValue::BulkString(member);
+            };
+            let Some(picked) = zset.random_members(count) else {
+                return count_out_of_range();
+            };
+            let mut reply = Vec::new();
+            for (member, score) in picked {
+                reply.push(RespValue::BulkString(member));
+                if withscores {
+                    reply.push(RespValue::BulkString(format_score(score)));
+                }
+            }
+            RespValue::Array(reply)
+        }
+        Command::ZRange(key, opts) => {
+            let zset = match get_zset(&store.dbs[client.db], &key) {
+                Ok(Some(zset)) => zset,
+                Ok(None) => return RespValue::Array(vec![]),
+                Err(e) => return e,
+            };
+
+            let (start, end) = zrange_bounds(zset, &opts.by, opts.rev, opts.limit);
+            let mut reply = Vec::new();
+            for (member, score) in zset.range(start, end, opts.rev) {
+                reply.push(RespValue::BulkString(member.clone()));
+                if opts.withscores {
+                    reply.push(RespValue::BulkString(format_score(score)));
+                }
+            }
+            RespValue::Array(reply)
+        }
+        Command::ZRangeStore(dst, src, opts) => {
+            let limits = store.config.zset_listpack;
+            let map = &mut store.dbs[client.db];
+
+            let entries: Vec<(String, f64)> = match get_zset(map, &src) {
+                Ok(Some(zset)) => {
+                    let (start, end) = zrange_bounds(zset, &opts.by, opts.rev, opts.limit);
+                    zset.range(start, end, opts.rev)
+                        .map(|(member, score)| (member.clone(), score))
+                        .collect()
+                }
+                Ok(None) => Vec::new(),
+                Err(e) => return e,
+            };
+
+            let len = entries.len();
+            store_zset(map, dst.clone(), entries, limits);
+            store.wake(client.db, &dst);
+            RespValue::Integer(len as i64)
+        }
+        Command::ZRemRange(key, spec) => {
+            let map = &mut store.dbs[client.db];
+
+            let zset = match get_zset_mut(map, &key) {
+                Ok(Some(zset)) => zset,
+                Ok(None) => return RespValue::Integer(0),
+                Err(e) => return e,
+            };
+
+            let (start, end) = zrange_bounds(zset, &spec, false, None);
+            let doomed: Vec<String> = zset
+                .range(start, end, false)
+                .map(|(member, _)| member.clone())
+                .collect();
+            for member in &doomed {
+                zset.remove(member);
+            }
+            if zset.is_empty() {
+                map.remove(&key);
+            }
+            RespValue::Integer(doomed.len() as i64)
+        }
+        Command::ZLexCount(key, range) => match get_zset(&store.dbs[client.db], &key) {
+            Ok(zset) => {
+                let count = zset.map_or(0, |z| {
+                    let (start, end) = z.lex_range(&range);
+                    end.saturating_sub(start)
+                });
+                RespValue::Integer(count as i64)
+            }
+            Err(e) => e,
+        },
+        Command::ZPopMin(key, count) => zpop(&mut store.dbs[client.db], &key, count, ScoreEnd::Min),
+        Command::ZPopMax(key, count) => zpop(&mut store.dbs[client.db], &key, count, ScoreEnd::Max),
+        Command::BZPopMin(keys, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                blocking_zpop(store, db_index, &keys, ScoreEnd::Min)
+            })
+        }
+        Command::BZPopMax(keys, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                blocking_zpop(store, db_index, &keys, ScoreEnd::Max)
+            })
+        }
+        Command::ZMPop(keys, end, count) => {
+            match zpop_first_nonempty(&mut store.dbs[client.db], &keys, end, count) {
+                Ok(Some((key, popped))) => zmpop_reply(key, popped),
+                Ok(None) => RespValue::NullArray,
+                Err(e) => e,
+            }
+        }
+        Command::BZMPop(keys, end, count, timeout) => {
+            let db_index = client.db;
+            block_until(store, db, client, keys.clone(), timeout, move |store| {
+                match zpop_first_nonempty(&mut store.dbs[db_index], &keys, end, count) {
+                    Ok(Some((key, popped))) => Some(zmpop_reply(key, popped)),
+                    Ok(None) => None,
+                    Err(e) => Some(e),
+                }
+            })
+        }
+        Command::LMove(src, dst, from, to) => {
+            match move_list_element(&mut store.dbs[client.db], &src, dst.clone(), from, to) {
+                Ok(Some(val)) => {
+                    store.wake(client.db, &dst);
+                    RespValue::BulkString(val)
+                }
+                Ok(None) => RespValue::Null,
+                Err(e) => e,
+            }
+        }
+        Command::BLMove(src, dst, from, to, timeout) => {
+            let db_index = client.db;
+            block_until(
+                store,
+                db,
+                client,
+                vec![src.clone()],
+                timeout,
+                move |store| match move_list_element(
+                    &mut store.dbs[db_index],
+                    &src,
+                    dst.clone(),
+                    from,
+                    to,
+                ) {
+                    Ok(Some(val)) => {
+                        store.blocked.signal(db_index, &dst);
+                        Some(RespValue::BulkString(val))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some(e),
+                },
+            )
+        }
+        Command::PfAdd(key, elements) => {
+            let sparse_max_bytes = store.config.hll_sparse_max_bytes;
+            let map = &mut store.dbs[client.db];
+
+            let (mut hll, mut changed) = match get_hll(map, &key) {
+                Ok(Some(hll)) => (hll, false),
+                Ok(None) => (HyperLogLog::new(), true),
+                Err(e) => return e,
+            };
+            for element in &elements {
+                match hll.add(&to_bytes(element), sparse_max_bytes) {
+                    Ok(updated) => changed |= updated,
+                    Err(_) => return corrupted_hll(),
+                }
+            }
+            if changed {
+                set_hll(map, &key, hll);
+            }
+            RespValue::Integer(changed as i64)
+        }
+        Command::PfCount(keys) => {
+            let map = &mut store.dbs[client.db];
+
+            if let [key] = keys.as_slice() {
+                // A single key serves the count from its cache, refreshing
+                // the cache if a PFADD invalidated it.
+                return match get_hll(map, key) {
+                    Ok(Some(mut hll)) => match hll.count() {
+                        Ok(count) => {
+                            set_hll(map, key, hll);
+                            RespValue::Integer(count as i64)
+                        }
+                        Err(_) => corrupted_hll(),
+                    },
+                    Ok(None) => RespValue::Integer(0),
+                    Err(e) => e,
+                };
+            }
+
+            // Several keys are counted through a temporary union, leaving
+            // the sources and their caches untouched.
+            let mut union = HyperLogLog::new();
+            for key in &keys {
+                match get_hll(map, key) {
+                    Ok(Some(hll)) => {
+                        if merge_hll(&mut union, &hll, 0).is_err() {
+                            return corrupted_hll();
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => return e,
+                }
+            }
+            match union.count() {
+                Ok(count) => RespValue::Integer(count as i64),
+                Err(_) => corrupted_hll(),
+            }
+        }
+        Command::PfMerge(dst, srcs) => {
+            let sparse_max_bytes = store.config.hll_sparse_max_bytes;
+            let map = &mut store.dbs[client.db];
+
+            let mut merged = match get_hll(map, &dst) {
+                Ok(hll) => hll.unwrap_or_default(),
+                Err(e) => return e,
+            };
+            // The result stays sparse only if every input is sparse.
+            let mut dense = !merged.is_sparse();
+            for key in &srcs {
+                match get_hll(map, key) {
+                    Ok(Some(hll)) => {
+                        dense |= !hll.is_sparse();
+                        if merge_hll(&mut merged, &hll, sparse_max_bytes).is_err() {
+                            return corrupted_hll();
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => return e,
+                }
+            }
+            if dense && merged.make_dense().is_err() {
+                return corrupted_hll();
+            }
+            set_hll(map, &dst, merged);
+            RespValue::SimpleString("OK".to_string())
+        }
+        Command::PfDebug(op, key) => {
+            let map = &mut store.dbs[client.db];
+
+            let mut hll = match get_hll(map, &key) {
+                Ok(Some(hll)) => hll,
+                Ok(None) => {
+                    return RespValue::Error("The specified key does not exist".to_string());
+                }
+                Err(e) => return e,
+            };
+            match op {
+                PfDebugOp::GetReg => {
+                    let Ok(converted) = hll.make_dense() else {
+                        return corrupted_hll();
+                    };
+                    let registers = hll.registers().unwrap();
+                    if converted {
+                        set_hll(map, &key, hll);
+                    }
+                    RespValue::Array(
+                        registers
+                            .into_iter()
+                            .map(|r| RespValue::Integer(r as i64))
+                            .collect(),
+                    )
+                }
+                PfDebugOp::Decode if !hll.is_sparse() => {
+                    RespValue::Error("HLL encoding is not sparse".to_string())
+                }
+                PfDebugOp::Decode => match hll.decode() {
+                    Ok(ops) => RespValue::BulkString(ops),
+                    Err(_) => corrupted_hll(),
+                },
+                PfDebugOp::Encoding => {
+                    let encoding = if hll.is_sparse() { "sparse" } else { "dense" };
+                    RespValue::SimpleString(encoding.to_string())
+                }
+                PfDebugOp::ToDense => match hll.make_dense() {
+                    Ok(converted) => {
+                        if converted {
+                            set_hll(map, &key, hll);
+                        }
+                        RespValue::Integer(converted as i64)
+                    }
+                    Err(_) => corrupted_hll(),
+                },
+            }
+        }
+        Command::PfSelfTest => {
+            let sparse_max_bytes = store.config.hll_sparse_max_bytes;
+            match hyperloglog::self_test(sparse_max_bytes) {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(e),
+            }
+        }
+    }
+}
+
+/// Reply for a random sampling count too large to collect.
+fn count_out_of_range() -> RespValue {
+    RespValue::Error("value is out of range".to_string())
+}
+
+fn wrong_type() -> RespValue {
+    RespValue::Error(
+        "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+    )
+}
+
+/// Returns the list stored at `key`, `Ok(None)` if the key does not exist,
+/// or a WRONGTYPE error if it holds another type.
+fn get_list<'a>(map: &'a Dict<DbEntry>, key: &str) -> Result<Option<&'a List>, RespValue> {
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::List(list)) => Ok(Some(list)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+fn get_list_mut<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<Option<&'a mut List>, RespValue> {
+    match map.get_mut(key).map(|e| &mut e.data) {
+        None => Ok(None),
+        Some(DbData::List(list)) => Ok(Some(list)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Lazily drops the expired fields of the hash at `key`, removing the key
+/// once no fields are left.
+fn expire_hash_fields(map: &mut Dict<DbEntry>, key: &str) {
+    if let Some(DbEntry {
+        data: DbData::Hash(hash),
+        ..
+    }) = map.get_mut(key)
+        && hash.remove_expired(unix_time_ms()) > 0
+        && hash.is_empty()
+    {
+        map.remove(key);
+    }
+}
+
+fn get_hash<'a>(map: &'a mut Dict<DbEntry>, key: &str) -> Result<Option<&'a Hash>, RespValue> {
+    expire_hash_fields(map, key);
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+fn get_hash_mut<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<Option<&'a mut Hash>, RespValue> {
+    expire_hash_fields(map, key);
+    match map.get_mut(key).map(|e| &mut e.data) {
+        None => Ok(None),
+        Some(DbData::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Returns the hash at `key`, creating an empty one if the key is missing.
+/// Callers must add at least one field so no empty hash is left behind.
+fn get_or_create_hash<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<&'a mut Hash, RespValue> {
+    expire_hash_fields(map, key);
+    let entry = map.get_or_insert_with(key.to_string(), || DbEntry {
+        data: DbData::Hash(Hash::new()),
+        expires_at: None,
+    });
+    match &mut entry.data {
+        DbData::Hash(hash) => Ok(hash),
+        _ => Err(wrong_type()),
+    }
+}
+
+fn get_set<'a>(map: &'a Dict<DbEntry>, key: &str) -> Result<Option<&'a Set>, RespValue> {
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+fn get_set_mut<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<Option<&'a mut Set>, RespValue> {
+    match map.get_mut(key).map(|e| &mut e.data) {
+        None => Ok(None),
+        Some(DbData::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Returns the set at `key`, creating an empty one if the key is missing.
+/// Callers must add at least one member so no empty set is left behind.
+fn get_or_create_set<'a>(map: &'a mut Dict<DbEntry>, key: &str) -> Result<&'a mut Set, RespValue> {
+    let entry = map.get_or_insert_with(key.to_string(), || DbEntry {
+        data: DbData::Set(Set::new()),
+        expires_at: None,
+    });
+    match &mut entry.data {
+        DbData::Set(set) => Ok(set),
+        _ => Err(wrong_type()),
+    }
+}
+
+fn get_zset<'a>(map: &'a Dict<DbEntry>, key: &str) -> Result<Option<&'a ZSet>, RespValue> {
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+fn get_zset_mut<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<Option<&'a mut ZSet>, RespValue> {
+    match map.get_mut(key).map(|e| &mut e.data) {
+        None => Ok(None),
+        Some(DbData::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Returns the sorted set at `key`, creating an empty one if the key is
+/// missing. Callers must remove the key again if nothing gets added.
+fn get_or_create_zset<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<&'a mut ZSet, RespValue> {
+    let entry = map.get_or_insert_with(key.to_string(), || DbEntry {
+        data: DbData::ZSet(ZSet::new()),
+        expires_at: None,
+    });
+    match &mut entry.data {
+        DbData::ZSet(zset) => Ok(zset),
+        _ => Err(wrong_type()),
+    }
+}
+
+fn get_stream<'a>(map: &'a Dict<DbEntry>, key: &str) -> Result<Option<&'a Stream>, RespValue> {
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Formats a stream entry as `[id, [field, value, ...]]`.
+fn entry_reply(id: StreamId, fields: &Fields) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString(id.to_string()),
+        RespValue::Array(
+            fields
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        RespValue::BulkString(field.clone()),
+                        RespValue::BulkString(value.clone()),
+                    ]
+                })
+                .collect(),
+        ),
+    ])
+}
+
+/// Shared implementation of `XRANGE` and `XREVRANGE`.
+fn xrange(
+    map: &Dict<DbEntry>,
+    key: &str,
+    start: StreamId,
+    end: StreamId,
+    count: Option<usize>,
+    rev: bool,
+) -> RespValue {
+    let stream = match get_stream(map, key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return RespValue::Array(vec![]),
+        Err(e) => return e,
+    };
+    if count == Some(0) {
+        return RespValue::NullArray;
+    }
+
+    RespValue::Array(
+        stream
+            .range(start, end, rev)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| entry_reply(id, fields))
+            .collect(),
+    )
+}
+
+/// One `XREAD` pass: up to `count` entries (0 for all) after the given ID of
+/// every stream, as `[key, [entry, ...]]` pairs for the streams that have
+/// any. Returns `None` if none do, so a blocking read keeps waiting.
+fn xread(map: &Dict<DbEntry>, streams: &[(String, StreamId)], count: usize) -> Option<RespValue> {
+    let limit = if count == 0 { usize::MAX } else { count };
+    let mut reply = Vec::new();
+    for (key, after) in streams {
+        let stream = match get_stream(map, key) {
+            Ok(Some(stream)) => stream,
+            Ok(None) => continue,
+            Err(e) => return Some(e),
+        };
+        let Some(start) = after.next() else {
+            continue;
+        };
+
+        let entries: Vec<RespValue> = stream
+            .range(start, StreamId::MAX, false)
+            .take(limit)
+            .map(|(id, fields)| entry_reply(id, fields))
+            .collect();
+        if !entries.is_empty() {
+            reply.push(RespValue::Array(vec![
+                RespValue::BulkString(key.clone()),
+                RespValue::Array(entries),
+            ]));
+        }
+    }
+    (!reply.is_empty()).then_some(RespValue::Array(reply))
+}
+
+fn get_stream_mut<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<Option<&'a mut Stream>, RespValue> {
+    match map.get_mut(key).map(|e| &mut e.data) {
+        None => Ok(None),
+        Some(DbData::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Error of the `XGROUP` subcommands for a missing key.
+const XGROUP_NO_KEY: &str = "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+/// Looks up the stream at `key` for an `XGROUP` subcommand, which fails
+/// unless both the stream and `group` exist.
+fn get_xgroup_stream<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+    group: &str,
+) -> Result<&'a mut Stream, RespValue> {
+    let Some(stream) = get_stream_mut(map, key)? else {
+        return Err(RespValue::Error(XGROUP_NO_KEY.to_string()));
+    };
+    if !stream.groups.contains_key(group) {
+        return Err(RespValue::Error(format!(
+            "NOGROUP No such consumer group '{}' for key name '{}'",
+            group, key
+        )));
+    }
+    Ok(stream)
+}
+
+/// One `XREADGROUP` pass. New entries (`>`) are delivered to `consumer`
+/// and added to the PEL unless `NOACK` is set, while an explicit ID
+/// re-reads the consumer's own pending entries after it. Returns `None`
+/// if there is nothing new to deliver, so a blocking read keeps waiting.
+fn xreadgroup(
+    map: &mut Dict<DbEntry>,
+    group: &str,
+    consumer: &str,
+    streams: &[(String, Option<StreamId>)],
+    opts: XReadOptions,
+) -> Option<RespValue> {
+    // Check every stream before delivering anything from any of them.
+    for (key, _) in streams {
+        match get_stream(map, key) {
+            Ok(Some(stream)) if stream.groups.contains_key(group) => {}
+            Ok(_) => {
+                return Some(RespValue::Error(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group
+                )));
+            }
+            Err(e) => return Some(e),
+        }
+    }
+
+    let now = unix_time_ms();
+    let limit = if opts.count == 0 {
+        usize::MAX
+    } else {
+        opts.count
+    };
+    let mut reply = Vec::new();
+    for (key, id) in streams {
+        let stream = get_stream_mut(map, key).unwrap().unwrap();
+        let entries: Vec<RespValue> = match id {
+            None => {
+                let delivered = stream
+                    .read_group(group, consumer, opts.count, opts.noack, now)
+                    .unwrap();
+                if delivered.is_empty() {
+                    continue;
+                }
+                delivered
+                    .iter()
+                    .map(|(id, fields)| entry_reply(*id, fields))
+                    .collect()
+            }
+            Some(after) => {
+                let cg = stream.groups.get_mut(group).unwrap();
+                let pending: Vec<StreamId> = match after.next() {
+                    Some(start) => cg
+                        .consumer(consumer, now)
+                        .pending
+                        .range(start..)
+                        .take(limit)
+                        .copied()
+                        .collect(),
+                    None => Vec::new(),
+                };
+                // Entries deleted since their delivery come back as nil.
+                pending
+                    .into_iter()
+                    .map(|id| match stream.get(id) {
+                        Some(fields) => entry_reply(id, fields),
+                        None => RespValue::Array(vec![
+                            RespValue::BulkString(id.to_string()),
+                            RespValue::NullArray,
+                        ]),
+                    })
+                    .collect()
+            }
+        };
+        reply.push(RespValue::Array(vec![
+            RespValue::BulkString(key.clone()),
+            RespValue::Array(entries),
+        ]));
+    }
+    (!reply.is_empty()).then_some(RespValue::Array(reply))
+}
+
+/// Replies with a claimed entry, or just its ID for `JUSTID`.
+fn claimed_reply(id: StreamId, fields: &Fields, justid: bool) -> RespValue {
+    if justid {
+        RespValue::BulkString(id.to_string())
+    } else {
+        entry_reply(id, fields)
+    }
+}
+
+/// Claims the pending `ids` that have been idle for at least `min_idle`
+/// ms for `consumer`. Entries deleted from the stream are dropped from the
+/// PEL instead, and `FORCE` claims entries nobody had pending yet.
+fn xclaim(
+    stream: &mut Stream,
+    group: &str,
+    consumer: &str,
+    min_idle: u64,
+    ids: &[StreamId],
+    opts: XClaimOptions,
+) -> RespValue {
+    let now = unix_time_ms();
+    let mut reply = Vec::new();
+    for &id in ids {
+        let Some(fields) = stream.get(id).cloned() else {
+            stream.groups.get_mut(group).unwrap().ack(id);
+            continue;
+        };
+
+        let cg = stream.groups.get_mut(group).unwrap();
+        match cg.pending.get(&id) {
+            Some(entry) if now.saturating_sub(entry.delivery_time) < min_idle => continue,
+            None if !opts.force => continue,
+            _ => {}
+        }
+        cg.claim(
+            id,
+            consumer,
+            opts.delivery_time.unwrap_or(now),
+            opts.retry_count,
+            opts.justid,
+            now,
+        );
+        reply.push(claimed_reply(id, &fields, opts.justid));
+    }
+
+    let cg = stream.groups.get_mut(group).unwrap();
+    cg.consumer(consumer, now);
+    if let Some(last_id) = opts.last_id {
+        cg.last_id = cg.last_id.max(last_id);
+    }
+    RespValue::Array(reply)
+}
+
+/// Scans the PEL from `start`, claiming up to `count` entries idle for at
+/// least `min_idle` ms and dropping those deleted from the stream. Replies
+/// with the cursor to resume from (0-0 once the PEL is exhausted), the
+/// claimed entries and the deleted IDs.
+fn xautoclaim(
+    stream: &mut Stream,
+    group: &str,
+    consumer: &str,
+    min_idle: u64,
+    start: StreamId,
+    count: usize,
+    justid: bool,
+) -> RespValue {
+    let now = unix_time_ms();
+    // Like Redis, bound the work done on a PEL full of young entries.
+    let mut attempts = count.saturating_mul(10);
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut cursor = Some(start);
+
+    while let Some(from) = cursor
+        && attempts > 0
+        && claimed.len() < count
+    {
+        let cg = stream.groups.get_mut(group).unwrap();
+        let Some((&id, entry)) = cg.pending.range(from..).next() else {
+            cursor = None;
+            break;
+        };
+        let idle = now.saturating_sub(entry.delivery_time);
+        cursor = id.next();
+        attempts -= 1;
+
+        let Some(fields) = stream.get(id).cloned() else {
+            stream.groups.get_mut(group).unwrap().ack(id);
+            deleted.push(RespValue::BulkString(id.to_string()));
+            continue;
+        };
+        if idle < min_idle {
+            continue;
+        }
+        let cg = stream.groups.get_mut(group).unwrap();
+        cg.claim(id, consumer, now, None, justid, now);
+        claimed.push(claimed_reply(id, &fields, justid));
+    }
+
+    // The cursor is the next pending entry, if any is left.
+    let cg = stream.groups.get_mut(group).unwrap();
+    cg.consumer(consumer, now);
+    let next = cursor
+        .and_then(|from| cg.pending.range(from..).next())
+        .map_or(StreamId::MIN, |(&id, _)| id);
+    RespValue::Array(vec![
+        RespValue::BulkString(next.to_string()),
+        RespValue::Array(claimed),
+        RespValue::Array(deleted),
+    ])
+}
+
+fn optional_integer(n: Option<u64>) -> RespValue {
+    n.map_or(RespValue::Null, |n| RespValue::Integer(n as i64))
+}
+
+/// The `XINFO STREAM` reply. With `FULL`, `full` is how many entries and
+/// PEL items to list (0 for all), and the groups are described in full
+/// instead of counted.
+fn xinfo_stream(stream: &Stream, full: Option<usize>) -> RespValue {
+    let field = |name: &str| RespValue::BulkString(name.to_string());
+    let entry_or_nil = |entry: Option<(StreamId, &Fields)>| {
+        entry.map_or(RespValue::Null, |(id, f)| entry_reply(id, f))
+    };
+
+    let mut reply = vec![
+        field("length"),
+        RespValue::Integer(stream.len() as i64),
+        field("radix-tree-keys"),
+        RespValue::Integer(stream.node_count() as i64),
+        field("radix-tree-nodes"),
+        RespValue::Integer(stream.node_count() as i64),
+        field("last-generated-id"),
+        RespValue::BulkString(stream.last_id().to_string()),
+        field("max-deleted-entry-id"),
+        RespValue::BulkString(stream.max_deleted_id().to_string()),
+        field("entries-added"),
+        RespValue::Integer(stream.entries_added() as i64),
+        field("recorded-first-entry-id"),
+        RespValue::BulkString(
+            stream
+                .first_entry()
+                .map_or(StreamId::MIN, |(id, _)| id)
+                .to_string(),
+        ),
+    ];
+
+    let Some(count) = full else {
+        reply.extend([
+            field("groups"),
+            RespValue::Integer(stream.groups.len() as i64),
+            field("first-entry"),
+            entry_or_nil(stream.first_entry()),
+            field("last-entry"),
+            entry_or_nil(stream.last_entry()),
+        ]);
+        return RespValue::Array(reply);
+    };
+
+    let limit = if count == 0 { usize::MAX } else { count };
+    let entries = stream
+        .range(StreamId::MIN, StreamId::MAX, false)
+        .take(limit)
+        .map(|(id, fields)| entry_reply(id, fields))
+        .collect();
+
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, cg)| {
+            let pending = cg
+                .pending
+                .iter()
+                .take(limit)
+                .map(|(id, entry)| {
+                    RespValue::Array(vec![
+                        RespValue::BulkString(id.to_string()),
+                        RespValue::BulkString(entry.consumer.clone()),
+                        RespValue::Integer(entry.delivery_time as i64),
+                        RespValue::Integer(entry.delivery_count as i64),
+                    ])
+                })
+                .collect();
+            let consumers = cg
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let pending = consumer
+                        .pending
+                        .iter()
+                        .take(limit)
+                        .map(|id| {
+                            let entry = &cg.pending[id];
+                            RespValue::Array(vec![
+                                RespValue::BulkString(id.to_string()),
+                                RespValue::Integer(entry.delivery_time as i64),
+                                RespValue::Integer(entry.delivery_count as i64),
+                            ])
+                        })
+                        .collect();
+                    RespValue::Array(vec![
+                        field("name"),
+                        RespValue::BulkString(name.clone()),
+                        field("seen-time"),
+                        RespValue::Integer(consumer.seen_time as i64),
+                        field("active-time"),
+                        RespValue::Integer(consumer.active_time.map_or(-1, |at| at as i64)),
+                        field("pel-count"),
+                        RespValue::Integer(consumer.pending.len() as i64),
+                        field("pending"),
+                        RespValue::Array(pending),
+                    ])
+                })
+                .collect();
+
+            RespValue::Array(vec![
+                field("name"),
+                RespValue::BulkString(name.clone()),
+                field("last-delivered-id"),
+                RespValue::BulkString(cg.last_id.to_string()),
+                field("entries-read"),
+                optional_integer(cg.entries_read),
+                field("lag"),
+                optional_integer(stream.lag(cg)),
+                field("pel-count"),
+                RespValue::Integer(cg.pending.len() as i64),
+                field("pending"),
+                RespValue::Array(pending),
+                field("consumers"),
+                RespValue::Array(consumers),
+            ])
+        })
+        .collect();
+
+    reply.extend([
+        field("entries"),
+        RespValue::Array(entries),
+        field("groups"),
+        RespValue::Array(groups),
+    ]);
+    RespValue::Array(reply)
+}
+
+/// The summary form of `XPENDING`: the number of pending entries, the
+/// smallest and largest pending ID, and how many each consumer holds.
+fn xpending_summary(cg: &ConsumerGroup) -> RespValue {
+    let (Some((first, _)), Some((last, _))) =
+        (cg.pending.first_key_value(), cg.pending.last_key_value())
+    else {
+        return RespValue::Array(vec![
+            RespValue::Integer(0),
+            RespValue::Null,
+            RespValue::Null,
+            RespValue::NullArray,
+        ]);
+    };
+
+    let consumers = cg
+        .consumers
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending.is_empty())
+        .map(|(name, consumer)| {
+            RespValue::Array(vec![
+                RespValue::BulkString(name.clone()),
+                RespValue::BulkString(consumer.pending.len().to_string()),
+            ])
+        })
+        .collect();
+    RespValue::Array(vec![
+        RespValue::Integer(cg.pending.len() as i64),
+        RespValue::BulkString(first.to_string()),
+        RespValue::BulkString(last.to_string()),
+        RespValue::Array(consumers),
+    ])
+}
+
+/// The extended form of `XPENDING`: `[id, consumer, idle ms, deliveries]`
+/// for each pending entry in the range.
+fn xpending_range(cg: &ConsumerGroup, range: &XPendingRange) -> RespValue {
+    if range.start > range.end {
+        return RespValue::Array(vec![]);
+    }
+
+    let now = unix_time_ms();
+    RespValue::Array(
+        cg.pending
+            .range(range.start..=range.end)
+            .filter(|(_, entry)| {
+                range
+                    .consumer
+                    .as_ref()
+                    .is_none_or(|name| &entry.consumer == name)
+            })
+            .map(|(id, entry)| (id, entry, now.saturating_sub(entry.delivery_time)))
+            .filter(|(_, _, idle)| *idle >= range.min_idle)
+            .take(range.count)
+            .map(|(id, entry, idle)| {
+                RespValue::Array(vec![
+                    RespValue::BulkString(id.to_string()),
+                    RespValue::BulkString(entry.consumer.clone()),
+                    RespValue::Integer(idle as i64),
+                    RespValue::Integer(entry.delivery_count as i64),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Returns the stream at `key`, creating an empty one if the key is missing.
+/// Callers must remove the key again if nothing gets added.
+fn get_or_create_stream<'a>(
+    map: &'a mut Dict<DbEntry>,
+    key: &str,
+) -> Result<&'a mut Stream, RespValue> {
+    let entry = map.get_or_insert_with(key.to_string(), || DbEntry {
+        data: DbData::Stream(Stream::new()),
+        expires_at: None,
+    });
+    match &mut entry.data {
+        DbData::Stream(stream) => Ok(stream),
+        _ => Err(wrong_type()),
+    }
+}
+
+/// Returns the HyperLogLog stored at `key`, or `Ok(None)` if the key does
+/// not exist. Like `GET`, an expired string is dropped first. Strings that
+/// lack the HyperLogLog header get their own WRONGTYPE error.
+fn get_hll(map: &mut Dict<DbEntry>, key: &str) -> Result<Option<HyperLogLog>, RespValue> {
+    if map.get(key).is_some_and(|entry| entry.is_expired()) {
+        map.remove(key);
+    }
+    match map.get(key).map(|e| &e.data) {
+        None => Ok(None),
+        Some(DbData::String(s)) => {
+            HyperLogLog::from_bytes(to_bytes(s))
+                .map(Some)
+                .ok_or_else(|| {
+                    RespValue::Error(
+                        "WRONGTYPE Key is not a valid HyperLogLog string value.".to_string(),
+                    )
+                })
+        }
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Stores `hll` as the string value of `key`, keeping any TTL.
+fn set_hll(map: &mut Dict<DbEntry>, key: &str, hll: HyperLogLog) {
+    let data = DbData::String(from_bytes(&hll.into_bytes()));
+    match map.get_mut(key) {
+        Some(entry) => entry.data = data,
+        None => {
+            map.insert(
+                key.to_string(),
+                DbEntry {
+                    data,
+                    expires_at: None,
+                },
+            );
+        }
+    }
+}
+
+fn merge_hll(
+    dst: &mut HyperLogLog,
+    src: &HyperLogLog,
+    sparse_max_bytes: usize,
+) -> Result<(), hyperloglog::Corrupted> {
+    dst.merge(&src.registers()?, sparse_max_bytes)
+}
+
+fn corrupted_hll() -> RespValue {
+    RespValue::Error("INVALIDOBJ Corrupted HLL object detected".to_string())
+}
+
+/// Applies the pairs of a `ZADD` (or `ZINCRBY`) to `zset` and builds the
+/// reply: the number of added (or, with `CH`, changed) members, or the new
+/// score in `INCR` mode.
+fn zadd(
+    zset: &mut ZSet,
+    opts: ZAddOptions,
+    pairs: Vec<(f64, String)>,
+    limits: ListpackLimits,
+) -> RespValue {
+    let mut added = 0;
+    let mut changed = 0;
+    let mut incr_result = None;
+
+    for (score, member) in pairs {
+        let new_score = match zset.score(&member) {
+            Some(_) if opts.nx => continue,
+            None if opts.xx => continue,
+            Some(current) => {
+                let new_score = if opts.incr { current + score } else { score };
+                if new_score.is_nan() {
+                    return RespValue::Error("resulting score is not a number (NaN)".to_string());
+                }
+                if (opts.gt && new_score <= current) || (opts.lt && new_score >= current) {
+                    continue;
+                }
+                if new_score != current {
+                    zset.insert(member, new_score, limits);
+                    changed += 1;
+                }
+                new_score
+            }
+            None => {
+                zset.insert(member, score, limits);
+                added += 1;
+                score
+            }
+        };
+        incr_result = Some(new_score);
+    }
+
+    if opts.incr {
+        match incr_result {
+            Some(score) => RespValue::BulkString(format_score(score)),
+            None => RespValue::Null,
+        }
+    } else if opts.ch {
+        RespValue::Integer(added + changed)
+    } else {
+        RespValue::Integer(added)
+    }
+}
+
+/// Resolves a range spec to the ascending rank range `start..end` to visit,
+/// in descending order if `rev`. With `rev`, ranks count from the highest
+/// score. `LIMIT` is applied by narrowing the range from the side iteration
+/// starts at, so skipped elements are never walked.
+fn zrange_bounds(
+    zset: &ZSet,
+    spec: &RangeSpec,
+    rev: bool,
+    limit: Option<(i64, i64)>,
+) -> (usize, usize) {
+    let len = zset.len();
+    let (start, end) = match spec {
+        RangeSpec::Rank(start, stop) => match clamp_rank_range(*start, *stop, len) {
+            Some((start, stop)) if rev => (len - 1 - stop, len - start),
+            Some((start, stop)) => (start, stop + 1),
+            None => return (0, 0),
+        },
+        RangeSpec::Score(range) => zset.score_range(range),
+        RangeSpec::Lex(range) => zset.lex_range(range),
+    };
+    if end <= start {
+        return (0, 0);
+    }
+
+    let Some((offset, count)) = limit else {
+        return (start, end);
+    };
+    let Ok(offset) = usize::try_from(offset) else {
+        return (0, 0);
+    };
+    let available = (end - start).saturating_sub(offset);
+    let take = usize::try_from(count).map_or(available, |count| count.min(available));
+    if rev {
+        let end = end - offset.min(end - start);
+        (end - take, end)
+    } else {
+        let start = start + offset.min(end - start);
+        (start, start + take)
+    }
+}
+
+/// Normalizes inclusive, possibly negative ranks the way `ZRANGE` does.
+/// Returns `None` when the range selects nothing.
+fn clamp_rank_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+/// Replaces `dst` with a sorted set of `entries`, or deletes it when there
+/// are none.
+fn store_zset(
+    map: &mut Dict<DbEntry>,
+    dst: String,
+    entries: Vec<(String, f64)>,
+    limits: ListpackLimits,
+) {
+    if entries.is_empty() {
+        map.remove(&dst);
+        return;
+    }
+
+    let mut zset = ZSet::new();
+    for (member, score) in entries {
+        zset.insert(member, score, limits);
+    }
+    map.insert(
+        dst,
+        DbEntry {
+            data: DbData::ZSet(zset),
+            expires_at: None,
+        },
+    );
+}
+
+/// Removes up to `count` elements from the `end` side of `zset`, lowest
+/// first for `MIN` and highest first for `MAX`.
+fn pop_zset(zset: &mut ZSet, end: ScoreEnd, count: usize) -> ZPopped {
+    let len = zset.len();
+    let count = count.min(len);
+    let popped: ZPopped = match end {
+        ScoreEnd::Min => zset.range(0, count, false),
+        ScoreEnd::Max => zset.range(len - count, len, true),
+    }
+    .map(|(member, score)| (member.clone(), score))
+    .collect();
+
+    for (member, _) in &popped {
+        zset.remove(member);
+    }
+    popped
+}
+
+/// Shared implementation of `ZPOPMIN` and `ZPOPMAX`, replying with a flat
+/// member/score array. Removes the key once the set has been drained.
+fn zpop(map: &mut Dict<DbEntry>, key: &str, count: Option<usize>, end: ScoreEnd) -> RespValue {
+    let zset = match get_zset_mut(map, key) {
+        Ok(Some(zset)) => zset,
+        Ok(None) => return RespValue::Array(vec![]),
+        Err(e) => return e,
+    };
+
+    let popped = pop_zset(zset, end, count.unwrap_or(1));
+    if zset.is_empty() {
+        map.remove(key);
+    }
+    RespValue::Array(
+        popped
+            .into_iter()
+            .flat_map(|(member, score)| {
+                [
+                    RespValue::BulkString(member),
+                    RespValue::BulkString(format_score(score)),
+                ]
+            })
+            .collect(),
+    )
+}
+
+/// Member/score pairs removed by a sorted set pop, in pop order.
+type ZPopped = Vec<(String, f64)>;
+
+/// Pops up to `count` elements from the first non-empty sorted set among
+/// `keys`, returning its key along with them. Fails on the first key that
+/// holds another type.
+fn zpop_first_nonempty(
+    map: &mut Dict<DbEntry>,
+    keys: &[String],
+    end: ScoreEnd,
+    count: usize,
+) -> Result<Option<(String, ZPopped)>, RespValue> {
+    for key in keys {
+        let Some(zset) = get_zset_mut(map, key)? else {
+            continue;
+        };
+
+        let popped = pop_zset(zset, end, count);
+        if zset.is_empty() {
+            map.remove(key);
+        }
+        return Ok(Some((key.clone(), popped)));
+    }
+    Ok(None)
+}
+
+/// One attempt at serving `BZPOPMIN`/`BZPOPMAX`, replying with
+/// `[key, member, score]`.
+fn blocking_zpop(
+    store: &mut Store,
+    db: usize,
+    keys: &[String],
+    end: ScoreEnd,
+) -> Option<RespValue> {
+    match zpop_first_nonempty(&mut store.dbs[db], keys, end, 1) {
+        Ok(Some((key, mut popped))) => {
+            let (member, score) = popped.pop().unwrap();
+            Some(RespValue::Array(vec![
+                RespValue::BulkString(key),
+                RespValue::BulkString(member),
+                RespValue::BulkString(format_score(score)),
+            ]))
+        }
+        Ok(None) => None,
+        Err(e) => Some(e),
+    }
+}
+
+fn zmpop_reply(key: String, popped: ZPopped) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString(key),
+        RespValue::Array(
+            popped
+                .into_iter()
+                .map(|(member, score)| {
+                    RespValue::Array(vec![
+                        RespValue::BulkString(member),
+                        RespValue::BulkString(format_score(score)),
+                    ])
+                })
+                .collect(),
+        ),
+    ])
+}
+
+/// Shared implementation of `ZRANK` and `ZREVRANK`.
+fn zrank(map: &Dict<DbEntry>, key: &str, member: &str, withscore: bool, rev: bool) -> RespValue {
+    let zset = match get_zset(map, key) {
+        Ok(zset) => zset,
+        Err(e) => return e,
+    };
+    let Some((zset, rank)) = zset.and_then(|z| Some((z, z.rank(member)?))) else {
+        return if withscore {
+            RespValue::NullArray
+        } else {
+            RespValue::Null
+        };
+    };
+
+    let rank = if rev { zset.len() - 1 - rank } else { rank };
+    if withscore {
+        let score = zset.score(member).unwrap();
+        RespValue::Array(vec![
+            RespValue::Integer(rank as i64),
+            RespValue::BulkString(format_score(score)),
+        ])
+    } else {
+        RespValue::Integer(rank as i64)
+    }
+}
+
+/// Looks up every key as a set, with `None` for missing keys. Fails if any
+/// key holds another type.
+fn get_sets<'a>(
+    map: &'a Dict<DbEntry>,
+    keys: &[String],
+) -> Result<Vec<Option<&'a Set>>, RespValue> {
+    keys.iter().map(|key| get_set(map, key)).collect()
+}
+
+/// Computes `SINTER`, `SUNION` or `SDIFF` over `sets`, where a missing key
+/// counts as the empty set. An intersection stops after `limit` members
+/// unless the limit is 0.
+fn combine_sets(sets: &[Option<&Set>], op: SetOp, limit: usize) -> Vec<String> {
+    let limit = if limit == 0 { usize::MAX } else { limit };
+
+    match op {
+        SetOp::Inter => {
+            let Some(mut sets) = sets.iter().copied().collect::<Option<Vec<_>>>() else {
+                return Vec::new();
+            };
+            // Walk the smallest set and probe the others, which are checked
+            // smallest first so non-members are rejected early.
+            sets.sort_by_key(|set| set.len());
+            let (smallest, rest) = sets.split_first().unwrap();
+            smallest
+                .iter()
+                .filter(|member| rest.iter().all(|set| set.contains(member)))
+                .take(limit)
+                .collect()
+        }
+        SetOp::Union => {
+            let mut seen = HashSet::new();
+            sets.iter()
+                .flatten()
+                .flat_map(|set| set.iter())
+                .filter(|member| seen.insert(member.clone()))
+                .collect()
+        }
+        SetOp::Diff => {
+            let Some(first) = sets[0] else {
+                return Vec::new();
+            };
+            first
+                .iter()
+                .filter(|member| !sets[1..].iter().flatten().any(|set| set.contains(member)))
+                .collect()
+        }
+    }
+}
+
+/// An input of the sorted set algebra commands, which also accept plain sets
+/// and treat their members as having score 1.
+#[derive(Clone, Copy)]
+enum ScoredSet<'a> {
+    ZSet(&'a ZSet),
+    Set(&'a Set),
+}
+
+impl ScoredSet<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::ZSet(zset) => zset.len(),
+            Self::Set(set) => set.len(),
+        }
+    }
+
+    fn score(&self, member: &str) -> Option<f64> {
+        match self {
+            Self::ZSet(zset) => zset.score(member),
+            Self::Set(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, f64)> + '_> {
+        match self {
+            Self::ZSet(zset) => Box::new(
+                zset.range(0, zset.len(), false)
+                    .map(|(member, score)| (member.clone(), score)),
+            ),
+            Self::Set(set) => Box::new(set.iter().map(|member| (member, 1.0))),
+        }
+    }
+}
+
+/// Looks up every key as a sorted set or set, with `None` for missing keys.
+/// Fails if any key holds another type.
+fn get_scored_sets<'a>(
+    map: &'a Dict<DbEntry>,
+    keys: &[String],
+) -> Result<Vec<Option<ScoredSet<'a>>>, RespValue> {
+    keys.iter()
+        .map(|key| match map.get(key).map(|e| &e.data) {
+            None => Ok(None),
+            Some(DbData::ZSet(zset)) => Ok(Some(ScoredSet::ZSet(zset))),
+            Some(DbData::Set(set)) => Ok(Some(ScoredSet::Set(set))),
+            Some(_) => Err(wrong_type()),
+        })
+        .collect()
+}
+
+/// Multiplies a score by its input's weight. Like in Redis, `0 * inf`
+/// counts as 0 rather than NaN.
+fn weigh(score: f64, weight: f64) -> f64 {
+    let weighted = score * weight;
+    if weighted.is_nan() { 0.0 } else { weighted }
+}
+
+fn aggregate(acc: f64, score: f64, how: Aggregate) -> f64 {
+    match how {
+        // inf + -inf is NaN, which is also stored as 0.
+        Aggregate::Sum => weigh(acc + score, 1.0),
+        Aggregate::Min => acc.min(score),
+        Aggregate::Max => acc.max(score),
+    }
+}
+
+/// Computes `ZUNION`, `ZINTER` or `ZDIFF` over `sets`, where a missing key
+/// counts as the empty set, returning the result in `(score, member)`
+/// order. An intersection stops after `limit` members unless the limit is 0.
+fn combine_zsets(
+    sets: &[Option<ScoredSet>],
+    op: SetOp,
+    opts: &ZCombineOptions,
+    limit: usize,
+) -> Vec<(String, f64)> {
+    let mut entries: Vec<(String, f64)> = match op {
+        SetOp::Inter => {
+            let Some(sets) = sets.iter().copied().collect::<Option<Vec<_>>>() else {
+                return Vec::new();
+            };
+            // Walk the smallest input and probe the others in argument order,
+            // so scores are aggregated the same way regardless of sizes.
+            let smallest = (0..sets.len()).min_by_key(|&i| sets[i].len()).unwrap();
+            let limit = if limit == 0 { usize::MAX } else { limit };
+            sets[smallest]
+                .iter()
+                .filter_map(|(member, _)| {
+                    let mut acc = None;
+                    for (set, &weight) in sets.iter().zip(&opts.weights) {
+                        let score = weigh(set.score(&member)?, weight);
+                        acc = Some(acc.map_or(score, |acc| aggregate(acc, score, opts.aggregate)));
+                    }
+                    Some((member, acc.unwrap()))
+                })
+                .take(limit)
+                .collect()
+        }
+        SetOp::Union => {
+            let mut scores: HashMap<String, f64> = HashMap::new();
+            for (set, &weight) in sets.iter().zip(&opts.weights) {
+                let Some(set) = set else {
+                    continue;
+                };
+                for (member, score) in set.iter() {
+                    let score = weigh(score, weight);
+                    scores
+                        .entry(member)
+                        .and_modify(|acc| *acc = aggregate(*acc, score, opts.aggregate))
+                        .or_insert(score);
+                }
+            }
+            scores.into_iter().collect()
+        }
+        SetOp::Diff => {
+            let Some(first) = sets[0] else {
+                return Vec::new();
+            };
+            first
+                .iter()
+                .filter(|(member, _)| {
+                    !sets[1..]
+                        .iter()
+                        .flatten()
+                        .any(|set| set.score(member).is_some())
+                })
+                .collect()
+        }
+    };
+
+    entries.sort_by(|(m1, s1), (m2, s2)| s1.partial_cmp(s2).unwrap().then_with(|| m1.cmp(m2)));
+    entries
+}
+
+/// Applies an `HGETEX`/`HSETEX` TTL option to an existing field. A time that
+/// has already passed deletes the field right away.
+fn apply_field_ttl(hash: &mut Hash, field: &str, ttl: FieldTtl) {
+    match ttl {
+        FieldTtl::Keep => {}
+        FieldTtl::Persist => {
+            hash.persist(field);
+        }
+        FieldTtl::ExpireAt(at) if at <= unix_time_ms() => {
+            hash.remove(field);
+        }
+        FieldTtl::ExpireAt(at) => hash.set_expire(field, at),
+    }
+}
+
+/// Builds the per-field status array returned by `HEXPIRE` and friends.
+fn field_statuses(codes: impl IntoIterator<Item = i64>) -> RespValue {
+    RespValue::Array(codes.into_iter().map(RespValue::Integer).collect())
+}
+
+/// Resolves a possibly negative list index against `len`.
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(index).ok().filter(|&i| i < len)
+}
+
+/// Shared implementation of `LPOP` and `RPOP`. Removes the key once the list
+/// has been drained.
+fn pop_list(map: &mut Dict<DbEntry>, key: &str, count: Option<usize>, end: End) -> RespValue {
+    let list = match get_list_mut(map, key) {
+        Ok(Some(list)) => list,
+        // With a count the reply is an array, so a missing key is a null
+        // array rather than a null bulk string.
+        Ok(None) if count.is_some() => return RespValue::NullArray,
+        Ok(None) => return RespValue::Null,
+        Err(e) => return e,
+    };
+
+    let reply = match count {
+        None => RespValue::BulkString(list.pop(end).unwrap()),
+        Some(n) => {
+            let take_n = std::cmp::min(n, list.len());
+            let removed_elements: Vec<RespValue> = (0..take_n)
+                .filter_map(|_| list.pop(end))
+                .map(RespValue::BulkString)
+                .collect();
+            RespValue::Array(removed_elements)
+        }
+    };
+
+    if list.is_empty() {
+        map.remove(key);
+    }
+    reply
+}
+
+/// Shared implementation of `LPUSHX` and `RPUSHX`: only pushes when the key
+/// already holds a list.
+fn push_existing(map: &mut Dict<DbEntry>, key: &str, values: Vec<String>, end: End) -> RespValue {
+    match get_list_mut(map, key) {
+        Ok(Some(list)) => {
+            for val in values {
+                list.push(end, val);
+            }
+            RespValue::Integer(list.len() as i64)
+        }
+        Ok(None) => RespValue::Integer(0),
+        Err(e) => e,
+    }
+}
+
+/// Pops up to `count` elements from the first non-empty list among `keys`,
+/// in argument order. A key holding another type aborts with WRONGTYPE.
+fn pop_first_nonempty(
+    map: &mut Dict<DbEntry>,
+    keys: &[String],
+    end: End,
+    count: usize,
+) -> Result<Option<(String, Vec<String>)>, RespValue> {
+    for key in keys {
+        let Some(list) = get_list_mut(map, key)? else {
+            continue;
+        };
+
+        let vals: Vec<String> = (0..count).map_while(|_| list.pop(end)).collect();
+        if list.is_empty() {
+            map.remove(key);
+        }
+        return Ok(Some((key.clone(), vals)));
+    }
+    Ok(None)
+}
+
+/// One attempt at serving `BLPOP`/`BRPOP`, replying with `[key, value]`.
+fn blocking_pop(store: &mut Store, db: usize, keys: &[String], end: End) -> Option<RespValue> {
+    match pop_first_nonempty(&mut store.dbs[db], keys, end, 1) {
+        Ok(Some((key, mut vals))) => Some(RespValue::Array(vec![
+            RespValue::BulkString(key),
+            RespValue::BulkString(vals.pop().unwrap()),
+        ])),
+        Ok(None) => None,
+        Err(e) => Some(e),
+    }
+}
+
+fn mpop_reply(key: String, vals: Vec<String>) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString(key),
+        RespValue::Array(vals.into_iter().map(RespValue::BulkString).collect()),
+    ])
+}
+
+/// Shared implementation of `LMOVE` and friends. Returns `Ok(None)` when
+/// `src` does not exist. Both keys are type-checked before anything is
+/// popped, and `src == dst` rotates the list in place.
+fn move_list_element(
+    map: &mut Dict<DbEntry>,
+    src: &str,
+    dst: String,
+    from: End,
+    to: End,
+) -> Result<Option<String>, RespValue> {
+    get_list(map, &dst)?;
+    let Some(list) = get_list_mut(map, src)? else {
+        return Ok(None);
+    };
+
+    let val = list.pop(from).unwrap();
+    if list.is_empty() {
+        map.remove(src);
+    }
+
+    let entry = map.get_or_insert_with(dst, || DbEntry {
+        data: DbData::List(List::new()),
+        expires_at: None,
+    });
+    if let DbData::List(ref mut list) = entry.data {
+        list.push(to, val.clone());
+    }
+    Ok(Some(val))
+}
+
+/// Locks the store for a command, first holding it back while `CLIENT PAUSE`
+/// is in effect for it. The command runs under the returned guard, so no
+/// pause can start between the check and the command. `CLIENT` itself is
+/// never paused, so `CLIENT UNPAUSE` can always get through.
+fn wait_while_paused<'a>(db: &'a Db, cmd: &Command) -> MutexGuard<'a, Store> {
+    let mut store = db.lock().unwrap();
+
+    while let Some(pause) = store.pause {
+        let now = Instant::now();
+        let applies = match pause.mode {
+            PauseMode::All => !cmd.is_client_admin(),
+            PauseMode::Write => cmd.is_write(),
+        };
+        if now >= pause.until || !applies {
+            break;
+        }
+
+        let unpaused = store.unpaused.clone();
+        store = unpaused.wait_timeout(store, pause.until - now).unwrap().0;
+    }
+    store
+}
+
+/// Serves a blocking command: runs `attempt` once under the command's
+/// `store` guard and, if it cannot be served yet, parks the client on `keys`
+/// until a write serves it, the timeout expires (0 waits forever) or the
+/// client disconnects. Expiry replies with a null array.
+fn block_until(
+    mut store: MutexGuard<'_, Store>,
+    db: &Db,
+    client: &Client,
+    keys: Vec<String>,
+    timeout: f64,
+    mut attempt: impl FnMut(&mut Store) -> Option<RespValue> + Send + 'static,
+) -> RespValue {
+    let deadline = (timeout > 0.0).then(|| Instant::now() + Duration::from_secs_f64(timeout));
+    let (tx, rx) = mpsc::channel();
+
+    if let Some(reply) = attempt(&mut store) {
+        // The attempt itself may have pushed somewhere (BLMOVE).
+        store.serve_blocked();
+        return reply;
+    }
+    let conn = client.conn.as_ref().and_then(|conn| conn.try_clone().ok());
+    store
+        .blocked
+        .block(client.id, client.db, keys, Box::new(attempt), tx, conn);
+    drop(store);
+
+    loop {
+        let wait = match deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(DISCONNECT_POLL),
+            None => DISCONNECT_POLL,
+        };
+
+        match rx.recv_timeout(wait) {
+            Ok(reply) => return reply,
+            Err(RecvTimeoutError::Timeout) => {
+                // The socket is only probed under the lock, so this never
+                // races with a write serving the client.
+                let expired = deadline.is_some_and(|d| Instant::now() >= d);
+                if !expired && !db.lock().unwrap().blocked.is_disconnected(client.id) {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {}
+        }
+
+        let mut store = db.lock().unwrap();
+        // We may have been served between the timeout and taking the lock.
+        if let Ok(reply) = rx.try_recv() {
+            return reply;
+        }
+        store.blocked.unblock(client.id);
+        return RespValue::NullArray;
+    }
+}
+
+pub fn extract_string(elems: &[RespValue], index: usize) -> Option<String> {
+    match elems.get(index) {
+        Some(RespValue::BulkString(s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ConsumerGroup;
+
+    fn id(ms: u64) -> StreamId {
+        StreamId { ms, seq: 0 }
+    }
+
+    /// A stream of `n` entries, each delivered to `alice` of group `g` at
+    /// unix time `delivered_at`.
+    fn pending_stream(n: u64, delivered_at: u64) -> Stream {
+        let mut stream = Stream::new();
+        let mut group = ConsumerGroup::new(StreamId::MIN, None);
+        for ms in 1..=n {
+            stream.append(id(ms), vec![("f".to_string(), ms.to_string())]);
+            group.deliver(id(ms), "alice", delivered_at);
+        }
+        stream.groups.insert("g".to_string(), group);
+        stream
+    }
+
+    /// The ID a reply element stands for: a bare ID or an `[id, fields]`
+    /// entry.
+    fn id_text(value: &RespValue) -> String {
+        match value {
+            RespValue::BulkString(id) => id.clone(),
+            RespValue::Array(entry) => id_text(&entry[0]),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    /// Runs `XAUTOCLAIM` for consumer `bob`, returning the next cursor, the
+    /// claimed IDs and the deleted IDs.
+    fn autoclaim(
+        stream: &mut Stream,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+    ) -> (String, Vec<String>, Vec<String>) {
+        let reply = xautoclaim(stream, "g", "bob", min_idle, start, count, justid);
+        let RespValue::Array(parts) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        let ids = |value: &RespValue| match value {
+            RespValue::Array(items) => items.iter().map(id_text).collect(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        (id_text(&parts[0]), ids(&parts[1]), ids(&parts[2]))
+    }
+
+    fn texts(range: std::ops::RangeInclusive<u64>) -> Vec<String> {
+        range.map(|ms| id(ms).to_string()).collect()
+    }
+
+    #[test]
+    fn xautoclaim_cursor_resumes_and_ends_at_zero() {
+        let mut stream = pending_stream(30, 0);
+        let (cursor, claimed, deleted) = autoclaim(&mut stream, 0, StreamId::MIN, 10, true);
+        assert_eq!((cursor.as_str(), claimed), ("11-0", texts(1..=10)));
+        assert!(deleted.is_empty());
+
+        let (cursor, claimed, _) = autoclaim(&mut stream, 0, id(11), 100, true);
+        assert_eq!((cursor.as_str(), claimed), ("0-0", texts(11..=30)));
+        let group = &stream.groups["g"];
+        assert_eq!(group.consumers["bob"].pending.len(), 30);
+        assert!(group.consumers["alice"].pending.is_empty());
+    }
+
+    #[test]
+    fn xautoclaim_scans_at_most_ten_entries_per_count() {
+        let now = unix_time_ms();
+        let mut stream = pending_stream(30, now);
+        let group = stream.groups.get_mut("g").unwrap();
+        group.pending.get_mut(&id(30)).unwrap().delivery_time = 0;
+
+        let (cursor, claimed, _) = autoclaim(&mut stream, 60_000, StreamId::MIN, 1, false);
+        assert_eq!(cursor, "11-0");
+        assert!(claimed.is_empty());
+        let (cursor, claimed, _) = autoclaim(&mut stream, 60_000, id(11), 1, false);
+        assert_eq!(cursor, "21-0");
+        assert!(claimed.is_empty());
+        let (cursor, claimed, _) = autoclaim(&mut stream, 60_000, id(21), 1, false);
+        assert_eq!((cursor.as_str(), claimed), ("0-0", texts(30..=30)));
+    }
+
+    #[test]
+    fn xautoclaim_drops_deleted_entries_from_the_pel() {
+        let mut stream = pending_stream(5, 0);
+        stream.delete(id(2));
+        stream.delete(id(4));
+
+        let (cursor, claimed, deleted) = autoclaim(&mut stream, 0, StreamId::MIN, 10, false);
+        assert_eq!(cursor, "0-0");
+        assert_eq!(claimed, ["1-0", "3-0", "5-0"]);
+        assert_eq!(deleted, ["2-0", "4-0"]);
+        let group = &stream.groups["g"];
+        assert_eq!(
+            group.pending.keys().copied().collect::<Vec<_>>(),
+            [id(1), id(3), id(5)]
+        );
+        assert!(group.consumers["alice"].pending.is_empty());
+    }
+
+    #[test]
+    fn xautoclaim_justid_leaves_the_delivery_count_alone() {
+        let mut stream = pending_stream(2, 0);
+        autoclaim(&mut stream, 0, id(1), 1, true);
+        autoclaim(&mut stream, 0, id(2), 1, false);
+        let pending = &stream.groups["g"].pending;
+        assert_eq!(pending[&id(1)].delivery_count, 1);
+        assert_eq!(pending[&id(2)].delivery_count, 2);
+        assert_eq!(pending[&id(2)].consumer, "bob");
+    }
+
+    /// Stores a hash at `key` whose only field expires at `at`, tracked for
+    /// the active expire cycle.
+    fn volatile_hash(store: &mut Store, db: usize, key: &str, at: u64) {
+        let mut hash = Hash::new();
+        hash.insert("f".to_string(), "v".to_string(), store.config.hash_listpack);
+        hash.set_expire("f", at);
+        store.dbs[db].insert(
+            key.to_string(),
+            DbEntry {
+                data: DbData::Hash(hash),
+                expires_at: None,
+            },
+        );
+        store.track_field_expiry(db, key);
+    }
+
+    #[test]
+    fn active_expire_reaps_a_bounded_number_of_hashes_per_database() {
+        let mut store = Store::new(2);
+        for i in 0..25 {
+            volatile_hash(&mut store, 0, &format!("h{}", i), 1);
+        }
+        for i in 0..3 {
+            volatile_hash(&mut store, 1, &format!("h{}", i), 1);
+        }
+        volatile_hash(&mut store, 0, "later", unix_time_ms() + 60_000);
+
+        store.active_expire_cycle();
+        assert_eq!(store.dbs[0].len(), 25 - ACTIVE_EXPIRE_KEYS + 1);
+        assert_eq!(store.dbs[1].len(), 0);
+
+        store.active_expire_cycle();
+        assert_eq!(store.dbs[0].len(), 1);
+        assert!(store.dbs[0].get("later").is_some());
+        assert_eq!(store.volatile_hashes[0].len(), 1);
+        assert!(store.volatile_hashes[1].is_empty());
     }
 }
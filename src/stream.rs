@@ -0,0 +1,1093 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Maximum number of entries stored in a single node, matching the default
+/// `stream-node-max-entries` of Redis.
+const NODE_CAPACITY: usize = 100;
+
+/// Error text for an ID argument that doesn't parse.
+pub const INVALID_ID: &str = "Invalid stream ID specified as stream command argument";
+
+/// A stream entry ID: milliseconds and a sequence number within them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: Self = Self { ms: 0, seq: 0 };
+    pub const MAX: Self = Self {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// The smallest ID greater than this one, if any.
+    pub fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self { ms: self.ms, seq }),
+            None => Some(Self {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    /// The largest ID smaller than this one, if any.
+    pub fn prev(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(Self { ms: self.ms, seq }),
+            None => Some(Self {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+
+    /// Parses `<ms>-<seq>`, or a bare `<ms>` with `missing_seq` as its
+    /// sequence.
+    pub fn parse(s: &str, missing_seq: u64) -> Option<Self> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+            None => (s.parse().ok()?, missing_seq),
+        };
+        Some(Self { ms, seq })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of `XADD`.
+#[derive(Debug, Clone, Copy)]
+pub enum XAddId {
+    /// `*`: the current time, or just past the last ID if the clock is
+    /// behind it.
+    Auto,
+    /// `<ms>-*`: the next free sequence number within `ms`.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// What `XTRIM` and the trimming options of `XADD` keep.
+#[derive(Debug, Clone, Copy)]
+pub enum TrimStrategy {
+    /// At most this many entries.
+    MaxLen(usize),
+    /// Only entries with IDs at or above this one.
+    MinId(StreamId),
+}
+
+/// A parsed `MAXLEN|MINID [=|~] threshold [LIMIT count]` clause.
+#[derive(Debug, Clone, Copy)]
+pub struct Trim {
+    pub strategy: TrimStrategy,
+    /// `~`: only evict whole nodes, possibly keeping a few extra entries.
+    pub approx: bool,
+    /// Most entries an approximate trim may evict, 0 for no limit.
+    pub limit: usize,
+}
+
+impl Trim {
+    /// The `LIMIT` of an approximate trim that doesn't give one.
+    pub const DEFAULT_LIMIT: usize = 100 * NODE_CAPACITY;
+}
+
+/// Field/value pairs of an entry, in the order they were added.
+pub type Fields = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct Entry {
+    id: StreamId,
+    fields: Fields,
+    // Set by XDEL and exact trimming. The entry keeps its place so the node
+    // stays sorted and indexed by the same first ID.
+    deleted: bool,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    entries: Vec<Entry>,
+    live: usize,
+}
+
+/// Append-only log of entries ordered by ID, laid out like Redis streams.
+///
+/// Entries are packed into bounded nodes, and the nodes are indexed by the
+/// ID of their first entry in a B-tree (Redis uses a radix tree keyed the
+/// same way), so a range read seeks straight to the node holding its start.
+/// Appends only ever touch the last node. Deleted entries are tombstoned in
+/// place, and a node is dropped once it holds no live entries.
+#[derive(Debug, Default)]
+pub struct Stream {
+    nodes: BTreeMap<StreamId, Node>,
+    len: usize,
+    last_id: StreamId,
+    // Total entries ever appended, and the largest ID removed by XDEL. Both
+    // feed the consumer group lag estimates.
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+/// A delivered but not yet acknowledged entry in a group's PEL.
+#[derive(Debug)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Consumer {
+    /// Unix time in milliseconds of the last interaction.
+    pub seen_time: u64,
+    /// Unix time in milliseconds of the last successful read, if any.
+    pub active_time: Option<u64>,
+    /// IDs of the entries this consumer owns in the group's PEL.
+    pub pending: BTreeSet<StreamId>,
+}
+
+/// A consumer group: the last ID delivered to it, and the pending entries
+/// list (PEL) of entries delivered to its consumers but not acknowledged.
+#[derive(Debug)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    /// Logical number of entries read so far, `None` when it can't be told
+    /// (such as after `XGROUP SETID` to an arbitrary ID).
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        Self {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the consumer called `name`, creating it if needed, and marks
+    /// it as seen at `now`.
+    pub fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_default();
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Removes a consumer along with its pending entries, returning how
+    /// many it had.
+    pub fn delete_consumer(&mut self, name: &str) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Records that `id` was delivered to `consumer` at `now`. An entry that
+    /// was already pending changes owner and starts counting afresh.
+    pub fn deliver(&mut self, id: StreamId, consumer: &str, now: u64) {
+        let previous = self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time: now,
+                delivery_count: 1,
+            },
+        );
+        if let Some(previous) = previous
+            && let Some(owner) = self.consumers.get_mut(&previous.consumer)
+        {
+            owner.pending.remove(&id);
+        }
+        self.consumer(consumer, now).pending.insert(id);
+    }
+
+    /// Hands the entry `id` over to `consumer` with its delivery time set to
+    /// `delivery_time`, as `XCLAIM` and `XAUTOCLAIM` do, adding it to the
+    /// PEL if it wasn't pending. The delivery count is set to `retry_count`
+    /// if given, and otherwise bumped unless `justid` is set.
+    pub fn claim(
+        &mut self,
+        id: StreamId,
+        consumer: &str,
+        delivery_time: u64,
+        retry_count: Option<u64>,
+        justid: bool,
+        now: u64,
+    ) {
+        let mut delivery_count = 0;
+        if let Some(previous) = self.pending.remove(&id) {
+            delivery_count = previous.delivery_count;
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        if let Some(count) = retry_count {
+            delivery_count = count;
+        } else if !justid {
+            delivery_count += 1;
+        }
+
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count,
+            },
+        );
+        let owner = self.consumer(consumer, now);
+        owner.pending.insert(id);
+        owner.active_time = Some(now);
+    }
+
+    /// Removes `id` from the PEL, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The largest ID ever added, which new entries must exceed.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Resolves the ID for a new entry given the current unix time in
+    /// milliseconds, failing with the Redis error text if it would not be
+    /// greater than the last ID added.
+    pub fn next_id(&self, id: XAddId, now_ms: u64) -> Result<StreamId, &'static str> {
+        const TOO_SMALL: &str =
+            "The ID specified in XADD is equal or smaller than the target stream top item";
+
+        let last = self.last_id;
+        let id = match id {
+            XAddId::Auto if now_ms > last.ms => StreamId { ms: now_ms, seq: 0 },
+            XAddId::Auto => last
+                .next()
+                .ok_or("The stream has exhausted the last possible ID, unable to add more items")?,
+            XAddId::AutoSeq(ms) if ms == last.ms => last
+                .seq
+                .checked_add(1)
+                .map(|seq| StreamId { ms, seq })
+                .ok_or(TOO_SMALL)?,
+            // 0-0 is never a valid ID, so an empty stream starts at 0-1.
+            XAddId::AutoSeq(ms) => StreamId {
+                ms,
+                seq: u64::from(ms == 0),
+            },
+            XAddId::Explicit(id) => id,
+        };
+
+        if id == StreamId::MIN {
+            Err("The ID specified in XADD must be greater than 0-0")
+        } else if id <= last {
+            Err(TOO_SMALL)
+        } else {
+            Ok(id)
+        }
+    }
+
+    /// Appends an entry. `id` must be greater than the last ID added.
+    pub fn append(&mut self, id: StreamId, fields: Fields) {
+        let node = match self.nodes.last_entry() {
+            Some(node) if node.get().entries.len() < NODE_CAPACITY => node.into_mut(),
+            _ => self.nodes.entry(id).or_default(),
+        };
+        node.entries.push(Entry {
+            id,
+            fields,
+            deleted: false,
+        });
+        node.live += 1;
+        self.len += 1;
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// The fields of the entry with this ID, unless it doesn't exist or was
+    /// deleted.
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        let (_, node) = self.nodes.range(..=id).next_back()?;
+        let pos = node
+            .entries
+            .binary_search_by_key(&id, |entry| entry.id)
+            .ok()?;
+        let entry = &node.entries[pos];
+        (!entry.deleted).then_some(&entry.fields)
+    }
+
+    /// Number of internal nodes.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn first_entry(&self) -> Option<(StreamId, &Fields)> {
+        self.range(StreamId::MIN, StreamId::MAX, false).next()
+    }
+
+    pub fn last_entry(&self) -> Option<(StreamId, &Fields)> {
+        self.range(StreamId::MIN, StreamId::MAX, true).next()
+    }
+
+    fn first_id(&self) -> Option<StreamId> {
+        self.first_entry().map(|(id, _)| id)
+    }
+
+    /// Overwrites the stream metadata, as `XSETID` does after a migration.
+    /// Fails with the Redis error text if it contradicts the entries.
+    pub fn set_id(
+        &mut self,
+        id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
+    ) -> Result<(), &'static str> {
+        if self.last_entry().is_some_and(|(last, _)| id < last) {
+            return Err("The ID specified in XSETID is smaller than the target stream top item");
+        }
+        if entries_added.is_some_and(|added| added < self.len as u64) {
+            return Err(
+                "The entries_added specified in XSETID is smaller than the target stream length",
+            );
+        }
+        if max_deleted_id.is_some_and(|deleted| id < deleted) {
+            return Err(
+                "The ID specified in XSETID is smaller than the provided max_deleted_entry_id",
+            );
+        }
+
+        self.last_id = id;
+        if let Some(added) = entries_added {
+            self.entries_added = added;
+        }
+        if let Some(deleted) = max_deleted_id {
+            self.max_deleted_id = deleted;
+        }
+        Ok(())
+    }
+
+    /// How many entries `group` has yet to read, when that can be told.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_id) => read,
+            _ => self.entries_up_to(group.last_id)?,
+        };
+        Some(self.entries_added.saturating_sub(read))
+    }
+
+    /// Whether an entry at or after `start` may have been deleted, which
+    /// makes counting entries by ID unreliable.
+    fn has_tombstones_from(&self, start: StreamId) -> bool {
+        self.len > 0 && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /// Works out how many entries were ever added up to and including `id`,
+    /// if the stream's history allows it. This is what `entries-read` of a
+    /// group whose last ID is `id` should be.
+    pub fn entries_up_to(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.len == 0 && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&self.last_id) {
+            Ordering::Equal => return Some(self.entries_added),
+            Ordering::Greater => return None,
+            Ordering::Less => {}
+        }
+
+        // Without deletions past the first entry, everything before it was
+        // trimmed in order.
+        let first = self.first_id()?;
+        let unfragmented = self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first;
+        let trimmed = self.entries_added - self.len as u64;
+        match id.cmp(&first) {
+            Ordering::Less if unfragmented => Some(trimmed),
+            Ordering::Equal if unfragmented => Some(trimmed + 1),
+            _ => None,
+        }
+    }
+
+    /// Delivers up to `count` (0 for all) entries past the group's last ID
+    /// to `consumer`, the `>` form of `XREADGROUP`. Unless `noack` is set
+    /// they become pending until acknowledged. Returns `None` if the group
+    /// doesn't exist.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: usize,
+        noack: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let start = self.groups.get(group)?.last_id.next();
+        let limit = if count == 0 { usize::MAX } else { count };
+        let entries: Vec<(StreamId, Fields)> = match start {
+            Some(start) => self
+                .range(start, StreamId::MAX, false)
+                .take(limit)
+                .map(|(id, fields)| (id, fields.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let mut entries_read = self.groups[group].entries_read;
+        for (id, _) in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_from(*id) => Some(read + 1),
+                _ => self.entries_up_to(*id),
+            };
+        }
+
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        if let Some((last, _)) = entries.last() {
+            group.last_id = *last;
+            group.entries_read = entries_read;
+            group.consumer(consumer, now).active_time = Some(now);
+        }
+        if !noack {
+            for (id, _) in &entries {
+                group.deliver(*id, consumer, now);
+            }
+        }
+        Some(entries)
+    }
+
+    /// Iterates the entries with IDs in `start..=end`, in descending order
+    /// if `rev` is set.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (StreamId, &Fields)> + '_> {
+        if rev {
+            Box::new(
+                self.nodes
+                    .range(..=end)
+                    .rev()
+                    .flat_map(|(_, node)| node.entries.iter().rev())
+                    .filter(|entry| !entry.deleted)
+                    .map(|entry| (entry.id, &entry.fields))
+                    .skip_while(move |(id, _)| *id > end)
+                    .take_while(move |(id, _)| *id >= start),
+            )
+        } else {
+            // The node holding `start` is the last one beginning at or
+            // before it.
+            let first = self
+                .nodes
+                .range(..=start)
+                .next_back()
+                .map_or(start, |(first, _)| *first);
+            Box::new(
+                self.nodes
+                    .range(first..)
+                    .flat_map(|(_, node)| node.entries.iter())
+                    .filter(|entry| !entry.deleted)
+                    .map(|entry| (entry.id, &entry.fields))
+                    .skip_while(move |(id, _)| *id < start)
+                    .take_while(move |(id, _)| *id <= end),
+            )
+        }
+    }
+
+    /// Tombstones the entry with this ID, returning whether it existed.
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        let Some((&first, node)) = self.nodes.range_mut(..=id).next_back() else {
+            return false;
+        };
+        let Ok(pos) = node.entries.binary_search_by_key(&id, |entry| entry.id) else {
+            return false;
+        };
+        let entry = &mut node.entries[pos];
+        if entry.deleted {
+            return false;
+        }
+
+        entry.deleted = true;
+        entry.fields = Vec::new();
+        node.live -= 1;
+        if node.live == 0 {
+            self.nodes.remove(&first);
+        }
+        self.len -= 1;
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Evicts the oldest entries as `trim` asks, returning how many were
+    /// removed. Whole nodes are dropped while they can be; an exact trim
+    /// then tombstones the remaining excess in the first node.
+    pub fn trim(&mut self, trim: &Trim) -> usize {
+        let limit = if trim.limit == 0 {
+            usize::MAX
+        } else {
+            trim.limit
+        };
+        let mut removed = 0;
+
+        while let Some(mut node) = self.nodes.first_entry() {
+            let live = node.get().live;
+            let whole = match trim.strategy {
+                TrimStrategy::MaxLen(max) => self.len - live >= max,
+                // Tombstones don't count: the node goes if its newest live
+                // entry is below the minimum.
+                TrimStrategy::MinId(min) => node
+                    .get()
+                    .entries
+                    .iter()
+                    .rfind(|entry| !entry.deleted)
+                    .is_none_or(|entry| entry.id < min),
+            };
+            if whole {
+                if trim.approx && removed + live > limit {
+                    break;
+                }
+                node.remove();
+                self.len -= live;
+                removed += live;
+                continue;
+            }
+            if trim.approx {
+                break;
+            }
+
+            let first = node.get_mut();
+            for entry in first.entries.iter_mut().filter(|entry| !entry.deleted) {
+                let keep = match trim.strategy {
+                    TrimStrategy::MaxLen(max) => self.len <= max,
+                    TrimStrategy::MinId(min) => entry.id >= min,
+                };
+                if keep {
+                    break;
+                }
+                entry.deleted = true;
+                entry.fields = Vec::new();
+                first.live -= 1;
+                self.len -= 1;
+                removed += 1;
+            }
+            if first.live == 0 {
+                node.remove();
+            }
+            break;
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    fn id(ms: u64) -> StreamId {
+        StreamId { ms, seq: 0 }
+    }
+
+    /// A stream holding entries `1..=n`, one per millisecond.
+    fn stream_of(n: u64) -> Stream {
+        let mut stream = Stream::new();
+        for ms in 1..=n {
+            stream.append(id(ms), vec![("f".to_string(), ms.to_string())]);
+        }
+        stream
+    }
+
+    fn exact(strategy: TrimStrategy) -> Trim {
+        Trim {
+            strategy,
+            approx: false,
+            limit: 0,
+        }
+    }
+
+    /// Checks that every node is keyed by its first ID, within capacity, in
+    /// order and holding live entries that match its count. Returns the IDs
+    /// of the live entries.
+    fn live_ids(stream: &Stream) -> Vec<StreamId> {
+        let mut live = Vec::new();
+        let mut all = Vec::new();
+        for (first, node) in &stream.nodes {
+            assert_eq!(node.entries.first().map(|entry| entry.id), Some(*first));
+            assert!(node.entries.len() <= NODE_CAPACITY);
+            all.extend(node.entries.iter().map(|entry| entry.id));
+
+            let before = live.len();
+            live.extend(
+                node.entries
+                    .iter()
+                    .filter(|entry| !entry.deleted)
+                    .map(|entry| entry.id),
+            );
+            assert_eq!(node.live, live.len() - before);
+            assert!(node.live > 0, "node {} has no live entries", first);
+        }
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(stream.len(), live.len());
+        live
+    }
+
+    /// `stream_of(n)` with a group `g` that has read nothing yet.
+    fn stream_with_group(n: u64) -> Stream {
+        let mut stream = stream_of(n);
+        stream
+            .groups
+            .insert("g".to_string(), ConsumerGroup::new(StreamId::MIN, Some(0)));
+        stream
+    }
+
+    fn read_ids(stream: &mut Stream, consumer: &str, count: usize, noack: bool) -> Vec<StreamId> {
+        let entries = stream
+            .read_group("g", consumer, count, noack, 1000)
+            .unwrap();
+        entries.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn ids(stream: &Stream, start: StreamId, end: StreamId, rev: bool) -> Vec<StreamId> {
+        stream.range(start, end, rev).map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn ids_parse_and_step() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(StreamId { ms: 5, seq: 3 }));
+        assert_eq!(StreamId::parse("5", 7), Some(StreamId { ms: 5, seq: 7 }));
+        assert_eq!(StreamId::parse("5-x", 0), None);
+        assert_eq!(StreamId::parse("-3", 0), None);
+        assert_eq!(StreamId { ms: 5, seq: 3 }.to_string(), "5-3");
+
+        let last_of_4 = StreamId {
+            ms: 4,
+            seq: u64::MAX,
+        };
+        assert_eq!(last_of_4.next(), Some(id(5)));
+        assert_eq!(id(5).prev(), Some(last_of_4));
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::MAX.next(), None);
+    }
+
+    #[test]
+    fn appends_fill_bounded_nodes() {
+        let stream = stream_of(250);
+        assert_eq!(stream.nodes.len(), 3);
+        assert_eq!(live_ids(&stream), (1..=250).map(id).collect::<Vec<_>>());
+        assert_eq!(stream.last_id, id(250));
+    }
+
+    #[test]
+    fn next_id_must_exceed_the_last_id() {
+        let stream = stream_of(5);
+        assert!(stream.next_id(XAddId::Explicit(id(5)), 0).is_err());
+        assert_eq!(stream.next_id(XAddId::Explicit(id(6)), 0), Ok(id(6)));
+        assert_eq!(
+            stream.next_id(XAddId::AutoSeq(5), 0),
+            Ok(StreamId { ms: 5, seq: 1 })
+        );
+        assert!(stream.next_id(XAddId::AutoSeq(4), 0).is_err());
+        // A clock behind the last ID bumps its sequence instead.
+        assert_eq!(
+            stream.next_id(XAddId::Auto, 3),
+            Ok(StreamId { ms: 5, seq: 1 })
+        );
+        assert_eq!(stream.next_id(XAddId::Auto, 9), Ok(id(9)));
+
+        let empty = Stream::new();
+        assert!(empty.next_id(XAddId::Explicit(StreamId::MIN), 0).is_err());
+        assert_eq!(
+            empty.next_id(XAddId::AutoSeq(0), 0),
+            Ok(StreamId { ms: 0, seq: 1 })
+        );
+    }
+
+    #[test]
+    fn ranges_are_inclusive_in_both_directions() {
+        let stream = stream_of(500);
+        let all = live_ids(&stream);
+        assert_eq!(ids(&stream, StreamId::MIN, StreamId::MAX, false), all);
+
+        for (start, end) in [
+            (1, 500),
+            (100, 101),
+            (99, 201),
+            (250, 250),
+            (0, 0),
+            (450, 999),
+        ] {
+            let (start, end) = (id(start), id(end));
+            let forward: Vec<_> = all
+                .iter()
+                .copied()
+                .filter(|id| (start..=end).contains(id))
+                .collect();
+            assert_eq!(ids(&stream, start, end, false), forward);
+            let backward: Vec<_> = forward.into_iter().rev().collect();
+            assert_eq!(ids(&stream, start, end, true), backward);
+        }
+        // Bounds between two IDs.
+        let mid = StreamId { ms: 100, seq: 1 };
+        assert_eq!(ids(&stream, mid, id(102), false), [id(101), id(102)]);
+        assert_eq!(ids(&stream, id(98), mid, true), [id(100), id(99), id(98)]);
+        assert!(ids(&stream, id(300), id(200), false).is_empty());
+    }
+
+    #[test]
+    fn deletes_tombstone_and_drop_empty_nodes() {
+        let mut stream = stream_of(250);
+        assert!(stream.delete(id(7)));
+        assert!(!stream.delete(id(7)));
+        assert!(!stream.delete(id(999)));
+        assert_eq!(ids(&stream, id(6), id(8), false), [id(6), id(8)]);
+
+        for ms in 101..=200 {
+            assert!(stream.delete(id(ms)));
+        }
+        assert_eq!(stream.nodes.len(), 2);
+        assert_eq!(live_ids(&stream).len(), 149);
+        assert_eq!(stream.last_id(), id(250));
+    }
+
+    #[test]
+    fn ranges_skip_tombstones() {
+        let mut stream = stream_of(500);
+        let mut expected: Vec<StreamId> = (1..=500).map(id).collect();
+        for _ in 0..300 {
+            let victim = id(random::below(500) as u64 + 1);
+            stream.delete(victim);
+            expected.retain(|&id| id != victim);
+        }
+        assert_eq!(live_ids(&stream), expected);
+
+        for _ in 0..100 {
+            let a = id(random::below(520) as u64);
+            let b = id(random::below(520) as u64);
+            let (start, end) = (a.min(b), a.max(b));
+            let forward: Vec<_> = expected
+                .iter()
+                .copied()
+                .filter(|id| (start..=end).contains(id))
+                .collect();
+            assert_eq!(ids(&stream, start, end, false), forward);
+            let backward: Vec<_> = forward.into_iter().rev().collect();
+            assert_eq!(ids(&stream, start, end, true), backward);
+        }
+    }
+
+    #[test]
+    fn maxlen_trims_exactly_or_by_whole_nodes() {
+        let mut stream = stream_of(250);
+        assert_eq!(stream.trim(&exact(TrimStrategy::MaxLen(120))), 130);
+        assert_eq!(live_ids(&stream), (131..=250).map(id).collect::<Vec<_>>());
+
+        let mut stream = stream_of(250);
+        let approx = Trim {
+            strategy: TrimStrategy::MaxLen(120),
+            approx: true,
+            limit: Trim::DEFAULT_LIMIT,
+        };
+        assert_eq!(stream.trim(&approx), 100);
+        assert_eq!(live_ids(&stream).len(), 150);
+
+        let mut stream = stream_of(250);
+        let limited = Trim {
+            limit: 50,
+            ..approx
+        };
+        assert_eq!(stream.trim(&limited), 0);
+        assert_eq!(stream.len(), 250);
+    }
+
+    #[test]
+    fn minid_trims_below_the_threshold() {
+        let mut stream = stream_of(250);
+        assert_eq!(stream.trim(&exact(TrimStrategy::MinId(id(150)))), 149);
+        assert_eq!(live_ids(&stream), (150..=250).map(id).collect::<Vec<_>>());
+        assert_eq!(stream.trim(&exact(TrimStrategy::MinId(id(150)))), 0);
+    }
+
+    #[test]
+    fn minid_trim_with_random_deletions_keeps_nodes_live() {
+        for _ in 0..50 {
+            let mut stream = stream_of(400);
+            for _ in 0..200 {
+                stream.delete(id(random::below(400) as u64 + 1));
+            }
+            let min = id(random::below(420) as u64);
+            let before = live_ids(&stream);
+
+            let removed = stream.trim(&exact(TrimStrategy::MinId(min)));
+            let after = live_ids(&stream);
+            let expected: Vec<_> = before.iter().copied().filter(|&id| id >= min).collect();
+            assert_eq!(after, expected);
+            assert_eq!(removed, before.len() - after.len());
+        }
+    }
+
+    #[test]
+    fn minid_trim_ignores_tombstones_when_dropping_nodes() {
+        let mut stream = stream_of(150);
+        // The first node holds 1..=100; tombstone its newest entry.
+        assert!(stream.delete(id(100)));
+
+        assert_eq!(stream.trim(&exact(TrimStrategy::MinId(id(100)))), 99);
+        assert_eq!(stream.len(), 50);
+        assert_eq!(stream.node_count(), 1);
+        assert_eq!(stream.first_entry().unwrap().0, id(101));
+    }
+
+    #[test]
+    fn read_group_delivers_new_entries_into_the_pel() {
+        let mut stream = stream_with_group(5);
+        assert!(stream.read_group("nope", "alice", 0, false, 0).is_none());
+        assert_eq!(read_ids(&mut stream, "alice", 2, false), [id(1), id(2)]);
+        assert_eq!(
+            read_ids(&mut stream, "bob", 0, false),
+            [id(3), id(4), id(5)]
+        );
+        assert!(read_ids(&mut stream, "bob", 0, false).is_empty());
+
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_id, id(5));
+        assert_eq!(group.entries_read, Some(5));
+        assert_eq!(group.pending.len(), 5);
+        assert_eq!(group.pending[&id(1)].consumer, "alice");
+        assert_eq!(group.pending[&id(1)].delivery_count, 1);
+        assert_eq!(group.pending[&id(1)].delivery_time, 1000);
+        let alice = &group.consumers["alice"];
+        assert_eq!(
+            alice.pending.iter().copied().collect::<Vec<_>>(),
+            [id(1), id(2)]
+        );
+        assert_eq!(alice.active_time, Some(1000));
+        assert_eq!(group.consumers["bob"].pending.len(), 3);
+    }
+
+    #[test]
+    fn noack_reads_advance_the_group_without_pending_entries() {
+        let mut stream = stream_with_group(3);
+        assert_eq!(read_ids(&mut stream, "alice", 0, true).len(), 3);
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_id, id(3));
+        assert!(group.pending.is_empty());
+        assert!(group.consumers["alice"].pending.is_empty());
+    }
+
+    #[test]
+    fn redelivery_moves_the_entry_to_its_new_owner() {
+        let mut group = ConsumerGroup::new(StreamId::MIN, None);
+        group.deliver(id(1), "alice", 100);
+        group.pending.get_mut(&id(1)).unwrap().delivery_count = 3;
+        group.deliver(id(1), "bob", 200);
+
+        let entry = &group.pending[&id(1)];
+        assert_eq!(entry.consumer, "bob");
+        assert_eq!(entry.delivery_time, 200);
+        assert_eq!(entry.delivery_count, 1);
+        assert!(group.consumers["alice"].pending.is_empty());
+        assert!(group.consumers["bob"].pending.contains(&id(1)));
+    }
+
+    #[test]
+    fn ack_and_delete_consumer_clear_both_pel_views() {
+        let mut stream = stream_with_group(4);
+        read_ids(&mut stream, "alice", 2, false);
+        read_ids(&mut stream, "bob", 2, false);
+        let group = stream.groups.get_mut("g").unwrap();
+
+        assert!(group.ack(id(1)));
+        assert!(!group.ack(id(1)));
+        assert!(!group.ack(id(9)));
+        assert_eq!(group.consumers["alice"].pending.len(), 1);
+
+        assert_eq!(group.delete_consumer("bob"), Some(2));
+        assert_eq!(group.delete_consumer("bob"), None);
+        assert_eq!(group.pending.keys().copied().collect::<Vec<_>>(), [id(2)]);
+        assert!(!group.consumers.contains_key("bob"));
+    }
+
+    #[test]
+    fn entries_read_is_lost_across_tombstones_until_the_last_entry() {
+        let mut stream = stream_with_group(10);
+        read_ids(&mut stream, "alice", 2, true);
+        assert_eq!(stream.groups["g"].entries_read, Some(2));
+
+        // Entries past a deletion can't be counted by ID any more...
+        stream.delete(id(6));
+        read_ids(&mut stream, "alice", 2, true);
+        assert_eq!(stream.groups["g"].entries_read, None);
+        read_ids(&mut stream, "alice", 4, true);
+        assert_eq!(stream.groups["g"].last_id, id(9));
+        assert_eq!(stream.groups["g"].entries_read, None);
+        // ...but the last one is always entry number `entries_added`.
+        read_ids(&mut stream, "alice", 1, true);
+        assert_eq!(stream.groups["g"].entries_read, Some(10));
+
+        // Deletions behind the group's position don't matter.
+        let mut stream = stream_with_group(10);
+        stream.delete(id(2));
+        read_ids(&mut stream, "alice", 3, true);
+        assert_eq!(stream.groups["g"].entries_read, None);
+        let mut stream = stream_with_group(10);
+        read_ids(&mut stream, "alice", 3, true);
+        stream.delete(id(2));
+        read_ids(&mut stream, "alice", 3, true);
+        assert_eq!(stream.groups["g"].entries_read, Some(6));
+    }
+
+    #[test]
+    fn claim_counts_deliveries_unless_justid_or_retrycount() {
+        let mut group = ConsumerGroup::new(StreamId::MIN, None);
+        group.deliver(id(1), "alice", 100);
+
+        group.claim(id(1), "bob", 200, None, false, 300);
+        let entry = &group.pending[&id(1)];
+        assert_eq!((entry.consumer.as_str(), entry.delivery_count), ("bob", 2));
+        assert_eq!(entry.delivery_time, 200);
+        assert!(group.consumers["alice"].pending.is_empty());
+        assert_eq!(group.consumers["bob"].active_time, Some(300));
+
+        group.claim(id(1), "carol", 200, None, true, 300);
+        assert_eq!(group.pending[&id(1)].delivery_count, 2);
+        group.claim(id(1), "carol", 200, Some(7), true, 300);
+        assert_eq!(group.pending[&id(1)].delivery_count, 7);
+        assert!(group.consumers["bob"].pending.is_empty());
+
+        // FORCE: an entry nobody had pending starts from zero deliveries.
+        group.claim(id(2), "carol", 200, None, false, 300);
+        assert_eq!(group.pending[&id(2)].delivery_count, 1);
+        assert_eq!(group.consumers["carol"].pending.len(), 2);
+    }
+
+    fn lag_at(stream: &Stream, last: StreamId, entries_read: Option<u64>) -> Option<u64> {
+        stream.lag(&ConsumerGroup::new(last, entries_read))
+    }
+
+    #[test]
+    fn lag_of_an_empty_stream_is_zero() {
+        let stream = Stream::new();
+        assert_eq!(lag_at(&stream, StreamId::MIN, None), Some(0));
+        assert_eq!(stream.entries_up_to(id(5)), Some(0));
+
+        // Entries were added and all deleted: the group is caught up if it
+        // is at or before the last ID.
+        let mut stream = stream_of(3);
+        for ms in 1..=3 {
+            stream.delete(id(ms));
+        }
+        assert_eq!(lag_at(&stream, id(1), None), Some(0));
+        assert_eq!(lag_at(&stream, StreamId::MIN, None), Some(0));
+    }
+
+    #[test]
+    fn lag_trusts_entries_read_without_tombstones_ahead() {
+        let mut stream = stream_of(10);
+        assert_eq!(lag_at(&stream, id(4), Some(4)), Some(6));
+        stream.delete(id(2));
+        assert_eq!(lag_at(&stream, id(4), Some(4)), Some(6));
+        stream.delete(id(8));
+        assert_eq!(lag_at(&stream, id(4), Some(4)), None);
+        assert_eq!(lag_at(&stream, id(10), Some(10)), Some(0));
+    }
+
+    #[test]
+    fn lag_is_unknown_past_the_last_id() {
+        let stream = stream_of(10);
+        assert_eq!(stream.entries_up_to(id(11)), None);
+        assert_eq!(lag_at(&stream, id(11), None), None);
+        assert_eq!(stream.entries_up_to(id(10)), Some(10));
+    }
+
+    #[test]
+    fn lag_below_the_first_entry_needs_an_unfragmented_stream() {
+        // 1..=120 added, 1..=100 trimmed away as a whole node.
+        let mut stream = stream_of(120);
+        let trim = Trim {
+            strategy: TrimStrategy::MaxLen(20),
+            approx: true,
+            limit: 0,
+        };
+        assert_eq!(stream.trim(&trim), 100);
+
+        assert_eq!(stream.entries_up_to(id(50)), Some(100));
+        assert_eq!(stream.entries_up_to(id(101)), Some(101));
+        assert_eq!(lag_at(&stream, id(50), None), Some(20));
+        assert_eq!(lag_at(&stream, StreamId::MIN, None), Some(20));
+        // Inside the stream, entries can't be counted by ID.
+        assert_eq!(stream.entries_up_to(id(110)), None);
+
+        // An XDEL before the first entry leaves the count intact...
+        let mut stream = stream_of(120);
+        stream.delete(id(50));
+        stream.trim(&trim);
+        assert_eq!(stream.max_deleted_id, id(50));
+        assert_eq!(lag_at(&stream, id(50), None), Some(20));
+
+        // ...but one past it fragments the stream.
+        stream.delete(id(105));
+        assert_eq!(stream.entries_up_to(id(50)), None);
+        assert_eq!(lag_at(&stream, id(50), None), None);
+        assert_eq!(lag_at(&stream, id(50), Some(50)), None);
+    }
+
+    #[test]
+    fn set_id_rejects_inconsistent_metadata() {
+        let mut stream = stream_of(10);
+        stream.delete(id(10));
+        assert_eq!(
+            stream.set_id(id(8), None, None),
+            Err("The ID specified in XSETID is smaller than the target stream top item")
+        );
+        assert_eq!(
+            stream.set_id(id(20), Some(8), None),
+            Err("The entries_added specified in XSETID is smaller than the target stream length")
+        );
+        assert_eq!(
+            stream.set_id(id(20), None, Some(id(21))),
+            Err("The ID specified in XSETID is smaller than the provided max_deleted_entry_id")
+        );
+        assert_eq!(stream.last_id(), id(10));
+
+        // Below the deleted top item is fine: only live entries count.
+        assert_eq!(stream.set_id(id(9), Some(9), Some(id(9))), Ok(()));
+        assert_eq!(stream.last_id(), id(9));
+        assert_eq!(stream.entries_added(), 9);
+        assert_eq!(stream.max_deleted_id(), id(9));
+    }
+}
@@ -0,0 +1,440 @@
+use crate::config::ListpackLimits;
+use crate::dict::Dict;
+use crate::random;
+use crate::resp::byte_len;
+use crate::skiplist::SkipList;
+
+/// Sorted set ordered by `(score, member)`, with two encodings mirroring
+/// Redis.
+///
+/// Small sets are kept as a sorted vector of member/score pairs (the
+/// "listpack"). Past the `zset-max-listpack-*` limits they are converted to
+/// a [`SkipList`] for ordered and ranked access paired with a [`Dict`] from
+/// member to score for O(1) lookups.
+#[derive(Debug)]
+pub struct ZSet {
+    encoding: Encoding,
+}
+
+#[derive(Debug)]
+enum Encoding {
+    Listpack(Vec<(String, f64)>),
+    SkipList { list: SkipList, scores: Dict<f64> },
+}
+
+impl Default for ZSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `(s, m)` sorts strictly before `(score, member)`.
+fn sorts_before(s: f64, m: &str, score: f64, member: &str) -> bool {
+    s < score || (s == score && m < member)
+}
+
+impl ZSet {
+    pub fn new() -> Self {
+        Self {
+            encoding: Encoding::Listpack(Vec::new()),
+        }
+    }
+
+    pub fn encoding_name(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Listpack(_) => "listpack",
+            Encoding::SkipList { .. } => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries.len(),
+            Encoding::SkipList { list, .. } => list.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries
+                .iter()
+                .find(|(m, _)| m == member)
+                .map(|(_, score)| *score),
+            Encoding::SkipList { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    /// Sets the score of `member`, returning true if the member is new.
+    /// Converts to a skiplist when the listpack limits are exceeded.
+    pub fn insert(&mut self, member: String, score: f64, limits: ListpackLimits) -> bool {
+        let existed = match self.score(&member) {
+            Some(current) if current == score => return false,
+            Some(_) => {
+                self.remove(&member);
+                true
+            }
+            None => false,
+        };
+
+        if let Encoding::Listpack(entries) = &mut self.encoding {
+            if limits.allows(entries.len() + 1, byte_len(&member)) {
+                let pos = entries.partition_point(|(m, s)| sorts_before(*s, m, score, &member));
+                entries.insert(pos, (member, score));
+                return !existed;
+            }
+            self.convert_to_skiplist();
+        }
+
+        if let Encoding::SkipList { list, scores } = &mut self.encoding {
+            scores.insert(member.clone(), score);
+            list.insert(score, member);
+        }
+        !existed
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match &mut self.encoding {
+            Encoding::Listpack(entries) => match entries.iter().position(|(m, _)| m == member) {
+                Some(pos) => {
+                    entries.remove(pos);
+                    true
+                }
+                None => false,
+            },
+            Encoding::SkipList { list, scores } => match scores.remove(member) {
+                Some(score) => list.remove(score, member),
+                None => false,
+            },
+        }
+    }
+
+    fn convert_to_skiplist(&mut self) {
+        if let Encoding::Listpack(entries) = &mut self.encoding {
+            let mut list = SkipList::new();
+            let mut scores = Dict::new();
+            for (member, score) in entries.drain(..) {
+                scores.insert(member.clone(), score);
+                list.insert(score, member);
+            }
+            self.encoding = Encoding::SkipList { list, scores };
+        }
+    }
+
+    /// 0-based rank of `member` in ascending order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.count_while(|s, m| sorts_before(s, m, score, member)))
+    }
+
+    /// Counts the leading elements for which `before` holds. `before` must
+    /// be true for a prefix of the set and false afterwards.
+    pub fn count_while(&self, before: impl Fn(f64, &str) -> bool) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries.partition_point(|(m, s)| before(*s, m)),
+            Encoding::SkipList { list, .. } => list.count_while(before),
+        }
+    }
+
+    /// Rank range `start..end` of the elements whose member is in `range`.
+    /// Like in Redis, this is only meaningful when all scores are equal.
+    pub fn lex_range(&self, range: &LexRange) -> (usize, usize) {
+        let start = self.count_while(|_, m| range.below_min(m));
+        let end = self.count_while(|_, m| !range.above_max(m));
+        (start, end)
+    }
+
+    /// Rank range `start..end` of the elements whose score is in `range`.
+    /// The range is empty when `end <= start`.
+    pub fn score_range(&self, range: &ScoreRange) -> (usize, usize) {
+        let start = self.count_while(|s, _| range.below_min(s));
+        let end = self.count_while(|s, _| !range.above_max(s));
+        (start, end)
+    }
+
+    /// Iterates the elements with ranks in `start..end`, in descending order
+    /// if `rev` is set.
+    pub fn range(
+        &self,
+        start: usize,
+        end: usize,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&String, f64)> + '_> {
+        match &self.encoding {
+            Encoding::Listpack(entries) => {
+                let end = end.min(entries.len());
+                let slice = entries.get(start..end).unwrap_or_default();
+                let iter = slice.iter().map(|(m, s)| (m, *s));
+                if rev {
+                    Box::new(iter.rev())
+                } else {
+                    Box::new(iter)
+                }
+            }
+            Encoding::SkipList { list, .. } => Box::new(list.range(start, end, rev)),
+        }
+    }
+
+    /// Picks elements with the `ZRANDMEMBER` count semantics of
+    /// [`random::try_sample`], or `None` if `count` is out of range. The set
+    /// must not be empty.
+    pub fn random_members(&self, count: i64) -> Option<Vec<(String, f64)>> {
+        Some(match &self.encoding {
+            Encoding::Listpack(entries) => random::try_sample(
+                count,
+                entries.len(),
+                || (0..entries.len()).collect(),
+                || random::below(entries.len()),
+            )?
+            .into_iter()
+            .map(|i| entries[i].clone())
+            .collect(),
+            Encoding::SkipList { scores, .. } => random::try_sample(
+                count,
+                scores.len(),
+                || scores.keys().collect(),
+                || scores.random_entry().unwrap().0,
+            )?
+            .into_iter()
+            .map(|member| (member.clone(), scores.get(member).copied().unwrap()))
+            .collect(),
+        })
+    }
+}
+
+/// A `min max` score interval as accepted by `ZCOUNT` and `ZRANGEBYSCORE`,
+/// where a leading `(` makes a bound exclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreRange {
+    pub min: f64,
+    pub min_exclusive: bool,
+    pub max: f64,
+    pub max_exclusive: bool,
+}
+
+impl ScoreRange {
+    pub fn parse(min: &str, max: &str) -> Option<Self> {
+        let (min, min_exclusive) = parse_score_bound(min)?;
+        let (max, max_exclusive) = parse_score_bound(max)?;
+        Some(Self {
+            min,
+            min_exclusive,
+            max,
+            max_exclusive,
+        })
+    }
+
+    /// Whether `score` sorts before the start of the range.
+    fn below_min(&self, score: f64) -> bool {
+        if self.min_exclusive {
+            score <= self.min
+        } else {
+            score < self.min
+        }
+    }
+
+    /// Whether `score` sorts after the end of the range.
+    fn above_max(&self, score: f64) -> bool {
+        if self.max_exclusive {
+            score >= self.max
+        } else {
+            score > self.max
+        }
+    }
+}
+
+/// One end of a `ZRANGEBYLEX` interval: `-`, `+`, `[member` or `(member`.
+#[derive(Debug, Clone)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn parse(bound: &str) -> Option<Self> {
+        match bound {
+            "-" => Some(Self::NegInf),
+            "+" => Some(Self::PosInf),
+            _ => match bound.split_at_checked(1)? {
+                ("[", member) => Some(Self::Inclusive(member.to_string())),
+                ("(", member) => Some(Self::Exclusive(member.to_string())),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A `min max` member interval as accepted by `ZRANGEBYLEX` and `ZLEXCOUNT`.
+#[derive(Debug, Clone)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    pub fn parse(min: &str, max: &str) -> Option<Self> {
+        Some(Self {
+            min: LexBound::parse(min)?,
+            max: LexBound::parse(max)?,
+        })
+    }
+
+    /// Whether `member` sorts before the start of the range.
+    fn below_min(&self, member: &str) -> bool {
+        match &self.min {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(min) => member < min.as_str(),
+            LexBound::Exclusive(min) => member <= min.as_str(),
+        }
+    }
+
+    /// Whether `member` sorts after the end of the range.
+    fn above_max(&self, member: &str) -> bool {
+        match &self.max {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(max) => member > max.as_str(),
+            LexBound::Exclusive(max) => member >= max.as_str(),
+        }
+    }
+}
+
+fn parse_score_bound(bound: &str) -> Option<(f64, bool)> {
+    let (num, exclusive) = match bound.strip_prefix('(') {
+        Some(rest) => (rest, true),
+        None => (bound, false),
+    };
+    let score = parse_score(num)?;
+    Some((score, exclusive))
+}
+
+/// Parses a score, accepting `inf`/`-inf` but never NaN.
+pub fn parse_score(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+/// Formats a score the way Redis replies with it: the shortest string that
+/// round-trips, switching to exponent notation for very large or small
+/// magnitudes, and `inf`/`-inf` for infinities.
+pub fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        return if score > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let sci = format!("{:e}", score);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if (-4..17).contains(&exp) {
+        score.to_string()
+    } else {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ListpackLimits = ListpackLimits {
+        max_entries: 4,
+        max_value: 8,
+    };
+
+    /// Members in rank order, whatever the encoding.
+    fn members(zset: &ZSet) -> Vec<String> {
+        let members: Vec<String> = match &zset.encoding {
+            Encoding::Listpack(entries) => entries.iter().map(|(m, _)| m.clone()).collect(),
+            Encoding::SkipList { scores, .. } => scores.keys().cloned().collect(),
+        };
+        let mut ranked: Vec<_> = members
+            .into_iter()
+            .map(|m| (zset.rank(&m).unwrap(), m))
+            .collect();
+        ranked.sort();
+        ranked.into_iter().map(|(_, m)| m).collect()
+    }
+
+    #[test]
+    fn listpack_orders_by_score_then_member() {
+        let mut zset = ZSet::new();
+        assert!(zset.insert("b".to_string(), 1.0, LIMITS));
+        assert!(zset.insert("a".to_string(), 1.0, LIMITS));
+        assert!(zset.insert("c".to_string(), 0.0, LIMITS));
+        assert_eq!(members(&zset), ["c", "a", "b"]);
+        let Encoding::Listpack(entries) = &zset.encoding else {
+            panic!("expected a listpack");
+        };
+        assert_eq!(entries[0], ("c".to_string(), 0.0));
+
+        assert!(!zset.insert("b".to_string(), -1.0, LIMITS));
+        assert!(!zset.insert("b".to_string(), -1.0, LIMITS));
+        assert_eq!(members(&zset), ["b", "c", "a"]);
+        assert_eq!(zset.score("b"), Some(-1.0));
+
+        assert!(zset.remove("c"));
+        assert!(!zset.remove("c"));
+        assert_eq!(members(&zset), ["b", "a"]);
+        assert_eq!(zset.encoding_name(), "listpack");
+    }
+
+    #[test]
+    fn outgrowing_the_listpack_converts_to_a_skiplist() {
+        let mut zset = ZSet::new();
+        for (i, member) in ["d", "c", "b", "a"].into_iter().enumerate() {
+            zset.insert(member.to_string(), (i % 2) as f64, LIMITS);
+        }
+        assert_eq!(zset.encoding_name(), "listpack");
+        let before = members(&zset);
+
+        zset.insert("e".to_string(), 0.5, LIMITS);
+        assert_eq!(zset.encoding_name(), "skiplist");
+        assert_eq!(members(&zset), ["b", "d", "e", "a", "c"]);
+        assert_eq!(
+            members(&zset)
+                .into_iter()
+                .filter(|m| m != "e")
+                .collect::<Vec<_>>(),
+            before
+        );
+        assert_eq!(zset.score("e"), Some(0.5));
+        assert!(!zset.insert("e".to_string(), 2.0, LIMITS));
+        assert_eq!(zset.rank("e"), Some(4));
+        assert!(zset.remove("e"));
+        assert_eq!(zset.len(), 4);
+        // A skiplist never converts back.
+        assert_eq!(zset.encoding_name(), "skiplist");
+
+        let mut zset = ZSet::new();
+        zset.insert("a-long-member".to_string(), 1.0, LIMITS);
+        assert_eq!(zset.encoding_name(), "skiplist");
+    }
+
+    #[test]
+    fn scores_format_like_redis() {
+        let cases = [
+            (1.0, "1"),
+            (-0.5, "-0.5"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (123456789.125, "123456789.125"),
+            (1e16, "10000000000000000"),
+            (1e17, "1e+17"),
+            (-1.5e17, "-1.5e+17"),
+            (1.25e300, "1.25e+300"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (1.5e-7, "1.5e-07"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (score, expected) in cases {
+            assert_eq!(format_score(score), expected);
+        }
+    }
+}